@@ -0,0 +1,308 @@
+#![forbid(unsafe_code)]
+
+use babencoin::{
+    block_forest::BlockForest,
+    block_store::FileBlockStore,
+    chain_archive::{
+        export_chain, import_chain, ArchiveFormat, ArchiveReader, ArchiveWriter, ImportReport,
+    },
+    codec::{decode_frame, encode_frame, WireFormat},
+    data::{Block, BlockHash, BlockHeader, PeerMessage},
+    node::Config,
+};
+
+use anyhow::{bail, Context, Result};
+use structopt::StructOpt;
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const HEADERS_PER_REQUEST: u64 = 32;
+const BLOCKS_PER_REQUEST: usize = 8;
+const MAX_FRAME_SIZE: usize = 16 << 20;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+enum Opts {
+    /// Write the main chain of a node store, or of a running node, to an archive
+    Export {
+        #[structopt(flatten)]
+        node_config: NodeConfigOpts,
+
+        /// Download the chain from this node instead of reading the store
+        #[structopt(short = "n", long = "node")]
+        node_address: Option<String>,
+
+        /// Archive path
+        #[structopt(short = "o", long = "output")]
+        output_path: PathBuf,
+
+        /// Archive format, json or binary
+        #[structopt(long = "format", default_value = "json")]
+        format: ArchiveFormat,
+    },
+
+    /// Verify an archive and add its blocks to a node store
+    Import {
+        #[structopt(flatten)]
+        node_config: NodeConfigOpts,
+
+        /// Archive path
+        #[structopt(short = "i", long = "input")]
+        input_path: PathBuf,
+    },
+
+    /// Verify an archive and report the first invalid block
+    Verify {
+        #[structopt(flatten)]
+        node_config: NodeConfigOpts,
+
+        /// Archive path
+        #[structopt(short = "i", long = "input")]
+        input_path: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct NodeConfigOpts {
+    /// Node config path, for the consensus parameters and the store path. Defaults are used if
+    /// not set
+    #[structopt(short = "c", long = "config")]
+    config_path: Option<PathBuf>,
+
+    /// Block log path, overrides the one of the config
+    #[structopt(short = "s", long = "store")]
+    store_path: Option<PathBuf>,
+}
+
+impl NodeConfigOpts {
+    fn read_config(&self) -> Result<Config> {
+        let mut config = match &self.config_path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        if let Some(path) = &self.store_path {
+            config.storage_path = Some(path.clone());
+        }
+        Ok(config)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A blocking session with a node, speaking JSON like a legacy peer.
+struct PeerConnection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl PeerConnection {
+    fn connect(address: &str) -> Result<Self> {
+        let stream =
+            TcpStream::connect(address).context(format!("failed to connect to {}", address))?;
+        stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+        Ok(Self {
+            stream,
+            buffer: vec![],
+        })
+    }
+
+    fn send(&mut self, message: &PeerMessage) -> Result<()> {
+        let data = encode_frame(message, WireFormat::Json)?;
+        self.stream
+            .write_all(&data)
+            .context("failed to send message")
+    }
+
+    fn receive(&mut self) -> Result<PeerMessage> {
+        loop {
+            if let Some((message, frame_len)) = decode_frame(&self.buffer, MAX_FRAME_SIZE)? {
+                self.buffer.drain(..frame_len);
+                return Ok(message);
+            }
+
+            let mut chunk = [0; 4096];
+            let len = self
+                .stream
+                .read(&mut chunk)
+                .context("failed to receive message")?;
+            if len == 0 {
+                bail!("connection is closed");
+            }
+            self.buffer.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn receive_headers(&mut self) -> Result<Vec<BlockHeader>> {
+        loop {
+            if let PeerMessage::Headers { headers } = self.receive()? {
+                return Ok(headers);
+            }
+        }
+    }
+
+    /// Returns the blocks in the order of the hashes, other messages are skipped.
+    fn receive_blocks(&mut self, block_hashes: &[BlockHash]) -> Result<Vec<Block>> {
+        let mut blocks = HashMap::new();
+        while blocks.len() < block_hashes.len() {
+            if let PeerMessage::Block(block) = self.receive()? {
+                let hash = block.compute_hash();
+                if block_hashes.contains(&hash) {
+                    blocks.insert(hash, *block);
+                }
+            }
+        }
+        Ok(block_hashes
+            .iter()
+            .map(|hash| blocks.remove(hash).unwrap())
+            .collect())
+    }
+}
+
+/// Downloads the main chain of the node headers first. The blocks are not verified, this is what
+/// `verify` is for.
+fn download_chain(
+    node_address: &str,
+    genesis_hash: BlockHash,
+    writer: &mut ArchiveWriter<impl Write>,
+) -> Result<u64> {
+    let mut conn = PeerConnection::connect(node_address)?;
+    let mut last_hash = genesis_hash;
+    let mut count = 0;
+    loop {
+        conn.send(&PeerMessage::GetHeaders {
+            locator: vec![last_hash],
+            limit: HEADERS_PER_REQUEST,
+        })?;
+        let headers = conn.receive_headers()?;
+        let Some(last_header) = headers.last() else {
+            return Ok(count);
+        };
+        if headers[0].prev_hash != last_hash {
+            bail!(
+                "node has no block {}, is its genesis different?",
+                base64::encode(last_hash)
+            );
+        }
+        BlockHeader::verify_chain(&headers).context("node has sent invalid headers")?;
+
+        for chunk in headers.chunks(BLOCKS_PER_REQUEST) {
+            let block_hashes: Vec<_> = chunk.iter().map(|header| header.hash).collect();
+            conn.send(&PeerMessage::GetBlocks {
+                block_hashes: block_hashes.clone(),
+            })?;
+            for block in conn.receive_blocks(&block_hashes)? {
+                writer.write(&block)?;
+                count += 1;
+            }
+        }
+        last_hash = last_header.hash;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn open_store(config: &Config) -> Result<FileBlockStore> {
+    let Some(path) = &config.storage_path else {
+        bail!("store path is set neither in the config nor with --store");
+    };
+    FileBlockStore::open(path)
+}
+
+fn export(
+    node_config: NodeConfigOpts,
+    node_address: Option<String>,
+    output_path: &Path,
+    format: ArchiveFormat,
+) -> Result<()> {
+    let config = node_config.read_config()?;
+    let file =
+        File::create(output_path).context(format!("failed to create {}", output_path.display()))?;
+    let mut writer = ArchiveWriter::new(BufWriter::new(file), format)?;
+
+    let count = match node_address {
+        Some(node_address) => {
+            let genesis_hash = *config.block_forest.consensus.genesis_block().hash();
+            download_chain(&node_address, genesis_hash, &mut writer)?
+        }
+        None => {
+            if let Some(path) = config.storage_path.as_ref().filter(|path| !path.exists()) {
+                bail!("store {} does not exist", path.display());
+            }
+            let store = open_store(&config)?;
+            let forest = BlockForest::with_store(config.block_forest, Box::new(store))?;
+            export_chain(&forest, &mut writer)?
+        }
+    };
+    writer.finish()?;
+
+    println!("exported {} blocks", count);
+    Ok(())
+}
+
+fn read_archive(input_path: &Path) -> Result<ArchiveReader<BufReader<File>>> {
+    let file =
+        File::open(input_path).context(format!("failed to open {}", input_path.display()))?;
+    ArchiveReader::new(BufReader::new(file))
+}
+
+fn print_report(report: &ImportReport) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(report)?);
+    if let Some(invalid) = &report.first_invalid {
+        bail!(
+            "block #{} ({}) is invalid: {}",
+            invalid.position,
+            base64::encode(invalid.hash),
+            invalid.error
+        );
+    }
+    Ok(())
+}
+
+fn import(node_config: NodeConfigOpts, input_path: &Path) -> Result<()> {
+    let config = node_config.read_config()?;
+    let store = open_store(&config)?;
+    let mut forest = BlockForest::with_store(config.block_forest, Box::new(store))?;
+    let report = import_chain(&mut forest, read_archive(input_path)?)?;
+    print_report(&report)
+}
+
+fn verify(node_config: NodeConfigOpts, input_path: &Path) -> Result<()> {
+    let config = node_config.read_config()?;
+    let mut forest = BlockForest::with_config(config.block_forest);
+    let report = import_chain(&mut forest, read_archive(input_path)?)?;
+    print_report(&report)
+}
+
+fn do_main() -> Result<()> {
+    match Opts::from_args() {
+        Opts::Export {
+            node_config,
+            node_address,
+            output_path,
+            format,
+        } => export(node_config, node_address, &output_path, format),
+        Opts::Import {
+            node_config,
+            input_path,
+        } => import(node_config, &input_path),
+        Opts::Verify {
+            node_config,
+            input_path,
+        } => verify(node_config, &input_path),
+    }
+}
+
+fn main() {
+    if let Err(err) = do_main() {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,223 @@
+#![forbid(unsafe_code)]
+
+use babencoin::{
+    codec::{encode_frame, WireFormat},
+    data::{PeerMessage, Transaction, VerifiedTransaction},
+    util::{
+        decode_wallet_id, encode_wallet_id, format_pkcs8_private, format_pkcs8_public,
+        parse_pkcs8_private,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use rand::thread_rng;
+use rsa::RSAPrivateKey;
+use structopt::StructOpt;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+};
+
+const MIN_KEY_BITS: usize = 1024;
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+enum Opts {
+    /// Generate a new PKCS#8 RSA private key
+    Generate {
+        /// Private key path
+        #[structopt(short = "k", long = "key")]
+        key_path: PathBuf,
+
+        /// RSA modulus size in bits
+        #[structopt(long = "bits", default_value = "4096")]
+        bits: usize,
+    },
+
+    /// Print the wallet id of a private key
+    Show {
+        /// Private key path
+        #[structopt(short = "k", long = "key")]
+        key_path: PathBuf,
+
+        /// Print the public key in PEM format instead of the wallet id
+        #[structopt(long = "pem")]
+        pem: bool,
+    },
+
+    /// Sign a transfer and print it as json
+    Sign {
+        #[structopt(flatten)]
+        transfer: TransferOpts,
+    },
+
+    /// Send a signed transaction to a node
+    Push {
+        /// Node address
+        #[structopt(short = "n", long = "node")]
+        node_address: String,
+
+        /// Signed transaction path, reads stdin if not set
+        #[structopt(short = "t", long = "tx")]
+        tx_path: Option<PathBuf>,
+    },
+
+    /// Sign a transfer and send it to a node
+    Send {
+        /// Node address
+        #[structopt(short = "n", long = "node")]
+        node_address: String,
+
+        #[structopt(flatten)]
+        transfer: TransferOpts,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct TransferOpts {
+    /// Sender private key path
+    #[structopt(short = "k", long = "key")]
+    key_path: PathBuf,
+
+    /// Receiver wallet id
+    #[structopt(short = "r", long = "receiver")]
+    receiver: String,
+
+    #[structopt(short = "a", long = "amount")]
+    amount: u64,
+
+    #[structopt(short = "f", long = "fee", default_value = "0")]
+    fee: u64,
+
+    /// Sender sequence number, must be greater than the one of any previous transfer
+    #[structopt(long = "nonce")]
+    nonce: u64,
+
+    #[structopt(short = "m", long = "comment", default_value = "")]
+    comment: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn read_private_key(path: &Path) -> Result<RSAPrivateKey> {
+    let raw = fs::read_to_string(path).context(format!("failed to read {}", path.display()))?;
+    parse_pkcs8_private(&raw).context(format!("failed to parse {}", path.display()))
+}
+
+fn generate(key_path: &Path, bits: usize) -> Result<()> {
+    if bits < MIN_KEY_BITS {
+        bail!("key size must be at least {} bits", MIN_KEY_BITS);
+    }
+
+    let key = RSAPrivateKey::new(&mut thread_rng(), bits).context("failed to generate key")?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        // The key is a secret, so only the owner may read it.
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(key_path)
+        .context(format!("failed to create {}", key_path.display()))?;
+    file.write_all(format_pkcs8_private(&key)?.as_bytes())
+        .context(format!("failed to write {}", key_path.display()))?;
+
+    println!("{}", encode_wallet_id(&key.to_public_key().into())?);
+    Ok(())
+}
+
+fn show(key_path: &Path, pem: bool) -> Result<()> {
+    let key = read_private_key(key_path)?;
+    if pem {
+        print!("{}", format_pkcs8_public(&key.to_public_key())?);
+    } else {
+        println!("{}", encode_wallet_id(&key.to_public_key().into())?);
+    }
+    Ok(())
+}
+
+fn sign(opts: TransferOpts) -> Result<Transaction> {
+    let key = read_private_key(&opts.key_path)?;
+    let receiver =
+        decode_wallet_id(&opts.receiver).context("failed to parse receiver wallet id")?;
+    let tx = VerifiedTransaction::sign(
+        &key,
+        receiver,
+        opts.amount,
+        opts.fee,
+        opts.nonce,
+        opts.comment,
+    )
+    .context("failed to sign transaction")?;
+    Ok(tx.into())
+}
+
+fn read_transaction(tx_path: Option<&Path>) -> Result<Transaction> {
+    let buffer = match tx_path {
+        Some(path) => {
+            fs::read_to_string(path).context(format!("failed to read {}", path.display()))?
+        }
+        None => {
+            let mut buffer = String::new();
+            io::stdin()
+                .read_to_string(&mut buffer)
+                .context("failed to read stdin")?;
+            buffer
+        }
+    };
+
+    let tx: Transaction = serde_json::from_str(&buffer).context("failed to parse transaction")?;
+    tx.clone()
+        .verified()
+        .context("transaction verification failed")?;
+    Ok(tx)
+}
+
+fn push(node_address: &str, tx: Transaction) -> Result<()> {
+    let message = PeerMessage::Transaction(Box::new(tx));
+    let data = encode_frame(&message, WireFormat::Json)?;
+
+    let mut conn = TcpStream::connect(node_address)
+        .context(format!("failed to connect to {}", node_address))?;
+    conn.write_all(&data)
+        .and_then(|_| conn.flush())
+        .context(format!("failed to send transaction to {}", node_address))?;
+    Ok(())
+}
+
+fn do_main() -> Result<()> {
+    match Opts::from_args() {
+        Opts::Generate { key_path, bits } => generate(&key_path, bits),
+        Opts::Show { key_path, pem } => show(&key_path, pem),
+        Opts::Sign { transfer } => {
+            let tx = sign(transfer)?;
+            println!("{}", serde_json::to_string_pretty(&tx)?);
+            Ok(())
+        }
+        Opts::Push {
+            node_address,
+            tx_path,
+        } => push(&node_address, read_transaction(tx_path.as_deref())?),
+        Opts::Send {
+            node_address,
+            transfer,
+        } => {
+            let tx = sign(transfer)?;
+            push(&node_address, tx.clone())?;
+            println!("{}", base64::encode(tx.compute_hash()));
+            Ok(())
+        }
+    }
+}
+
+fn main() {
+    if let Err(err) = do_main() {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
+}
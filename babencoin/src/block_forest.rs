@@ -1,94 +1,496 @@
-use crate::data::{
-    BlockHash, TransactionHash, VerifiedBlock, VerifiedTransaction, WalletId, HASH_LEN,
+use crate::{
+    block_store::BlockStore,
+    consensus::{block_work, BlockRules, ConsensusParams},
+    data::{
+        BlockAttributes, BlockHash, BlockHeader, MultisigPolicy, Timelock, TransactionHash,
+        VerifiedBlock, VerifiedTransaction, WalletId,
+    },
+    mempool::{Mempool, MempoolConfig},
 };
 
 use anyhow::{bail, Context, Result};
-use chrono::Duration;
-use log::debug;
+use chrono::{DateTime, Utc};
 use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, info, instrument};
 
 use std::{
     collections::{HashMap, HashSet, VecDeque},
+    fmt,
     sync::Arc,
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Defaults of the consensus parameters.
 pub const EPOCH_SIZE: usize = 16;
 pub const TARGET_BLOCK_MINING_TIME_SECONDS: u64 = 10;
 
+const REORG_EVENTS_CAPACITY: usize = 64;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockForestConfig {
+    pub consensus: ConsensusParams,
+    pub mempool: MempoolConfig,
+
+    /// Branches forking off the main chain more than this many blocks below the head are refused.
+    pub max_reorg_depth: u64,
+    /// The oldest known bad blocks are forgotten once there are more of them.
+    pub max_bad_blocks: usize,
+
+    pub pruning: PruningConfig,
+}
+
+impl Default for BlockForestConfig {
+    fn default() -> Self {
+        Self {
+            consensus: ConsensusParams::default(),
+            mempool: MempoolConfig::default(),
+            max_reorg_depth: 100,
+            max_bad_blocks: 10000,
+            pruning: PruningConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PruningConfig {
+    /// Drops side branches that can no longer win because of `max_reorg_depth`, along with
+    /// the balance snapshots of blocks other than the head and the checkpoints.
+    pub enabled: bool,
+    /// Main chain blocks with an index divisible by this are checkpoints. Balances at other
+    /// blocks are recomputed from the closest checkpoint below.
+    pub snapshot_interval: u64,
+}
+
+impl Default for PruningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            snapshot_interval: 32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccountState {
+    /// Includes the locked funds.
+    pub balance: u64,
+    /// Nonce of the last non-legacy transaction sent from the wallet, 0 if there were none.
+    pub last_nonce: u64,
+    /// Hashes of the legacy transactions sent from the wallet. They have no nonce, so these
+    /// guard against their replay.
+    pub legacy_transactions: HashSet<TransactionHash>,
+    /// Received funds which timelocks have not expired yet as of the last wallet transaction.
+    pub locked: Vec<LockedFunds>,
+    pub multisig_policy: Option<MultisigPolicy>,
+}
+
+impl AccountState {
+    /// Returns the part of the balance a block with the given index and timestamp may spend.
+    pub fn spendable_balance(&self, block_index: u64, timestamp: DateTime<Utc>) -> u64 {
+        let locked: u64 = self
+            .locked
+            .iter()
+            .filter(|funds| !funds.timelock.is_expired(block_index, timestamp))
+            .map(|funds| funds.amount)
+            .sum();
+        self.balance.saturating_sub(locked)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockedFunds {
+    pub amount: u64,
+    pub timelock: Timelock,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WalletEventKind {
+    Sent(VerifiedTransaction),
+    Received(VerifiedTransaction),
+    /// Block reward plus the fees of all block transactions.
+    MiningReward(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalletEvent {
+    pub block_index: u64,
+    pub block_hash: BlockHash,
+    pub kind: WalletEventKind,
+}
+
+/// The head has switched to a branch that does not contain the old head.
+#[derive(Clone, Debug)]
+pub struct ReorgEvent {
+    pub old_head: Arc<VerifiedBlock>,
+    pub new_head: Arc<VerifiedBlock>,
+    /// The last block shared by both branches.
+    pub lca: Arc<VerifiedBlock>,
+    /// Transactions of the abandoned blocks which are not in the new branch. They are returned
+    /// to the mempool unless they no longer apply.
+    pub disconnected_transactions: Vec<VerifiedTransaction>,
+    /// Transactions of the new branch blocks, now confirmed on the main chain.
+    pub reconnected_transactions: Vec<VerifiedTransaction>,
+}
+
+impl ReorgEvent {
+    /// Number of main chain blocks that were abandoned.
+    pub fn depth(&self) -> u64 {
+        self.old_head.index - self.lca.index
+    }
+}
+
+/// The block forks off the main chain below `max_reorg_depth`. Unlike a bad block, it may be
+/// valid, so it is not remembered and its sender is not to blame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReorgTooDeep {
+    pub block_hash: BlockHash,
+    pub depth: u64,
+    pub max_depth: u64,
+}
+
+impl fmt::Display for ReorgTooDeep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block {} forks off the main chain {} blocks below the head, the limit is {}",
+            base64::encode(self.block_hash),
+            self.depth,
+            self.max_depth
+        )
+    }
+}
+
+impl std::error::Error for ReorgTooDeep {}
+
+/// The store has failed to persist the block, it is not added to the forest. This is a local
+/// failure, the sender of the block is not to blame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockNotPersisted {
+    pub block_hash: BlockHash,
+}
+
+impl fmt::Display for BlockNotPersisted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to persist block {}",
+            base64::encode(self.block_hash)
+        )
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct BlockForest {
+    consensus: ConsensusParams,
+    rules: BlockRules,
     head: Arc<VerifiedBlock>,
+    main_chain: Vec<BlockHash>,
+    main_chain_tx_indices: HashMap<TransactionHash, u64>,
     blocks: HashMap<BlockHash, Arc<VerifiedBlock>>,
+    /// Total work of the chain ending with the block, known for the blocks connected to genesis.
+    chain_work: HashMap<BlockHash, BigUint>,
     children_hashes: HashMap<BlockHash, Vec<BlockHash>>,
     bad_block_hashes: HashSet<BlockHash>,
+    /// Bad block hashes in the order they were found.
+    bad_block_queue: VecDeque<BlockHash>,
     unknown_block_hashes: HashSet<BlockHash>,
-    balance_snapshots: HashMap<BlockHash, HashMap<WalletId, u64>>,
-    pending_transactions: HashMap<TransactionHash, VerifiedTransaction>,
-    pending_snapshot: HashMap<WalletId, u64>,
+    balance_snapshots: HashMap<BlockHash, HashMap<WalletId, AccountState>>,
+    mempool: Mempool,
+    /// Head snapshot with all the pending transactions applied.
+    pending_snapshot: HashMap<WalletId, AccountState>,
+    store: Option<Box<dyn BlockStore>>,
+    max_reorg_depth: u64,
+    max_bad_blocks: usize,
+    pruning: PruningConfig,
+    /// Side branches forking off the main chain below this index have been pruned.
+    pruned_index: u64,
+    reorg_sender: broadcast::Sender<ReorgEvent>,
 }
 
 impl Default for BlockForest {
     fn default() -> Self {
-        let genesis = Arc::new(VerifiedBlock::genesis());
+        Self::with_config(BlockForestConfig::default())
+    }
+}
+
+impl BlockForest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: BlockForestConfig) -> Self {
+        let genesis = Arc::new(config.consensus.genesis_block());
 
         let mut blocks = HashMap::new();
         blocks.insert(*genesis.hash(), genesis.clone());
 
+        let mut chain_work = HashMap::new();
+        chain_work.insert(*genesis.hash(), block_work(&genesis.max_hash));
+
         let mut balance_snapshots = HashMap::new();
         balance_snapshots.insert(*genesis.hash(), HashMap::new());
 
         Self {
+            rules: BlockRules::with_genesis_hash(&config.consensus, *genesis.hash()),
+            consensus: config.consensus,
+            main_chain: vec![*genesis.hash()],
+            main_chain_tx_indices: HashMap::new(),
             head: genesis,
             blocks,
+            chain_work,
             children_hashes: HashMap::new(),
             bad_block_hashes: HashSet::new(),
+            bad_block_queue: VecDeque::new(),
             unknown_block_hashes: HashSet::new(),
             balance_snapshots,
-            pending_transactions: HashMap::new(),
+            mempool: Mempool::new(config.mempool),
             pending_snapshot: HashMap::new(),
+            store: None,
+            max_reorg_depth: config.max_reorg_depth,
+            max_bad_blocks: config.max_bad_blocks,
+            pruning: config.pruning,
+            pruned_index: 0,
+            reorg_sender: broadcast::channel(REORG_EVENTS_CAPACITY).0,
         }
     }
-}
 
-impl BlockForest {
-    pub fn new() -> Self {
-        Self::default()
+    /// Restores the forest from the store and persists every newly accepted block to it.
+    pub fn with_store(config: BlockForestConfig, mut store: Box<dyn BlockStore>) -> Result<Self> {
+        let mut forest = Self::with_config(config);
+        for block in store.load().context("failed to load blocks")? {
+            let hash = block.compute_hash();
+            let result = block
+                .verified_with(&forest.rules)
+                .and_then(|verified| forest.add_block(verified));
+            if let Err(err) = result {
+                debug!(
+                    "stored block {} is rejected: {:#}",
+                    base64::encode(hash),
+                    err
+                );
+            }
+        }
+        forest.store = Some(store);
+        Ok(forest)
+    }
+
+    /// Makes the blocks accepted so far durable, if the forest has a store.
+    pub fn flush(&mut self) -> Result<()> {
+        match self.store.as_mut() {
+            Some(store) => store.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn consensus(&self) -> &ConsensusParams {
+        &self.consensus
+    }
+
+    /// Rules blocks are verified against before they are added.
+    pub fn block_rules(&self) -> &BlockRules {
+        &self.rules
+    }
+
+    pub fn genesis(&self) -> &Arc<VerifiedBlock> {
+        &self.blocks[&self.main_chain[0]]
     }
 
     pub fn head(&self) -> &Arc<VerifiedBlock> {
         &self.head
     }
 
+    /// Subscribes to the reorganizations of the main chain. Slow receivers miss the oldest events.
+    pub fn subscribe_reorgs(&self) -> broadcast::Receiver<ReorgEvent> {
+        self.reorg_sender.subscribe()
+    }
+
     pub fn unknown_block_hashes(&self) -> &HashSet<BlockHash> {
         &self.unknown_block_hashes
     }
 
     pub fn pending_transactions(&self) -> &HashMap<TransactionHash, VerifiedTransaction> {
-        &self.pending_transactions
+        self.mempool.transactions()
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// Picks up to `max_count` pending transactions for the next block, highest priority first.
+    /// The result is valid when applied on top of the head in the returned order.
+    pub fn select_transactions(&self, max_count: usize) -> Vec<VerifiedTransaction> {
+        let mut snapshot = self.balance_snapshots[self.head.hash()].clone();
+        let (block_index, timestamp) = self.next_block_time();
+        self.mempool
+            .select(max_count, |tx| {
+                Self::try_apply_tx_to_snapshot(tx, block_index, timestamp, &mut snapshot)
+            })
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Drops the pending transactions that have been waiting for longer than the mempool expiry.
+    pub fn expire_pending_transactions(&mut self) {
+        let expired = self.mempool.expire(Utc::now());
+        if expired.is_empty() {
+            return;
+        }
+        for tx in expired.iter() {
+            debug!("transaction {} has expired", base64::encode(tx.hash()));
+        }
+        self.rebuild_pending_snapshot();
     }
 
     pub fn find_block(&self, hash: &BlockHash) -> Option<&Arc<VerifiedBlock>> {
         self.blocks.get(hash)
     }
 
-    pub fn next_max_hash(&self) -> BlockHash {
-        let next_index = self.head.index + 1;
-        if next_index % EPOCH_SIZE as u64 > 0 {
-            return self.head.max_hash;
+    /// Returns the total work of the chain ending with the block, if it is connected to genesis.
+    pub fn chain_work(&self, hash: &BlockHash) -> Option<&BigUint> {
+        self.chain_work.get(hash)
+    }
+
+    /// Returns the block with the given index on the chain ending at the head.
+    pub fn find_main_chain_block(&self, index: u64) -> Option<&Arc<VerifiedBlock>> {
+        let hash = self.main_chain.get(usize::try_from(index).ok()?)?;
+        self.blocks.get(hash)
+    }
+
+    /// Returns the main chain block containing the transaction.
+    pub fn find_transaction_block(&self, tx_hash: &TransactionHash) -> Option<&Arc<VerifiedBlock>> {
+        let index = *self.main_chain_tx_indices.get(tx_hash)?;
+        self.find_main_chain_block(index)
+    }
+
+    /// Returns main chain hashes from the head back to genesis, densely near the head and
+    /// exponentially sparser further from it. A peer finds the last common block by picking the
+    /// first hash it has on its main chain.
+    pub fn block_locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut index = self.main_chain.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.main_chain[index]);
+            if index == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Returns up to `limit` main chain headers following the first locator block on the main
+    /// chain, or following genesis if there is none.
+    pub fn headers_after(&self, locator: &[BlockHash], limit: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| {
+                let index = self.blocks.get(hash)?.index as usize;
+                (self.main_chain.get(index) == Some(hash)).then_some(index)
+            })
+            .unwrap_or(0);
+        self.main_chain[start + 1..]
+            .iter()
+            .take(limit)
+            .map(|hash| self.blocks[hash].header())
+            .collect()
+    }
+
+    /// Returns the balance of the wallet at the head.
+    pub fn balance(&self, wallet: &WalletId) -> u64 {
+        self.balance_at(wallet, self.head.hash()).unwrap()
+    }
+
+    /// Returns the balance of the wallet after the given block, if the block is known and
+    /// connected to genesis.
+    pub fn balance_at(&self, wallet: &WalletId, block_hash: &BlockHash) -> Option<u64> {
+        self.account_at(wallet, block_hash)
+            .map(|account| account.balance)
+    }
+
+    pub fn account_at(&self, wallet: &WalletId, block_hash: &BlockHash) -> Option<AccountState> {
+        let account = match self.balance_snapshots.get(block_hash) {
+            Some(snapshot) => snapshot.get(wallet).cloned(),
+            None => self.compute_snapshot(block_hash)?.get(wallet).cloned(),
         };
+        Some(account.unwrap_or_default())
+    }
+
+    /// Returns the smallest nonce a new transaction from the wallet may have, taking pending
+    /// transactions into account.
+    pub fn next_nonce(&self, wallet: &WalletId) -> u64 {
+        let account = self.pending_snapshot.get(wallet).cloned();
+        account.unwrap_or_default().last_nonce + 1
+    }
+
+    /// Lists all main chain events affecting the wallet, from genesis to the head.
+    pub fn wallet_history(&self, wallet: &WalletId) -> Vec<WalletEvent> {
+        let mut events = vec![];
+        for hash in self.main_chain.iter() {
+            let block = &self.blocks[hash];
+            let mut push_event = |kind| {
+                events.push(WalletEvent {
+                    block_index: block.index,
+                    block_hash: *hash,
+                    kind,
+                })
+            };
+
+            // Genesis block has no reward: its issuer is just a placeholder.
+            if block.index > 0 && &block.issuer == wallet {
+                let fees = block.transactions().iter().map(|tx| tx.fee).sum::<u64>();
+                push_event(WalletEventKind::MiningReward(block.reward + fees));
+            }
+            for tx in block.transactions() {
+                if &tx.sender == wallet {
+                    push_event(WalletEventKind::Sent(tx.clone()));
+                }
+                if &tx.receiver == wallet {
+                    push_event(WalletEventKind::Received(tx.clone()));
+                }
+            }
+        }
+        events
+    }
 
-        let mut prev_epoch = self.get_ancestors(&self.head, EPOCH_SIZE - 1);
-        prev_epoch.reverse();
-        prev_epoch.push(&self.head);
+    /// Returns the number of main chain blocks confirming the transaction: 0 if it is pending,
+    /// 1 if it is in the head and so on. Returns `None` for unknown transactions.
+    pub fn confirmation_depth(&self, tx_hash: &TransactionHash) -> Option<u64> {
+        if let Some(block_index) = self.main_chain_tx_indices.get(tx_hash) {
+            return Some(self.head.index - block_index + 1);
+        }
+        if self.mempool.contains(tx_hash) {
+            return Some(0);
+        }
+        None
+    }
 
-        assert_eq!(prev_epoch.len(), EPOCH_SIZE);
-        self.compute_epoch_max_hash(&prev_epoch)
+    pub fn next_max_hash(&self) -> BlockHash {
+        self.expected_max_hash(&self.head)
+            .expect("main chain is connected to genesis")
     }
 
+    #[instrument(skip_all, fields(block = %base64::encode(block.hash()), index = block.index))]
     pub fn add_block(&mut self, block: VerifiedBlock) -> Result<()> {
+        if let Err(err) = self.rules.check(&block) {
+            bail!(
+                "block {} breaks consensus rules: {}",
+                base64::encode(block.hash()),
+                err
+            );
+        }
         if self.bad_block_hashes.contains(block.hash()) {
             bail!("block {} is known to be bad", base64::encode(block.hash()));
         }
@@ -119,46 +521,172 @@ impl BlockForest {
         self.validate_new_block(&block)?;
 
         if self.is_block_connected_to_genesis(block.hash()) {
-            self.validate_transaction_balances(block.hash())?;
+            let lca = self.find_lca(&self.head, &block_arc);
+            let depth = self.head.index - lca.index;
+            if depth > self.max_reorg_depth {
+                self.remove_subtree(block.hash());
+                return Err(ReorgTooDeep {
+                    block_hash: *block.hash(),
+                    depth,
+                    max_depth: self.max_reorg_depth,
+                }
+                .into());
+            }
+        }
+
+        if self.is_block_connected_to_genesis(block.hash()) {
+            // Orphans are persisted once they are connected and valid, so that invalid blocks
+            // never end up in the store.
+            let validated_hashes = self.validate_transaction_balances(block.hash())?;
+            if let Err(err) = self.persist(&validated_hashes) {
+                // Forget the blocks, so that they are persisted when received again.
+                self.remove_subtree(block.hash());
+                return Err(err.context(BlockNotPersisted {
+                    block_hash: *block.hash(),
+                }));
+            }
 
             let head_candidate = self.find_head_candidate(&block_arc);
-            if head_candidate.index > self.head.index {
-                let new_head = head_candidate.clone();
-                self.switch_head_to(new_head);
+            if self.chain_work[head_candidate.hash()] > self.chain_work[self.head.hash()] {
+                self.switch_head_to(head_candidate);
+                self.prune();
             }
         }
 
         Ok(())
     }
 
+    fn persist(&mut self, hashes: &[BlockHash]) -> Result<()> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        for hash in hashes {
+            store.append(&self.blocks[hash])?;
+        }
+        Ok(())
+    }
+
+    /// Drops the side branches and orphans which can no longer become the main chain, and the
+    /// balance snapshots other than the head and checkpoint ones. Does nothing if pruning is
+    /// disabled.
+    pub fn prune(&mut self) {
+        if !self.pruning.enabled {
+            return;
+        }
+
+        // Branches forking off below this index are refused, see `max_reorg_depth`.
+        let limit = self.head.index.saturating_sub(self.max_reorg_depth);
+        while self.pruned_index < limit {
+            let fork_index = self.pruned_index as usize;
+            let main_child_hash = self.main_chain[fork_index + 1];
+            let dead_hashes: Vec<_> = self
+                .children_hashes
+                .get(&self.main_chain[fork_index])
+                .into_iter()
+                .flatten()
+                .filter(|hash| **hash != main_child_hash)
+                .copied()
+                .collect();
+            for hash in dead_hashes {
+                let removed = self.remove_subtree(&hash);
+                debug!(
+                    "pruned side branch of {} blocks at {}",
+                    removed.len(),
+                    base64::encode(hash)
+                );
+            }
+            self.pruned_index += 1;
+        }
+
+        let mut dead_orphan_hashes = vec![];
+        for unknown_hash in self.unknown_block_hashes.iter() {
+            for hash in self.children_hashes.get(unknown_hash).into_iter().flatten() {
+                if self.blocks[hash].index <= limit {
+                    dead_orphan_hashes.push(*hash);
+                }
+            }
+        }
+        for hash in dead_orphan_hashes {
+            let removed = self.remove_subtree(&hash);
+            debug!(
+                "pruned {} orphan blocks at {}",
+                removed.len(),
+                base64::encode(hash)
+            );
+        }
+
+        let interval = self.pruning.snapshot_interval.max(1);
+        let head_hash = *self.head.hash();
+        let blocks = &self.blocks;
+        let main_chain = &self.main_chain;
+        self.balance_snapshots.retain(|hash, _| {
+            let is_checkpoint = blocks.get(hash).is_some_and(|block| {
+                block.index.is_multiple_of(interval)
+                    && main_chain.get(block.index as usize) == Some(hash)
+            });
+            is_checkpoint || *hash == head_hash
+        });
+    }
+
     pub fn add_transaction(&mut self, tx: VerifiedTransaction) -> Result<()> {
-        if self.pending_transactions.contains_key(tx.hash()) {
+        if self.mempool.contains(tx.hash()) {
             return Ok(());
         }
+        self.expire_pending_transactions();
 
-        Self::try_apply_tx_to_snapshot(&tx, &mut self.pending_snapshot)?;
-        self.pending_transactions.insert(*tx.hash(), tx);
+        let (block_index, timestamp) = self.next_block_time();
+        Self::try_apply_tx_to_snapshot(&tx, block_index, timestamp, &mut self.pending_snapshot)?;
+        let hash = *tx.hash();
+        self.mempool.insert(tx, Utc::now());
+        if !self.mempool.is_overflown() {
+            return Ok(());
+        }
+
+        // Evicted transactions may have funded other pending ones, so recompute the snapshot.
+        self.evict_pending_transactions();
+        self.rebuild_pending_snapshot();
+        if !self.mempool.contains(&hash) {
+            bail!("mempool is full and the transaction fee is too low");
+        }
         Ok(())
     }
 
     fn mark_bad_block(&mut self, root_hash: &BlockHash) {
+        for hash in self.remove_subtree(root_hash) {
+            if self.bad_block_hashes.insert(hash) {
+                self.bad_block_queue.push_back(hash);
+            }
+        }
+        while self.bad_block_queue.len() > self.max_bad_blocks {
+            let hash = self.bad_block_queue.pop_front().unwrap();
+            self.bad_block_hashes.remove(&hash);
+        }
+    }
+
+    /// Removes the block with all its descendants, returning their hashes.
+    fn remove_subtree(&mut self, root_hash: &BlockHash) -> Vec<BlockHash> {
         let root_block = &self.blocks[root_hash];
         if root_block.index > 0 {
             let parent_hash = self.blocks[root_hash].prev_hash;
-            self.children_hashes
-                .get_mut(&parent_hash)
-                .unwrap()
-                .retain(|hash| hash != root_hash);
+            let siblings = self.children_hashes.get_mut(&parent_hash).unwrap();
+            siblings.retain(|hash| hash != root_hash);
+            if siblings.is_empty() && self.unknown_block_hashes.remove(&parent_hash) {
+                self.children_hashes.remove(&parent_hash);
+            }
         }
 
+        let mut removed = vec![];
         let mut stack = vec![*root_hash];
         while let Some(hash) = stack.pop() {
             self.blocks.remove(&hash);
-            self.bad_block_hashes.insert(hash);
+            self.chain_work.remove(&hash);
+            self.balance_snapshots.remove(&hash);
             if let Some(children_hashes) = self.children_hashes.remove(&hash) {
                 stack.extend(children_hashes);
             }
+            removed.push(hash);
         }
+        removed
     }
 
     fn validate_new_block(&mut self, block: &VerifiedBlock) -> Result<()> {
@@ -174,7 +702,7 @@ impl BlockForest {
         let mut stack = vec![*block.hash()];
         let mut bad_children = vec![];
 
-        // Validate all descendants down to 2 * EPOCH_SIZE generations.
+        // Validate the descendants which max hashes may depend on the block.
         while let Some(hash) = stack.pop() {
             let children_hashes = match self.children_hashes.get(&hash) {
                 Some(h) => h,
@@ -185,7 +713,7 @@ impl BlockForest {
                 let child_block = &self.blocks[child_hash];
                 match self.validate_block(child_block) {
                     Ok(()) => {
-                        if child_block.index - block.index < (2 * EPOCH_SIZE) as u64 {
+                        if child_block.index - block.index < self.consensus.retarget_depth() {
                             stack.push(*child_hash);
                         }
                     }
@@ -227,40 +755,36 @@ impl BlockForest {
                 );
             }
 
-            if block.index % EPOCH_SIZE as u64 > 0 && prev.max_hash != block.max_hash {
-                bail!(
-                    "wrong max_hash: expected {:?}, got {:?}",
-                    prev.max_hash,
-                    block.max_hash,
-                );
-            }
-        }
-
-        if let Some(expected_max_hash) = self.compute_max_hash(block) {
-            if block.max_hash != expected_max_hash {
-                bail!(
-                    "wrong max_hash: expected {:?}, got {:?}",
-                    expected_max_hash,
-                    block.max_hash
-                );
+            if let Some(expected_max_hash) = self.expected_max_hash(prev) {
+                if block.max_hash != expected_max_hash {
+                    bail!(
+                        "wrong max_hash: expected {:?}, got {:?}",
+                        expected_max_hash,
+                        block.max_hash
+                    );
+                }
             }
         }
 
         Ok(())
     }
 
-    fn compute_max_hash(&self, block: &VerifiedBlock) -> Option<BlockHash> {
-        if block.index % EPOCH_SIZE as u64 > 0 {
-            let parent = self.blocks.get(&block.prev_hash)?;
-            Some(parent.max_hash)
-        } else {
-            let mut prev_epoch = self.get_ancestors(block, EPOCH_SIZE);
-            if prev_epoch.len() != EPOCH_SIZE {
-                return None;
-            }
-            prev_epoch.reverse();
-            Some(self.compute_epoch_max_hash(&prev_epoch))
+    /// Returns the max hash of a child of the block, or `None` if some of the blocks it depends
+    /// on are unknown.
+    fn expected_max_hash(&self, parent: &VerifiedBlock) -> Option<BlockHash> {
+        let Some(window_len) = self.consensus.retarget_window(parent.index + 1) else {
+            return Some(parent.max_hash);
+        };
+
+        let mut window = self.get_ancestors(parent, window_len - 1);
+        if window.len() != window_len - 1 {
+            return None;
         }
+        window.reverse();
+        window.push(parent);
+
+        let window: Vec<&BlockAttributes> = window.into_iter().map(|block| &**block).collect();
+        Some(self.consensus.retarget(&window))
     }
 
     fn get_ancestors(&self, block: &VerifiedBlock, limit: usize) -> Vec<&VerifiedBlock> {
@@ -276,58 +800,8 @@ impl BlockForest {
         ancestors
     }
 
-    fn compute_epoch_max_hash(&self, epoch: &[&VerifiedBlock]) -> BlockHash {
-        assert_eq!(epoch.len(), EPOCH_SIZE);
-        let epoch_id = epoch[0].index / 16;
-        assert_eq!(epoch[0].index, epoch_id * EPOCH_SIZE as u64);
-        assert_eq!(
-            epoch.last().unwrap().index,
-            (epoch_id + 1) * EPOCH_SIZE as u64 - 1
-        );
-
-        let avg_duration = {
-            let mut sum_duration = Duration::zero();
-            for (prev, cur) in epoch.iter().zip(epoch.iter().skip(1)) {
-                assert_eq!(prev.max_hash, cur.max_hash);
-
-                let delta = cur.timestamp - prev.timestamp;
-                assert!(delta > Duration::zero());
-
-                sum_duration = sum_duration
-                    .checked_add(&delta)
-                    .expect("duration add overflow");
-            }
-            sum_duration / (epoch.len() - 1) as i32
-        };
-
-        let old_max_hash = BigUint::from_bytes_be(&epoch[0].max_hash);
-        let factor = (avg_duration.num_seconds() as f64 / TARGET_BLOCK_MINING_TIME_SECONDS as f64)
-            .max(0.001)
-            .min(1000.);
-
-        let max_hash = if factor > 1. {
-            old_max_hash * factor.round() as u64
-        } else {
-            old_max_hash / (1. / factor).round() as u64
-        };
-
-        let bytes = max_hash.to_bytes_be();
-        let prefix_size = bytes.len().saturating_sub(HASH_LEN);
-        let leading_zeros = HASH_LEN.saturating_sub(bytes.len());
-
-        if bytes.iter().take(prefix_size).any(|b| *b > 0) {
-            [255u8; HASH_LEN]
-        } else {
-            let mut result = [0u8; HASH_LEN];
-            for (i, byte) in (leading_zeros..HASH_LEN).zip(bytes.into_iter().skip(prefix_size)) {
-                result[i] = byte;
-            }
-            result
-        }
-    }
-
     fn is_block_connected_to_genesis(&self, hash: &BlockHash) -> bool {
-        let genesis_hash = *VerifiedBlock::genesis().hash();
+        let genesis_hash = self.main_chain[0];
         let mut last_hash = *hash;
         while last_hash != genesis_hash {
             if let Some(parent) = self.blocks.get(&last_hash) {
@@ -339,16 +813,23 @@ impl BlockForest {
         true
     }
 
-    fn validate_transaction_balances(&mut self, hash: &BlockHash) -> Result<()> {
+    /// Returns the hashes of the newly validated blocks, parents first.
+    fn validate_transaction_balances(&mut self, hash: &BlockHash) -> Result<Vec<BlockHash>> {
         if self.balance_snapshots.contains_key(hash) {
-            return Ok(());
+            return Ok(vec![]);
         }
 
-        let mut root_block = &self.blocks[hash];
-        while !self.balance_snapshots.contains_key(&root_block.prev_hash) {
-            root_block = &self.blocks[&root_block.prev_hash];
+        // The parent has been validated before, but its snapshot may have been pruned.
+        let parent_hash = self.blocks[hash].prev_hash;
+        if !self.balance_snapshots.contains_key(&parent_hash) {
+            let snapshot = self
+                .compute_snapshot(&parent_hash)
+                .context("failed to recompute parent balances")?;
+            self.balance_snapshots.insert(parent_hash, snapshot);
         }
 
+        let root_block = &self.blocks[hash];
+        let mut validated_hashes = vec![];
         let mut bad_block_hashes = vec![];
         let mut queue: VecDeque<_> = vec![root_block].into();
         'next_block: while let Some(block) = queue.pop_back() {
@@ -365,7 +846,9 @@ impl BlockForest {
             }
 
             for tx in block.transactions() {
-                if let Err(err) = Self::try_apply_tx_to_snapshot(tx, &mut snapshot) {
+                if let Err(err) =
+                    Self::try_apply_tx_to_snapshot(tx, block.index, block.timestamp, &mut snapshot)
+                {
                     debug!(
                         "failed to apply block transactions: {:#} (block {}, tx {})",
                         err,
@@ -378,6 +861,7 @@ impl BlockForest {
             }
 
             self.balance_snapshots.insert(*block.hash(), snapshot);
+            validated_hashes.push(*block.hash());
 
             if let Some(children_hashes) = self.children_hashes.get(block.hash()) {
                 for child_hash in children_hashes {
@@ -394,26 +878,55 @@ impl BlockForest {
             bail!("block transactions are invalid");
         }
 
-        Ok(())
+        Ok(validated_hashes)
+    }
+
+    /// Replays the blocks since the closest ancestor with a snapshot. Returns `None` if the block
+    /// is not connected to genesis.
+    fn compute_snapshot(&self, hash: &BlockHash) -> Option<HashMap<WalletId, AccountState>> {
+        let mut path = vec![];
+        let mut last_hash = hash;
+        while !self.balance_snapshots.contains_key(last_hash) {
+            let block = self.blocks.get(last_hash)?;
+            path.push(block);
+            last_hash = &block.prev_hash;
+        }
+
+        let mut snapshot = self.balance_snapshots[last_hash].clone();
+        for block in path.into_iter().rev() {
+            Self::try_apply_issuer_reward_to_snapshot(block, &mut snapshot).ok()?;
+            for tx in block.transactions() {
+                Self::try_apply_tx_to_snapshot(tx, block.index, block.timestamp, &mut snapshot)
+                    .ok()?;
+            }
+        }
+        Some(snapshot)
     }
 
-    fn find_head_candidate<'a>(&'a self, root: &'a Arc<VerifiedBlock>) -> &'a Arc<VerifiedBlock> {
-        let mut stack = vec![root];
-        let mut best = root;
+    /// Computes the chain work of a newly connected block and its descendants, returning the one
+    /// with the most work. Ties go to the earliest timestamp.
+    fn find_head_candidate(&mut self, root: &Arc<VerifiedBlock>) -> Arc<VerifiedBlock> {
+        let root_work = &self.chain_work[&root.prev_hash] + block_work(&root.max_hash);
+        self.chain_work.insert(*root.hash(), root_work);
+
+        let mut stack = vec![root.clone()];
+        let mut best = root.clone();
         while let Some(block) = stack.pop() {
+            let work = self.chain_work[block.hash()].clone();
+            let best_work = &self.chain_work[best.hash()];
+            if work > *best_work || work == *best_work && block.timestamp < best.timestamp {
+                best = block.clone();
+            }
+
             let children_hashes = match self.children_hashes.get(block.hash()) {
                 Some(h) => h,
                 None => continue,
             };
-
             for child_hash in children_hashes {
                 if let Some(child_block) = self.blocks.get(child_hash) {
-                    if child_block.index > best.index
-                        || child_block.index == best.index && child_block.timestamp < best.timestamp
-                    {
-                        best = child_block;
-                    }
-                    stack.push(child_block);
+                    self.chain_work
+                        .insert(*child_hash, &work + block_work(&child_block.max_hash));
+                    stack.push(child_block.clone());
                 }
             }
         }
@@ -423,40 +936,110 @@ impl BlockForest {
     fn switch_head_to(&mut self, new_head: Arc<VerifiedBlock>) {
         let lca = self.find_lca(&self.head, &new_head);
 
-        let new_branch_tx_hashes: HashSet<_> = self
-            .list_transactions(&new_head, lca)
-            .into_iter()
-            .map(|tx| *tx.hash())
-            .collect();
+        let new_branch_txs = self.list_transactions(&new_head, lca);
+        let new_branch_tx_hashes: HashSet<_> = new_branch_txs.iter().map(|tx| *tx.hash()).collect();
 
         let old_branch_txs = self.list_transactions(&self.head, lca);
 
-        let mut new_pending_transactions = HashMap::new();
-        let mut new_snapshot = self.balance_snapshots.get(new_head.hash()).unwrap().clone();
-        for tx in old_branch_txs
-            .iter()
-            .chain(self.pending_transactions.values())
-        {
-            if new_branch_tx_hashes.contains(tx.hash())
-                || new_pending_transactions.contains_key(tx.hash())
-            {
-                continue;
+        let lca = lca.clone();
+        let lca_index = lca.index;
+        let mut new_branch_hashes = vec![];
+        let mut block = &new_head;
+        while block.hash() != lca.hash() {
+            new_branch_hashes.push(*block.hash());
+            block = &self.blocks[&block.prev_hash];
+        }
+
+        let old_head = std::mem::replace(&mut self.head, new_head);
+        for hash in self.main_chain.drain(lca_index as usize + 1..) {
+            for tx in self.blocks[&hash].transactions() {
+                self.main_chain_tx_indices.remove(tx.hash());
+            }
+        }
+        for hash in new_branch_hashes.into_iter().rev() {
+            let block = &self.blocks[&hash];
+            for tx in block.transactions() {
+                self.main_chain_tx_indices.insert(*tx.hash(), block.index);
             }
+            self.main_chain.push(hash);
+        }
 
-            if let Err(err) = Self::try_apply_tx_to_snapshot(tx, &mut new_snapshot) {
-                debug!(
-                    "discarding transaction {}: {:#}",
-                    base64::encode(tx.hash()),
-                    err,
-                );
-            } else {
-                new_pending_transactions.insert(*tx.hash(), tx.clone());
+        let now = Utc::now();
+        for hash in new_branch_tx_hashes.iter() {
+            self.mempool.remove(hash);
+        }
+        let disconnected_txs: Vec<_> = old_branch_txs
+            .into_iter()
+            .filter(|tx| !new_branch_tx_hashes.contains(tx.hash()))
+            .collect();
+        for tx in disconnected_txs.iter() {
+            self.mempool.insert(tx.clone(), now);
+        }
+        self.evict_pending_transactions();
+        self.rebuild_pending_snapshot();
+
+        if lca.hash() != old_head.hash() {
+            let event = ReorgEvent {
+                old_head,
+                new_head: self.head.clone(),
+                lca,
+                disconnected_transactions: disconnected_txs,
+                reconnected_transactions: new_branch_txs,
+            };
+            info!(
+                "chain reorganization of depth {}: head {} -> {}",
+                event.depth(),
+                base64::encode(event.old_head.hash()),
+                base64::encode(event.new_head.hash())
+            );
+            // Nobody may be listening, that's fine.
+            let _ = self.reorg_sender.send(event);
+        }
+    }
+
+    fn evict_pending_transactions(&mut self) {
+        while self.mempool.is_overflown() {
+            if let Some(tx) = self.mempool.evict() {
+                debug!("evicting transaction {}", base64::encode(tx.hash()));
             }
         }
+    }
 
-        self.head = new_head;
-        self.pending_transactions = new_pending_transactions;
-        self.pending_snapshot = new_snapshot;
+    /// Reapplies the pending transactions on top of the head, dropping the ones that can never
+    /// apply. The others are kept, since they may wait for funds or an earlier nonce.
+    fn rebuild_pending_snapshot(&mut self) {
+        let mut snapshot = self.balance_snapshots[self.head.hash()].clone();
+        let (block_index, timestamp) = self.next_block_time();
+        let selected: HashSet<_> = self
+            .mempool
+            .select(usize::MAX, |tx| {
+                Self::try_apply_tx_to_snapshot(tx, block_index, timestamp, &mut snapshot)
+            })
+            .into_iter()
+            .map(|tx| *tx.hash())
+            .collect();
+
+        let confirmed = &self.balance_snapshots[self.head.hash()];
+        self.mempool.retain(|tx| {
+            let keep = selected.contains(tx.hash()) || !Self::is_stale(tx, confirmed);
+            if !keep {
+                debug!("discarding transaction {}", base64::encode(tx.hash()));
+            }
+            keep
+        });
+        self.pending_snapshot = snapshot;
+    }
+
+    /// Whether the transaction is outdated by the confirmed state of its sender.
+    fn is_stale(tx: &VerifiedTransaction, confirmed: &HashMap<WalletId, AccountState>) -> bool {
+        let Some(account) = confirmed.get(&tx.sender) else {
+            return false;
+        };
+        if tx.is_legacy() {
+            account.legacy_transactions.contains(tx.hash())
+        } else {
+            tx.nonce <= account.last_nonce
+        }
     }
 
     fn find_lca<'a>(
@@ -478,7 +1061,7 @@ impl BlockForest {
 
     fn try_apply_issuer_reward_to_snapshot(
         block: &VerifiedBlock,
-        snapshot: &mut HashMap<WalletId, u64>,
+        snapshot: &mut HashMap<WalletId, AccountState>,
     ) -> Result<()> {
         let mut reward = block.reward;
         for tx in block.transactions() {
@@ -487,60 +1070,746 @@ impl BlockForest {
                 .context("reward + fees overflows u64")?;
         }
 
-        let old_balance = *snapshot.get(&block.issuer).unwrap_or(&0);
-        let new_balance = old_balance
+        let mut issuer = snapshot.get(&block.issuer).cloned().unwrap_or_default();
+        issuer.balance = issuer
+            .balance
             .checked_add(reward)
             .context("issuer balance overflows u64")?;
 
-        if new_balance > 0 {
-            snapshot.insert(block.issuer.clone(), new_balance);
-        } else {
-            snapshot.remove(&block.issuer);
-        }
-
+        Self::update_account(snapshot, &block.issuer, issuer);
         Ok(())
     }
 
+    /// Index and timestamp that timelocks of pending transactions are checked against. The next
+    /// block timestamp is not known yet, but it is greater than the head one.
+    fn next_block_time(&self) -> (u64, DateTime<Utc>) {
+        (self.head.index + 1, self.head.timestamp)
+    }
+
     fn try_apply_tx_to_snapshot(
         tx: &VerifiedTransaction,
-        snapshot: &mut HashMap<WalletId, u64>,
+        block_index: u64,
+        timestamp: DateTime<Utc>,
+        snapshot: &mut HashMap<WalletId, AccountState>,
     ) -> Result<()> {
-        let old_sender_balance = *snapshot.get(&tx.sender).unwrap_or(&0);
-        let new_sender_balance = old_sender_balance
+        let mut sender = snapshot.get(&tx.sender).cloned().unwrap_or_default();
+        match sender.multisig_policy.as_ref() {
+            Some(policy) => policy.check_cosignatures(&tx.cosignatures)?,
+            None if !tx.cosignatures.is_empty() => bail!("sender has no multisig policy"),
+            None => {}
+        }
+        if tx.is_legacy() {
+            if !sender.legacy_transactions.insert(*tx.hash()) {
+                bail!("legacy transaction is already confirmed");
+            }
+        } else {
+            if tx.nonce <= sender.last_nonce {
+                bail!(
+                    "nonce {} is not greater than the last sender nonce {}",
+                    tx.nonce,
+                    sender.last_nonce
+                );
+            }
+            sender.last_nonce = tx.nonce;
+        }
+        sender
+            .spendable_balance(block_index, timestamp)
             .checked_sub(tx.amount)
             .and_then(|value| value.checked_sub(tx.fee))
             .context("sender has insufficient funds")?;
+        sender.balance -= tx.amount + tx.fee;
+        sender
+            .locked
+            .retain(|funds| !funds.timelock.is_expired(block_index, timestamp));
+        if let Some(policy) = tx.multisig_policy.as_ref() {
+            sender.multisig_policy = Some(policy.clone());
+        }
 
-        let old_receiver_balance = *snapshot.get(&tx.receiver).unwrap_or(&0);
-        let new_receiver_balance = old_receiver_balance
+        let mut receiver = if tx.receiver == tx.sender {
+            sender.clone()
+        } else {
+            snapshot.get(&tx.receiver).cloned().unwrap_or_default()
+        };
+        receiver.balance = receiver
+            .balance
             .checked_add(tx.amount)
             .context("receiver balance overflows u64")?;
-
-        for (key, value) in [
-            (tx.sender.clone(), new_sender_balance),
-            (tx.receiver.clone(), new_receiver_balance),
-        ] {
-            if value > 0 {
-                snapshot.insert(key, value);
-            } else {
-                snapshot.remove(&key);
+        if let Some(timelock) = tx.timelock {
+            if !timelock.is_expired(block_index, timestamp) {
+                receiver.locked.push(LockedFunds {
+                    amount: tx.amount,
+                    timelock,
+                });
             }
         }
 
+        Self::update_account(snapshot, &tx.sender, sender);
+        Self::update_account(snapshot, &tx.receiver, receiver);
+
         Ok(())
     }
 
+    fn update_account(
+        snapshot: &mut HashMap<WalletId, AccountState>,
+        wallet: &WalletId,
+        account: AccountState,
+    ) {
+        if account != AccountState::default() {
+            snapshot.insert(wallet.clone(), account);
+        } else {
+            snapshot.remove(wallet);
+        }
+    }
+
+    /// Lists transactions of the blocks in chain order, oldest first.
     fn list_transactions(
         &self,
         inclusive_from: &Arc<VerifiedBlock>,
         exclusive_to: &Arc<VerifiedBlock>,
     ) -> Vec<VerifiedTransaction> {
-        let mut transactions = vec![];
+        let mut blocks = vec![];
         let mut block = inclusive_from;
         while block.hash() != exclusive_to.hash() {
-            transactions.extend(block.transactions().iter().cloned());
+            blocks.push(block);
             block = &self.blocks[&block.prev_hash];
         }
-        transactions
+        blocks
+            .into_iter()
+            .rev()
+            .flat_map(|block| block.transactions().iter().cloned())
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_store::FileBlockStore,
+        consensus::{DifficultyAdjustment, GenesisParams},
+        data::{
+            Block, MultisigPolicy, Transaction, CONDITIONAL_TRANSACTION_VERSION, HASH_LEN,
+            LEGACY_TRANSACTION_VERSION, MAX_REWARD,
+        },
+        test_util::{make_child, make_unverified_child, test_key},
+    };
+
+    use chrono::Duration;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use rsa::RSAPrivateKey;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_wallet_queries() {
+        let key = test_key();
+        let wallet: WalletId = key.to_public_key().into();
+        let receiver = WalletId::genesis();
+
+        let mut forest = BlockForest::new();
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        forest.add_block(first.clone()).unwrap();
+
+        let tx = VerifiedTransaction::sign(&key, receiver.clone(), 30, 5, 1, "hi".into()).unwrap();
+        forest.add_transaction(tx.clone()).unwrap();
+        assert_eq!(forest.confirmation_depth(tx.hash()), Some(0));
+
+        let second = make_child(&first, vec![tx.clone()]);
+        forest.add_block(second.clone()).unwrap();
+        let third = make_child(&second, vec![]);
+        forest.add_block(third.clone()).unwrap();
+
+        assert_eq!(forest.balance(&wallet), 100 + 100 - 30 + 100);
+        assert_eq!(forest.balance(&receiver), 30);
+        assert_eq!(forest.balance_at(&wallet, first.hash()), Some(100));
+        assert_eq!(forest.balance_at(&receiver, first.hash()), Some(0));
+        assert_eq!(forest.balance_at(&wallet, &[0u8; HASH_LEN]), None);
+
+        assert_eq!(forest.confirmation_depth(tx.hash()), Some(2));
+        assert_eq!(forest.confirmation_depth(&[0u8; HASH_LEN]), None);
+        assert_eq!(
+            forest.find_main_chain_block(2).map(|block| *block.hash()),
+            Some(*second.hash())
+        );
+
+        let history = forest.wallet_history(&wallet);
+        let kinds: Vec<_> = history.iter().map(|event| event.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                WalletEventKind::MiningReward(100),
+                WalletEventKind::MiningReward(105),
+                WalletEventKind::Sent(tx.clone()),
+                WalletEventKind::MiningReward(100),
+            ]
+        );
+        assert_eq!(
+            forest.wallet_history(&receiver),
+            vec![WalletEvent {
+                block_index: 2,
+                block_hash: *second.hash(),
+                kind: WalletEventKind::Received(tx),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.log");
+        let key = test_key();
+        let mut forest = BlockForest::with_store(
+            BlockForestConfig::default(),
+            Box::new(FileBlockStore::open(&path).unwrap()),
+        )
+        .unwrap();
+
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        forest.add_block(first.clone()).unwrap();
+        let tx =
+            VerifiedTransaction::sign(&key, WalletId::genesis(), 1000, 0, 1, "".into()).unwrap();
+        let unaffordable = make_child(&first, vec![tx]);
+        assert!(forest.add_block(unaffordable).is_err());
+
+        let second = make_child(&first, vec![]);
+        let third = make_child(&second, vec![]);
+        forest.add_block(third.clone()).unwrap();
+        forest.add_block(second.clone()).unwrap();
+        assert_eq!(forest.head().hash(), third.hash());
+
+        assert_eq!(
+            FileBlockStore::open(&path).unwrap().load().unwrap(),
+            vec![first.to_block(), second.to_block(), third.to_block()]
+        );
+    }
+
+    /// Fails to append blocks while `is_broken` is set.
+    #[derive(Default)]
+    struct FlakyStore {
+        is_broken: Arc<AtomicBool>,
+        blocks: Vec<Block>,
+    }
+
+    impl BlockStore for FlakyStore {
+        fn append(&mut self, block: &VerifiedBlock) -> Result<()> {
+            if self.is_broken.load(Ordering::SeqCst) {
+                bail!("disk is full");
+            }
+            self.blocks.push(block.to_block());
+            Ok(())
+        }
+
+        fn contains(&self, hash: &BlockHash) -> bool {
+            self.blocks
+                .iter()
+                .any(|block| block.compute_hash() == *hash)
+        }
+
+        fn get(&mut self, hash: &BlockHash) -> Result<Option<Block>> {
+            Ok(self
+                .blocks
+                .iter()
+                .find(|block| block.compute_hash() == *hash)
+                .cloned())
+        }
+
+        fn load(&mut self) -> Result<Vec<Block>> {
+            Ok(self.blocks.clone())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_store_failure() {
+        let is_broken = Arc::new(AtomicBool::new(true));
+        let store = FlakyStore {
+            is_broken: is_broken.clone(),
+            ..Default::default()
+        };
+        let mut forest =
+            BlockForest::with_store(BlockForestConfig::default(), Box::new(store)).unwrap();
+
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        let second = make_child(&first, vec![]);
+        forest.add_block(second.clone()).unwrap();
+        let err = forest.add_block(first.clone()).unwrap_err();
+        assert!(err.is::<BlockNotPersisted>());
+        assert!(forest.find_block(first.hash()).is_none());
+        assert!(forest.find_block(second.hash()).is_none());
+        assert_eq!(forest.head().hash(), VerifiedBlock::genesis().hash());
+
+        is_broken.store(false, Ordering::SeqCst);
+        forest.add_block(first.clone()).unwrap();
+        forest.add_block(second.clone()).unwrap();
+        assert_eq!(forest.head().hash(), second.hash());
+        let store = forest.store.as_mut().unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![first.to_block(), second.to_block()]
+        );
+    }
+
+    #[test]
+    fn test_nonces() {
+        let key = test_key();
+        let wallet: WalletId = key.to_public_key().into();
+        let receiver = WalletId::genesis();
+
+        let mut forest = BlockForest::new();
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        forest.add_block(first.clone()).unwrap();
+        assert_eq!(forest.next_nonce(&wallet), 1);
+
+        let tx = VerifiedTransaction::sign(&key, receiver.clone(), 10, 0, 3, "".into()).unwrap();
+        forest.add_transaction(tx.clone()).unwrap();
+        assert_eq!(forest.next_nonce(&wallet), 4);
+
+        let stale = VerifiedTransaction::sign(&key, receiver.clone(), 20, 0, 2, "".into()).unwrap();
+        assert!(forest.add_transaction(stale).is_err());
+
+        let second = make_child(&first, vec![tx.clone()]);
+        forest.add_block(second.clone()).unwrap();
+        assert_eq!(forest.head().hash(), second.hash());
+        assert_eq!(
+            forest
+                .account_at(&wallet, second.hash())
+                .unwrap()
+                .last_nonce,
+            3
+        );
+
+        let replay = make_child(&second, vec![tx]);
+        assert!(forest.add_block(replay).is_err());
+        assert_eq!(forest.head().hash(), second.hash());
+    }
+
+    #[test]
+    fn test_legacy_replay() {
+        let key = test_key();
+        let mut forest = BlockForest::new();
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        forest.add_block(first.clone()).unwrap();
+
+        let tx = VerifiedTransaction::sign_transaction(
+            &key,
+            Transaction {
+                version: LEGACY_TRANSACTION_VERSION,
+                amount: 10,
+                fee: 0,
+                nonce: 0,
+                comment: "".into(),
+                sender: key.to_public_key().into(),
+                receiver: WalletId::genesis(),
+                signature: vec![],
+                timelock: None,
+                multisig_policy: None,
+                cosignatures: vec![],
+            },
+        )
+        .unwrap();
+        let twice = make_child(&first, vec![tx.clone(), tx.clone()]);
+        assert!(forest.add_block(twice).is_err());
+
+        let second = make_child(&first, vec![tx.clone()]);
+        forest.add_block(second.clone()).unwrap();
+        assert!(forest.add_transaction(tx.clone()).is_err());
+        let replay = make_child(&second, vec![tx]);
+        assert!(forest.add_block(replay).is_err());
+        assert_eq!(forest.head().hash(), second.hash());
+    }
+
+    #[test]
+    fn test_mempool_limits() {
+        let key = test_key();
+        let wallet: WalletId = key.to_public_key().into();
+        let receiver = WalletId::genesis();
+        let sign = |fee, nonce| {
+            VerifiedTransaction::sign(&key, receiver.clone(), 1, fee, nonce, "".into()).unwrap()
+        };
+
+        let mut forest = BlockForest::with_config(BlockForestConfig {
+            mempool: MempoolConfig {
+                max_transactions: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        forest.add_block(first.clone()).unwrap();
+
+        let cheap = sign(1, 1);
+        let medium = sign(20, 2);
+        forest.add_transaction(cheap.clone()).unwrap();
+        forest.add_transaction(medium.clone()).unwrap();
+        assert!(forest.add_transaction(sign(0, 3)).is_err());
+        assert_eq!(forest.next_nonce(&wallet), 3);
+
+        let expensive = sign(30, 3);
+        forest.add_transaction(expensive.clone()).unwrap();
+        assert!(!forest.pending_transactions().contains_key(cheap.hash()));
+        assert_eq!(forest.select_transactions(1), vec![medium.clone()]);
+        assert_eq!(
+            forest.select_transactions(10),
+            vec![medium.clone(), expensive.clone()]
+        );
+
+        // `spend` has a greater nonce, so `expensive` is no longer valid.
+        let spend = sign(9, 10);
+        let second = make_child(&first, vec![medium, spend]);
+        forest.add_block(second).unwrap();
+        assert!(forest.pending_transactions().is_empty());
+        assert_eq!(forest.next_nonce(&wallet), 11);
+    }
+
+    #[test]
+    fn test_reorg() {
+        let key = test_key();
+        let receiver = WalletId::genesis();
+        let sign = |nonce| {
+            VerifiedTransaction::sign(&key, receiver.clone(), 1, 1, nonce, "".into()).unwrap()
+        };
+        let fork = |parent: &VerifiedBlock, transactions| {
+            let mut block: Block = make_child(parent, transactions).into();
+            block.timestamp += Duration::seconds(1);
+            block.verified().unwrap()
+        };
+
+        // Pruning would drop the old branch before it gets too deep.
+        let mut forest = BlockForest::with_config(BlockForestConfig {
+            max_reorg_depth: 2,
+            pruning: PruningConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut reorgs = forest.subscribe_reorgs();
+        let genesis = VerifiedBlock::genesis();
+        let (first, second) = (sign(1), sign(2));
+
+        let a1 = make_child(&genesis, vec![]);
+        let a2 = make_child(&a1, vec![first.clone(), second.clone()]);
+        forest.add_block(a1.clone()).unwrap();
+        forest.add_block(a2.clone()).unwrap();
+
+        let b1 = fork(&genesis, vec![]);
+        let b2 = make_child(&b1, vec![first.clone()]);
+        let b3 = make_child(&b2, vec![]);
+        forest.add_block(b1.clone()).unwrap();
+        forest.add_block(b2.clone()).unwrap();
+        assert_eq!(forest.head().hash(), a2.hash());
+        assert!(reorgs.try_recv().is_err());
+
+        forest.add_block(b3.clone()).unwrap();
+        assert_eq!(forest.head().hash(), b3.hash());
+        let event = reorgs.try_recv().unwrap();
+        assert_eq!(event.old_head.hash(), a2.hash());
+        assert_eq!(event.new_head.hash(), b3.hash());
+        assert_eq!(event.lca.hash(), genesis.hash());
+        assert_eq!(event.depth(), 2);
+        assert_eq!(event.disconnected_transactions, vec![second.clone()]);
+        assert_eq!(event.reconnected_transactions, vec![first]);
+        assert!(forest.pending_transactions().contains_key(second.hash()));
+
+        // Extending the head is not a reorg.
+        let b4 = make_child(&b3, vec![]);
+        forest.add_block(b4.clone()).unwrap();
+        assert!(reorgs.try_recv().is_err());
+
+        // The old branch now forks 4 blocks below the head.
+        let a3 = make_child(&a2, vec![]);
+        for _ in 0..2 {
+            let err = forest.add_block(a3.clone()).unwrap_err();
+            assert!(err.is::<ReorgTooDeep>());
+        }
+        assert!(forest.find_block(a3.hash()).is_none());
+        assert!(!forest.bad_block_hashes.contains(a3.hash()));
+
+        let c3 = fork(&b2, vec![]);
+        forest.add_block(c3.clone()).unwrap();
+        assert!(forest.find_block(c3.hash()).is_some());
+    }
+
+    #[test]
+    fn test_fork_choice_by_work() {
+        let mut forest = BlockForest::with_config(BlockForestConfig {
+            consensus: ConsensusParams {
+                difficulty: DifficultyAdjustment::MovingAverage { window: 1 },
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mine = |forest: &mut BlockForest, parent: &VerifiedBlock, seconds| {
+            let mut block: Block = make_child(parent, vec![]).into();
+            block.timestamp = parent.timestamp + Duration::seconds(seconds);
+            block.max_hash = forest.expected_max_hash(parent).unwrap();
+            let block = loop {
+                if let Ok(block) = block.clone().verified() {
+                    break block;
+                }
+                block.nonce += 1;
+            };
+            forest.add_block(block.clone()).unwrap();
+            block
+        };
+        let genesis = forest.genesis().clone();
+
+        // Fast blocks make the next one harder to mine.
+        let a1 = mine(&mut forest, &genesis, 1);
+        let a2 = mine(&mut forest, &a1, 1);
+        assert_eq!(forest.chain_work(a2.hash()), Some(&BigUint::from(5u8)));
+
+        let mut b = mine(&mut forest, &genesis, 100);
+        for _ in 0..2 {
+            b = mine(&mut forest, &b, 100);
+        }
+        assert_eq!(b.index, 3);
+        assert_eq!(forest.chain_work(b.hash()), Some(&BigUint::from(4u8)));
+        assert_eq!(forest.head().hash(), a2.hash());
+
+        // Equal work does not replace the head.
+        b = mine(&mut forest, &b, 100);
+        assert_eq!(forest.head().hash(), a2.hash());
+        b = mine(&mut forest, &b, 100);
+        assert_eq!(forest.head().hash(), b.hash());
+    }
+
+    #[test]
+    fn test_pruning() {
+        let key = test_key();
+        let wallet: WalletId = key.to_public_key().into();
+        let fork = |parent: &VerifiedBlock| {
+            let mut block: Block = make_child(parent, vec![]).into();
+            block.timestamp += Duration::seconds(1);
+            block.verified().unwrap()
+        };
+
+        let mut forest = BlockForest::with_config(BlockForestConfig {
+            max_reorg_depth: 2,
+            max_bad_blocks: 2,
+            pruning: PruningConfig {
+                enabled: true,
+                snapshot_interval: 2,
+            },
+            ..Default::default()
+        });
+        let genesis = VerifiedBlock::genesis();
+        let mut main_chain = vec![genesis.clone()];
+        let mut extend = |forest: &mut BlockForest| {
+            let block = make_child(main_chain.last().unwrap(), vec![]);
+            forest.add_block(block.clone()).unwrap();
+            main_chain.push(block.clone());
+            block
+        };
+
+        extend(&mut forest);
+        let a2 = extend(&mut forest);
+        let b1 = fork(&genesis);
+        let b2 = make_child(&b1, vec![]);
+        forest.add_block(b1.clone()).unwrap();
+        forest.add_block(b2.clone()).unwrap();
+        let c3 = fork(&a2);
+        forest.add_block(c3.clone()).unwrap();
+
+        let mut orphan: Block = make_child(&a2, vec![]).into();
+        orphan.prev_hash = [7u8; HASH_LEN];
+        let orphan = orphan.verified().unwrap();
+        forest.add_block(orphan.clone()).unwrap();
+
+        extend(&mut forest);
+        let a4 = extend(&mut forest);
+        assert!(forest.find_block(b1.hash()).is_none());
+        assert!(forest.find_block(b2.hash()).is_none());
+        assert!(forest.find_block(c3.hash()).is_some());
+        assert!(forest.find_block(orphan.hash()).is_some());
+
+        let a5 = extend(&mut forest);
+        assert!(forest.find_block(c3.hash()).is_none());
+        assert!(forest.find_block(orphan.hash()).is_none());
+        assert!(forest.unknown_block_hashes().is_empty());
+
+        let mut snapshot_hashes: Vec<_> = forest.balance_snapshots.keys().copied().collect();
+        snapshot_hashes.sort();
+        let mut expected = vec![*genesis.hash(), *a2.hash(), *a4.hash(), *a5.hash()];
+        expected.sort();
+        assert_eq!(snapshot_hashes, expected);
+        for block in main_chain.iter() {
+            assert_eq!(
+                forest.balance_at(&wallet, block.hash()),
+                Some(100 * block.index)
+            );
+        }
+
+        // A block on top of a pruned snapshot still gets validated.
+        let c4 = fork(&main_chain[3]);
+        forest.add_block(c4.clone()).unwrap();
+        assert_eq!(forest.balance_at(&wallet, c4.hash()), Some(400));
+
+        let bad_blocks: Vec<_> = (0..3)
+            .map(|i| {
+                let mut block: Block = make_child(&a5, vec![]).into();
+                block.index += 1;
+                block.nonce = i;
+                block.verified().unwrap()
+            })
+            .collect();
+        for block in bad_blocks.iter() {
+            assert!(forest.add_block(block.clone()).is_err());
+        }
+        assert_eq!(forest.bad_block_hashes.len(), 2);
+        assert!(!forest.bad_block_hashes.contains(bad_blocks[0].hash()));
+        assert!(forest.bad_block_hashes.contains(bad_blocks[2].hash()));
+    }
+
+    #[test]
+    fn test_custom_consensus() {
+        let consensus = ConsensusParams {
+            max_reward: 100,
+            difficulty: DifficultyAdjustment::MovingAverage { window: 2 },
+            genesis: GenesisParams {
+                timestamp: VerifiedBlock::genesis().timestamp + Duration::seconds(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut forest = BlockForest::with_config(BlockForestConfig {
+            consensus,
+            ..Default::default()
+        });
+        let genesis = forest.genesis().clone();
+        assert_ne!(genesis.hash(), VerifiedBlock::genesis().hash());
+        assert!(forest
+            .add_block(make_child(&VerifiedBlock::genesis(), vec![]))
+            .is_err());
+
+        // Blocks of this network do not pass the rules of the main one.
+        let rules = forest.block_rules().clone();
+        let block = make_unverified_child(&genesis, vec![]);
+        assert!(block.clone().verified().is_err());
+        block.verified_with(&rules).unwrap();
+
+        let mut generous = make_unverified_child(&genesis, vec![]);
+        generous.reward = 101;
+        assert!(generous.clone().verified_with(&rules).is_err());
+        let lax_rules = ConsensusParams {
+            max_reward: MAX_REWARD,
+            ..forest.consensus().clone()
+        }
+        .block_rules();
+        let generous = generous.verified_with(&lax_rules).unwrap();
+        assert!(forest.add_block(generous).is_err());
+
+        // Blocks come twice as fast as the target, so the max hash halves.
+        let mine = |parent: &VerifiedBlock, max_hash: BlockHash| {
+            let mut block = make_unverified_child(parent, vec![]);
+            block.timestamp = parent.timestamp + Duration::seconds(5);
+            block.max_hash = max_hash;
+            loop {
+                if let Ok(block) = block.clone().verified_with(&rules) {
+                    return block;
+                }
+                block.nonce += 1;
+            }
+        };
+        let mut head = genesis;
+        for _ in 0..2 {
+            head = Arc::new(mine(&head, forest.next_max_hash()));
+            forest.add_block((*head).clone()).unwrap();
+        }
+        let max_hash = forest.next_max_hash();
+        assert_eq!(max_hash[0], 0x7f);
+        assert!(forest.add_block(mine(&head, head.max_hash)).is_err());
+        forest.add_block(mine(&head, max_hash)).unwrap();
+        assert_eq!(forest.head().index, 3);
+    }
+
+    #[test]
+    fn test_multisig_and_timelocks() {
+        let key = test_key();
+        let wallet: WalletId = key.to_public_key().into();
+        let [treasury_key, a, b, c] = [1, 2, 3, 4]
+            .map(|seed| RSAPrivateKey::new(&mut ChaCha20Rng::seed_from_u64(seed), 768).unwrap());
+        let treasury: WalletId = treasury_key.to_public_key().into();
+        let make_tx = |sender: &WalletId, receiver: &WalletId, amount, nonce| Transaction {
+            version: CONDITIONAL_TRANSACTION_VERSION,
+            amount,
+            fee: 0,
+            nonce,
+            comment: "".into(),
+            sender: sender.clone(),
+            receiver: receiver.clone(),
+            signature: vec![],
+            timelock: None,
+            multisig_policy: None,
+            cosignatures: vec![],
+        };
+        let cosign = |mut tx: Transaction, signers: &[&RSAPrivateKey]| {
+            for signer in signers {
+                tx.add_cosignature(signer).unwrap();
+            }
+            tx.verified().unwrap()
+        };
+
+        let mut forest = BlockForest::new();
+        let mut head = make_child(&VerifiedBlock::genesis(), vec![]);
+        forest.add_block(head.clone()).unwrap();
+
+        let mut deposit = make_tx(&wallet, &treasury, 60, 1);
+        deposit.timelock = Some(Timelock::BlockIndex(4));
+        let mut setup = make_tx(&treasury, &treasury, 0, 1);
+        setup.multisig_policy = Some(MultisigPolicy {
+            threshold: 2,
+            signers: [&a, &b, &c].map(|k| k.to_public_key().into()).to_vec(),
+        });
+        let txs = vec![
+            VerifiedTransaction::sign_transaction(&key, deposit).unwrap(),
+            VerifiedTransaction::sign_transaction(&treasury_key, setup).unwrap(),
+        ];
+        head = make_child(&head, txs);
+        forest.add_block(head.clone()).unwrap();
+
+        let account = forest.account_at(&treasury, head.hash()).unwrap();
+        assert_eq!(account.balance, 60);
+        assert_eq!(account.spendable_balance(3, head.timestamp), 0);
+        assert_eq!(account.spendable_balance(4, head.timestamp), 60);
+        assert!(account.multisig_policy.is_some());
+
+        // Funds are locked until block 4.
+        let payout = cosign(make_tx(&treasury, &wallet, 10, 2), &[&a, &b]);
+        assert!(forest.add_transaction(payout.clone()).is_err());
+        let locked_block = make_child(&head, vec![payout.clone()]);
+        assert!(forest.add_block(locked_block).is_err());
+
+        head = make_child(&head, vec![]);
+        forest.add_block(head.clone()).unwrap();
+
+        // The treasury key alone can no longer spend, neither can too few or foreign signers.
+        let signed = VerifiedTransaction::sign_transaction(
+            &treasury_key,
+            make_tx(&treasury, &wallet, 10, 2),
+        )
+        .unwrap();
+        assert!(forest.add_transaction(signed).is_err());
+        let one_signer = cosign(make_tx(&treasury, &wallet, 10, 2), &[&c]);
+        assert!(forest.add_transaction(one_signer).is_err());
+        let foreign = cosign(make_tx(&treasury, &wallet, 10, 2), &[&a, &treasury_key]);
+        assert!(forest.add_transaction(foreign).is_err());
+
+        forest.add_transaction(payout.clone()).unwrap();
+        head = make_child(&head, forest.select_transactions(10));
+        assert_eq!(head.transactions(), &[payout]);
+        forest.add_block(head.clone()).unwrap();
+        assert_eq!(forest.balance(&treasury), 50);
+        assert!(forest
+            .account_at(&treasury, head.hash())
+            .unwrap()
+            .locked
+            .is_empty());
     }
 }
@@ -0,0 +1,224 @@
+use crate::data::{Block, BlockHash, VerifiedBlock};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::warn;
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub trait BlockStore: Send + Sync {
+    /// Persists the block. Storing an already known block is a no-op.
+    fn append(&mut self, block: &VerifiedBlock) -> Result<()>;
+
+    fn contains(&self, hash: &BlockHash) -> bool;
+
+    fn get(&mut self, hash: &BlockHash) -> Result<Option<Block>>;
+
+    /// Returns all the stored blocks in the order they were appended.
+    fn load(&mut self) -> Result<Vec<Block>>;
+
+    /// Makes the appended blocks durable.
+    fn flush(&mut self) -> Result<()>;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Append-only log of blocks, one json-serialized block per line.
+pub struct FileBlockStore {
+    path: PathBuf,
+    file: File,
+    index: HashMap<BlockHash, u64>,
+    len: u64,
+}
+
+impl FileBlockStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .context(format!("failed to open {}", path.display()))?;
+
+        let mut store = Self {
+            path,
+            file,
+            index: HashMap::new(),
+            len: 0,
+        };
+        store.rebuild_index()?;
+        Ok(store)
+    }
+
+    fn rebuild_index(&mut self) -> Result<()> {
+        let (records, len) = self.scan()?;
+        for (offset, block) in records {
+            self.index.insert(block.compute_hash(), offset);
+        }
+
+        if len < self.file.metadata()?.len() {
+            self.file
+                .set_len(len)
+                .context("failed to truncate block log")?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// Returns the records with their offsets and the length of the readable prefix of the log.
+    fn scan(&mut self) -> Result<(Vec<(u64, Block)>, u64)> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&self.file);
+
+        let mut records = vec![];
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let bytes_read = reader
+                .read_line(&mut line)
+                .context("failed to read block log")?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            let record = if line.ends_with('\n') {
+                serde_json::from_str(&line).context("failed to deserialize block")
+            } else {
+                Err(anyhow!("record is not terminated"))
+            };
+            match record {
+                Ok(block) => records.push((offset, block)),
+                Err(err) => {
+                    line.clear();
+                    if reader.read_line(&mut line)? > 0 {
+                        bail!("block log is corrupted at offset {}: {:#}", offset, err);
+                    }
+
+                    // A torn write of the last record is expected after a crash.
+                    warn!(
+                        "dropping the last record of {}: {:#}",
+                        self.path.display(),
+                        err
+                    );
+                    break;
+                }
+            }
+            offset += bytes_read as u64;
+        }
+        Ok((records, offset))
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn append(&mut self, block: &VerifiedBlock) -> Result<()> {
+        if self.index.contains_key(block.hash()) {
+            return Ok(());
+        }
+
+        let mut record =
+            serde_json::to_vec(&block.to_block()).context("failed to serialize block")?;
+        record.push(b'\n');
+        self.file
+            .write_all(&record)
+            .context(format!("failed to write {}", self.path.display()))?;
+
+        self.index.insert(*block.hash(), self.len);
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    fn contains(&self, hash: &BlockHash) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    fn get(&mut self, hash: &BlockHash) -> Result<Option<Block>> {
+        let Some(&offset) = self.index.get(hash) else {
+            return Ok(None);
+        };
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(&self.file).read_line(&mut line)?;
+        let block: Block = serde_json::from_str(&line).context("failed to deserialize block")?;
+        if &block.compute_hash() != hash {
+            bail!("block log is corrupted at offset {}", offset);
+        }
+        Ok(Some(block))
+    }
+
+    fn load(&mut self) -> Result<Vec<Block>> {
+        let (records, _) = self.scan()?;
+        Ok(records.into_iter().map(|(_, block)| block).collect())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file
+            .sync_data()
+            .context(format!("failed to sync {}", self.path.display()))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::make_child;
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.log");
+
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        let second = make_child(&first, vec![]);
+        {
+            let mut store = FileBlockStore::open(&path).unwrap();
+            store.append(&first).unwrap();
+            store.append(&second).unwrap();
+            store.append(&first).unwrap();
+        }
+
+        let mut store = FileBlockStore::open(&path).unwrap();
+        assert!(store.contains(first.hash()));
+        assert_eq!(store.get(second.hash()).unwrap(), Some(second.to_block()));
+        assert_eq!(
+            store.load().unwrap(),
+            vec![first.to_block(), second.to_block()]
+        );
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.log");
+
+        let first = make_child(&VerifiedBlock::genesis(), vec![]);
+        FileBlockStore::open(&path).unwrap().append(&first).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"index\": 2")
+            .unwrap();
+
+        let mut store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(store.load().unwrap(), vec![first.to_block()]);
+
+        let second = make_child(&first, vec![]);
+        store.append(&second).unwrap();
+        let mut store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![first.to_block(), second.to_block()]
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use crate::{
+    block_forest::BlockForest,
+    codec::{from_binary, to_binary},
+    data::{Block, BlockHash},
+    util::serialize_base64,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::Serialize;
+
+use std::{
+    io::{BufRead, Write},
+    str::FromStr,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Binary archives start with these bytes, followed by blocks prefixed with their u32 length.
+/// JSON archives hold one block per line, so the format is told apart by the first byte.
+const BINARY_ARCHIVE_MAGIC: &[u8; 8] = b"\xb1bbnch01";
+const MAX_BINARY_RECORD_SIZE: usize = 64 << 20;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Newline-delimited JSON, readable and easy to process with other tools.
+    Json,
+    /// Length-prefixed binary encoding, the one used on the wire.
+    Binary,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "binary" => Ok(Self::Binary),
+            _ => bail!("unknown archive format {:?}, expected json or binary", s),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    format: ArchiveFormat,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, format: ArchiveFormat) -> Result<Self> {
+        if format == ArchiveFormat::Binary {
+            writer
+                .write_all(BINARY_ARCHIVE_MAGIC)
+                .context("failed to write archive header")?;
+        }
+        Ok(Self { writer, format })
+    }
+
+    pub fn write(&mut self, block: &Block) -> Result<()> {
+        match self.format {
+            ArchiveFormat::Json => {
+                let mut record = serde_json::to_vec(block).context("failed to serialize block")?;
+                record.push(b'\n');
+                self.writer.write_all(&record)
+            }
+            ArchiveFormat::Binary => {
+                let payload = to_binary(block)?;
+                let len = u32::try_from(payload.len()).context("block is too large")?;
+                self.writer
+                    .write_u32::<LittleEndian>(len)
+                    .and_then(|_| self.writer.write_all(&payload))
+            }
+        }
+        .context("failed to write block")
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush().context("failed to flush archive")?;
+        Ok(self.writer)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reads the blocks of an archive in either format.
+pub struct ArchiveReader<R: BufRead> {
+    reader: R,
+    format: ArchiveFormat,
+    position: u64,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let first_byte = reader
+            .fill_buf()
+            .context("failed to read archive")?
+            .first()
+            .copied();
+        let format = if first_byte == Some(BINARY_ARCHIVE_MAGIC[0]) {
+            let mut magic = [0; BINARY_ARCHIVE_MAGIC.len()];
+            reader
+                .read_exact(&mut magic)
+                .context("failed to read archive header")?;
+            if &magic != BINARY_ARCHIVE_MAGIC {
+                bail!("unknown archive header");
+            }
+            ArchiveFormat::Binary
+        } else {
+            ArchiveFormat::Json
+        };
+
+        Ok(Self {
+            reader,
+            format,
+            position: 0,
+        })
+    }
+
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        match self.format {
+            ArchiveFormat::Json => {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                Ok(Some(
+                    serde_json::from_str(&line).context("failed to deserialize block")?,
+                ))
+            }
+            ArchiveFormat::Binary => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let len = self
+                    .reader
+                    .read_u32::<LittleEndian>()
+                    .context("record is truncated")? as usize;
+                if len > MAX_BINARY_RECORD_SIZE {
+                    bail!("record of {} bytes is too large", len);
+                }
+                let mut payload = vec![0; len];
+                self.reader
+                    .read_exact(&mut payload)
+                    .context("record is truncated")?;
+                from_binary(&payload).map(Some)
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for ArchiveReader<R> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self
+            .read_block()
+            .context(format!("failed to read block #{}", self.position))
+            .transpose()?;
+        self.position += 1;
+        Some(result)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    /// Number of blocks that passed verification.
+    pub block_count: u64,
+    pub head_index: u64,
+    #[serde(serialize_with = "serialize_base64")]
+    pub head_hash: BlockHash,
+    /// Import stops at the first block which fails verification.
+    pub first_invalid: Option<InvalidBlock>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvalidBlock {
+    /// Zero-based position of the block in the archive.
+    pub position: u64,
+    pub index: u64,
+    #[serde(serialize_with = "serialize_base64")]
+    pub hash: BlockHash,
+    pub error: String,
+}
+
+/// Writes the main chain of the forest, genesis excluded. Returns the number of blocks written.
+pub fn export_chain<W: Write>(forest: &BlockForest, writer: &mut ArchiveWriter<W>) -> Result<u64> {
+    let mut count = 0;
+    for index in 1..=forest.head().index {
+        let block = forest
+            .find_main_chain_block(index)
+            .expect("main chain is contiguous");
+        writer.write(&block.to_block())?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Adds the archived chain to the forest with full verification: signatures and proof-of-work,
+/// the `max_hash` schedule, rewards and balances. The blocks must follow the forest genesis in
+/// order. Unreadable records are errors, while invalid blocks end the import and are reported.
+pub fn import_chain(
+    forest: &mut BlockForest,
+    blocks: impl IntoIterator<Item = Result<Block>>,
+) -> Result<ImportReport> {
+    let mut prev_hash = *forest.genesis().hash();
+    let mut block_count = 0;
+    let mut first_invalid = None;
+    for (position, block) in blocks.into_iter().enumerate() {
+        let block = block?;
+        let index = block.index;
+        let hash = block.compute_hash();
+
+        let result = if block.prev_hash != prev_hash {
+            Err(anyhow!(
+                "block does not follow {}",
+                base64::encode(prev_hash)
+            ))
+        } else {
+            block
+                .verified_with(forest.block_rules())
+                .context("block verification failed")
+                .and_then(|block| forest.add_block(block))
+        };
+        if let Err(err) = result {
+            first_invalid = Some(InvalidBlock {
+                position: position as u64,
+                index,
+                hash,
+                error: format!("{:#}", err),
+            });
+            break;
+        }
+
+        prev_hash = hash;
+        block_count += 1;
+    }
+
+    Ok(ImportReport {
+        block_count,
+        head_index: forest.head().index,
+        head_hash: *forest.head().hash(),
+        first_invalid,
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{VerifiedBlock, VerifiedTransaction, WalletId},
+        test_util::{make_chain, make_child, test_key},
+    };
+
+    use std::io::Cursor;
+
+    fn to_blocks(chain: &[VerifiedBlock]) -> Vec<Block> {
+        chain.iter().map(VerifiedBlock::to_block).collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let chain = to_blocks(&make_chain(3)[1..]);
+        for format in [ArchiveFormat::Json, ArchiveFormat::Binary] {
+            let mut writer = ArchiveWriter::new(vec![], format).unwrap();
+            for block in chain.iter() {
+                writer.write(block).unwrap();
+            }
+            let data = writer.finish().unwrap();
+
+            let reader = ArchiveReader::new(Cursor::new(&data)).unwrap();
+            assert_eq!(reader.format(), format);
+            let blocks: Vec<_> = reader.map(Result::unwrap).collect();
+            assert_eq!(blocks, chain);
+
+            let reader = ArchiveReader::new(Cursor::new(&data[..data.len() - 2])).unwrap();
+            let results: Vec<_> = reader.collect();
+            assert_eq!(results.len(), 3);
+            assert!(results[2].is_err());
+        }
+        assert_eq!(ArchiveReader::new(Cursor::new(b"")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut forest = BlockForest::new();
+        for block in make_chain(3).into_iter().skip(1) {
+            forest.add_block(block).unwrap();
+        }
+        let mut writer = ArchiveWriter::new(vec![], ArchiveFormat::Binary).unwrap();
+        assert_eq!(export_chain(&forest, &mut writer).unwrap(), 3);
+        let data = writer.finish().unwrap();
+
+        let mut imported = BlockForest::new();
+        let report = import_chain(
+            &mut imported,
+            ArchiveReader::new(Cursor::new(data)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report.block_count, 3);
+        assert_eq!(report.head_index, 3);
+        assert_eq!(report.head_hash, *forest.head().hash());
+        assert!(report.first_invalid.is_none());
+        assert_eq!(imported.balance(&test_key().to_public_key().into()), 300);
+    }
+
+    #[test]
+    fn test_first_invalid() {
+        let mut chain = make_chain(2);
+        let tx = VerifiedTransaction::sign(&test_key(), WalletId::genesis(), 1000, 0, 1, "".into())
+            .unwrap();
+        chain.push(make_child(&chain[2], vec![tx]));
+        chain.push(make_child(&chain[3], vec![]));
+        let chain = to_blocks(&chain[1..]);
+
+        let mut forest = BlockForest::new();
+        let report = import_chain(&mut forest, chain.iter().cloned().map(Ok)).unwrap();
+        assert_eq!(report.block_count, 2);
+        assert_eq!(report.head_index, 2);
+        let invalid = report.first_invalid.unwrap();
+        assert_eq!(invalid.position, 2);
+        assert_eq!(invalid.index, 3);
+        assert_eq!(invalid.hash, chain[2].compute_hash());
+
+        // Blocks must form a chain starting at genesis.
+        let mut forest = BlockForest::new();
+        let report = import_chain(&mut forest, chain[1..].iter().cloned().map(Ok)).unwrap();
+        assert_eq!(report.block_count, 0);
+        assert_eq!(report.first_invalid.unwrap().position, 0);
+        assert_eq!(forest.head().hash(), VerifiedBlock::genesis().hash());
+    }
+}
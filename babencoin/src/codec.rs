@@ -0,0 +1,671 @@
+use crate::{
+    data::{
+        Block, BlockAttributes, BlockHash, BlockHeader, Cosignature, Hello, MultisigPolicy,
+        PeerMessage, Timelock, Transaction, WalletId, CONDITIONAL_TRANSACTION_VERSION, HASH_LEN,
+    },
+    util::{wallet_id_from_pkcs8, wallet_id_to_pkcs8},
+};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use chrono::{DateTime, LocalResult, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Binary frames start with this byte followed by the u32 payload length. JSON frames start with
+/// '{' and end with a zero byte, so both kinds can be told apart on the same stream.
+const BINARY_FRAME_MARKER: u8 = 0xb1;
+const BINARY_FRAME_HEADER_LEN: usize = 5;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// Zero-terminated JSON, readable but large.
+    Json,
+    /// Length-prefixed binary encoding with raw hashes and DER-encoded keys.
+    #[default]
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge { size: usize, limit: usize },
+    Invalid(anyhow::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { size, limit } => write!(
+                f,
+                "frame of {} bytes exceeds the limit of {} bytes",
+                size, limit
+            ),
+            Self::Invalid(err) => write!(f, "invalid frame: {:#}", err),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+pub fn encode_frame(message: &PeerMessage, format: WireFormat) -> Result<Vec<u8>> {
+    match format {
+        WireFormat::Json => {
+            let mut frame = serde_json::to_vec(message).context("failed to serialize message")?;
+            frame.push(0);
+            Ok(frame)
+        }
+        WireFormat::Binary => {
+            let payload = to_binary(message)?;
+            let len = u32::try_from(payload.len()).context("message is too large")?;
+            let mut frame = Vec::with_capacity(BINARY_FRAME_HEADER_LEN + payload.len());
+            frame.push(BINARY_FRAME_MARKER);
+            frame.write_u32::<LittleEndian>(len).unwrap();
+            frame.extend_from_slice(&payload);
+            Ok(frame)
+        }
+    }
+}
+
+/// Parses the first frame of the buffer in either format. Returns the message and the frame
+/// length, or `None` if the frame is incomplete.
+pub fn decode_frame(
+    buffer: &[u8],
+    max_frame_size: usize,
+) -> Result<Option<(PeerMessage, usize)>, FrameError> {
+    let Some(&first_byte) = buffer.first() else {
+        return Ok(None);
+    };
+
+    if first_byte == BINARY_FRAME_MARKER {
+        if buffer.len() < BINARY_FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let size = LittleEndian::read_u32(&buffer[1..BINARY_FRAME_HEADER_LEN]) as usize;
+        if size > max_frame_size {
+            return Err(FrameError::TooLarge {
+                size,
+                limit: max_frame_size,
+            });
+        }
+        let frame_len = BINARY_FRAME_HEADER_LEN + size;
+        if buffer.len() < frame_len {
+            return Ok(None);
+        }
+        let message = from_binary(&buffer[BINARY_FRAME_HEADER_LEN..frame_len])
+            .map_err(FrameError::Invalid)?;
+        return Ok(Some((message, frame_len)));
+    }
+
+    let Some(zero_pos) = buffer.iter().position(|b| *b == 0) else {
+        if buffer.len() > max_frame_size {
+            return Err(FrameError::TooLarge {
+                size: buffer.len(),
+                limit: max_frame_size,
+            });
+        }
+        return Ok(None);
+    };
+    if zero_pos > max_frame_size {
+        return Err(FrameError::TooLarge {
+            size: zero_pos,
+            limit: max_frame_size,
+        });
+    }
+    let message = std::str::from_utf8(&buffer[..zero_pos])
+        .context("message is not a valid utf-8")
+        .and_then(|data| serde_json::from_str(data).context("failed to deserialize message"))
+        .map_err(FrameError::Invalid)?;
+    Ok(Some((message, zero_pos + 1)))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub trait BinaryCodec: Sized {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()>;
+    fn decode(decoder: &mut Decoder) -> Result<Self>;
+}
+
+pub fn to_binary<T: BinaryCodec>(value: &T) -> Result<Vec<u8>> {
+    let mut encoder = Encoder::default();
+    value.encode(&mut encoder)?;
+    Ok(encoder.buffer)
+}
+
+pub fn from_binary<T: BinaryCodec>(data: &[u8]) -> Result<T> {
+    let mut decoder = Decoder { data };
+    let value = T::decode(&mut decoder)?;
+    if !decoder.data.is_empty() {
+        bail!("{} trailing bytes", decoder.data.len());
+    }
+    Ok(value)
+}
+
+#[derive(Default)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buffer.write_u32::<LittleEndian>(value).unwrap();
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buffer.write_u64::<LittleEndian>(value).unwrap();
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.buffer.write_i64::<LittleEndian>(value).unwrap();
+    }
+
+    fn len(&mut self, len: usize) -> Result<()> {
+        self.u32(u32::try_from(len).context("length overflows u32")?);
+        Ok(())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.len(bytes.len())?;
+        self.buffer.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn string(&mut self, string: &str) -> Result<()> {
+        self.bytes(string.as_bytes())
+    }
+
+    fn hash(&mut self, hash: &BlockHash) {
+        self.buffer.extend_from_slice(hash);
+    }
+
+    fn hashes(&mut self, hashes: &[BlockHash]) -> Result<()> {
+        self.len(hashes.len())?;
+        hashes.iter().for_each(|hash| self.hash(hash));
+        Ok(())
+    }
+
+    fn wallet_id(&mut self, wallet: &WalletId) -> Result<()> {
+        self.bytes(&wallet_id_to_pkcs8(wallet)?)
+    }
+
+    fn list<T: BinaryCodec>(&mut self, items: &[T]) -> Result<()> {
+        self.len(items.len())?;
+        items.iter().try_for_each(|item| item.encode(self))
+    }
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("unexpected end of data");
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(LittleEndian::read_i64(self.take(8)?))
+    }
+
+    fn timestamp(&mut self) -> Result<DateTime<Utc>> {
+        match Utc.timestamp_opt(self.i64()?, 0) {
+            LocalResult::Single(timestamp) => Ok(timestamp),
+            _ => bail!("invalid timestamp"),
+        }
+    }
+
+    /// Reads a length, checking that there are at least `len * item_size` bytes left, so that
+    /// malicious lengths do not cause huge allocations.
+    fn len(&mut self, item_size: usize) -> Result<usize> {
+        let len = self.u32()? as usize;
+        if len.saturating_mul(item_size) > self.data.len() {
+            bail!("length {} exceeds the remaining data", len);
+        }
+        Ok(len)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.len(1)?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let bytes = self.bytes()?;
+        Ok(std::str::from_utf8(bytes)
+            .context("string is not a valid utf-8")?
+            .to_owned())
+    }
+
+    fn hash(&mut self) -> Result<BlockHash> {
+        Ok(self.take(HASH_LEN)?.try_into().unwrap())
+    }
+
+    fn hashes(&mut self) -> Result<Vec<BlockHash>> {
+        let len = self.len(HASH_LEN)?;
+        (0..len).map(|_| self.hash()).collect()
+    }
+
+    fn wallet_id(&mut self) -> Result<WalletId> {
+        wallet_id_from_pkcs8(self.bytes()?)
+    }
+
+    fn list<T: BinaryCodec>(&mut self) -> Result<Vec<T>> {
+        let len = self.len(1)?;
+        (0..len).map(|_| T::decode(self)).collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl BinaryCodec for Transaction {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.u32(self.version);
+        encoder.u64(self.amount);
+        encoder.u64(self.fee);
+        encoder.u64(self.nonce);
+        encoder.string(&self.comment)?;
+        encoder.wallet_id(&self.sender)?;
+        encoder.wallet_id(&self.receiver)?;
+        encoder.bytes(&self.signature)?;
+        if self.version < CONDITIONAL_TRANSACTION_VERSION {
+            return Ok(());
+        }
+
+        match self.timelock {
+            None => encoder.u8(0),
+            Some(Timelock::BlockIndex(index)) => {
+                encoder.u8(1);
+                encoder.u64(index);
+            }
+            Some(Timelock::Timestamp(timestamp)) => {
+                encoder.u8(2);
+                encoder.i64(timestamp.timestamp());
+            }
+        }
+        match &self.multisig_policy {
+            None => encoder.u8(0),
+            Some(policy) => {
+                encoder.u8(1);
+                encoder.u32(policy.threshold);
+                encoder.len(policy.signers.len())?;
+                for signer in policy.signers.iter() {
+                    encoder.wallet_id(signer)?;
+                }
+            }
+        }
+        encoder.list(&self.cosignatures)
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        let mut tx = Self {
+            version: decoder.u32()?,
+            amount: decoder.u64()?,
+            fee: decoder.u64()?,
+            nonce: decoder.u64()?,
+            comment: decoder.string()?,
+            sender: decoder.wallet_id()?,
+            receiver: decoder.wallet_id()?,
+            signature: decoder.bytes()?.to_vec(),
+            timelock: None,
+            multisig_policy: None,
+            cosignatures: vec![],
+        };
+        if tx.version < CONDITIONAL_TRANSACTION_VERSION {
+            return Ok(tx);
+        }
+
+        tx.timelock = match decoder.u8()? {
+            0 => None,
+            1 => Some(Timelock::BlockIndex(decoder.u64()?)),
+            2 => Some(Timelock::Timestamp(decoder.timestamp()?)),
+            tag => bail!("unknown timelock tag {}", tag),
+        };
+        tx.multisig_policy = match decoder.u8()? {
+            0 => None,
+            1 => {
+                let threshold = decoder.u32()?;
+                let len = decoder.len(4)?;
+                let signers = (0..len)
+                    .map(|_| decoder.wallet_id())
+                    .collect::<Result<_>>()?;
+                Some(MultisigPolicy { threshold, signers })
+            }
+            tag => bail!("unknown multisig policy tag {}", tag),
+        };
+        tx.cosignatures = decoder.list()?;
+        Ok(tx)
+    }
+}
+
+impl BinaryCodec for Cosignature {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.wallet_id(&self.signer)?;
+        encoder.bytes(&self.signature)
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Self {
+            signer: decoder.wallet_id()?,
+            signature: decoder.bytes()?.to_vec(),
+        })
+    }
+}
+
+impl BinaryCodec for BlockAttributes {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.u64(self.index);
+        encoder.u64(self.reward);
+        encoder.u64(self.nonce);
+        encoder.i64(self.timestamp.timestamp());
+        encoder.wallet_id(&self.issuer)?;
+        encoder.hash(&self.max_hash);
+        encoder.hash(&self.prev_hash);
+        match &self.merkle_root {
+            Some(merkle_root) => {
+                encoder.u8(1);
+                encoder.hash(merkle_root);
+            }
+            None => encoder.u8(0),
+        }
+        Ok(())
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Self {
+            index: decoder.u64()?,
+            reward: decoder.u64()?,
+            nonce: decoder.u64()?,
+            timestamp: decoder.timestamp()?,
+            issuer: decoder.wallet_id()?,
+            max_hash: decoder.hash()?,
+            prev_hash: decoder.hash()?,
+            merkle_root: match decoder.u8()? {
+                0 => None,
+                1 => Some(decoder.hash()?),
+                flag => bail!("invalid merkle root flag {}", flag),
+            },
+        })
+    }
+}
+
+impl BinaryCodec for Block {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        self.attrs.encode(encoder)?;
+        encoder.list(&self.transactions)
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Self {
+            attrs: BlockAttributes::decode(decoder)?,
+            transactions: decoder.list()?,
+        })
+    }
+}
+
+impl BinaryCodec for BlockHeader {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.hash(&self.hash);
+        self.attrs.encode(encoder)
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Self {
+            hash: decoder.hash()?,
+            attrs: BlockAttributes::decode(decoder)?,
+        })
+    }
+}
+
+impl BinaryCodec for Hello {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.u32(self.protocol_version);
+        encoder.u64(self.node_id);
+        encoder.u64(self.head_index);
+        encoder.hash(&self.head_hash);
+        match &self.listen_address {
+            Some(address) => {
+                encoder.u8(1);
+                encoder.string(address)?;
+            }
+            None => encoder.u8(0),
+        }
+        encoder.len(self.wire_formats.len())?;
+        for format in self.wire_formats.iter() {
+            encoder.u8(match format {
+                WireFormat::Json => 0,
+                WireFormat::Binary => 1,
+            });
+        }
+        Ok(())
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Self {
+            protocol_version: decoder.u32()?,
+            node_id: decoder.u64()?,
+            head_index: decoder.u64()?,
+            head_hash: decoder.hash()?,
+            listen_address: match decoder.u8()? {
+                0 => None,
+                1 => Some(decoder.string()?),
+                flag => bail!("invalid listen address flag {}", flag),
+            },
+            wire_formats: {
+                let len = decoder.len(1)?;
+                (0..len)
+                    .map(|_| match decoder.u8()? {
+                        0 => Ok(WireFormat::Json),
+                        1 => Ok(WireFormat::Binary),
+                        tag => bail!("unknown wire format {}", tag),
+                    })
+                    .collect::<Result<_>>()?
+            },
+        })
+    }
+}
+
+impl BinaryCodec for String {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.string(self)
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        decoder.string()
+    }
+}
+
+impl BinaryCodec for PeerMessage {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        match self {
+            Self::Block(block) => {
+                encoder.u8(0);
+                block.encode(encoder)
+            }
+            Self::Transaction(tx) => {
+                encoder.u8(1);
+                tx.encode(encoder)
+            }
+            Self::Request { block_hash } => {
+                encoder.u8(2);
+                encoder.hash(block_hash);
+                Ok(())
+            }
+            Self::GetHeaders { locator, limit } => {
+                encoder.u8(3);
+                encoder.hashes(locator)?;
+                encoder.u64(*limit);
+                Ok(())
+            }
+            Self::Headers { headers } => {
+                encoder.u8(4);
+                encoder.list(headers)
+            }
+            Self::GetBlocks { block_hashes } => {
+                encoder.u8(5);
+                encoder.hashes(block_hashes)
+            }
+            Self::Hello(hello) => {
+                encoder.u8(6);
+                hello.encode(encoder)
+            }
+            Self::GetPeers => {
+                encoder.u8(7);
+                Ok(())
+            }
+            Self::Peers { addresses } => {
+                encoder.u8(8);
+                encoder.list(addresses)
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(match decoder.u8()? {
+            0 => Self::Block(Box::new(Block::decode(decoder)?)),
+            1 => Self::Transaction(Box::new(Transaction::decode(decoder)?)),
+            2 => Self::Request {
+                block_hash: decoder.hash()?,
+            },
+            3 => Self::GetHeaders {
+                locator: decoder.hashes()?,
+                limit: decoder.u64()?,
+            },
+            4 => Self::Headers {
+                headers: decoder.list()?,
+            },
+            5 => Self::GetBlocks {
+                block_hashes: decoder.hashes()?,
+            },
+            6 => Self::Hello(Box::new(Hello::decode(decoder)?)),
+            7 => Self::GetPeers,
+            8 => Self::Peers {
+                addresses: decoder.list()?,
+            },
+            tag => bail!("unknown message tag {}", tag),
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::VerifiedBlock;
+
+    fn test_block() -> Block {
+        serde_json::from_str(include_str!("../data/test_block.json")).unwrap()
+    }
+
+    fn roundtrip(message: PeerMessage) {
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let mut frame = encode_frame(&message, format).unwrap();
+            let frame_len = frame.len();
+            frame.extend_from_slice(b"{\"kind\"");
+
+            let (decoded, len) = decode_frame(&frame, 1 << 20).unwrap().unwrap();
+            assert_eq!(len, frame_len);
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&message).unwrap()
+            );
+            assert!(decode_frame(&frame[..frame_len - 1], 1 << 20)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let block = test_block();
+        let genesis = VerifiedBlock::genesis();
+        roundtrip(PeerMessage::Block(Box::new(block.clone())));
+        roundtrip(PeerMessage::Transaction(Box::new(
+            block.transactions[0].clone(),
+        )));
+        let mut conditional = block.transactions[0].clone();
+        conditional.version = CONDITIONAL_TRANSACTION_VERSION;
+        conditional.timelock = Some(Timelock::Timestamp(block.timestamp));
+        conditional.multisig_policy = Some(MultisigPolicy {
+            threshold: 1,
+            signers: vec![block.issuer.clone(), genesis.issuer.clone()],
+        });
+        conditional.cosignatures = vec![Cosignature {
+            signer: block.issuer.clone(),
+            signature: vec![1, 2, 3],
+        }];
+        roundtrip(PeerMessage::Transaction(Box::new(conditional)));
+        roundtrip(PeerMessage::GetHeaders {
+            locator: vec![*genesis.hash(), block.compute_hash()],
+            limit: 10,
+        });
+        roundtrip(PeerMessage::Headers {
+            headers: vec![genesis.header()],
+        });
+        roundtrip(PeerMessage::Hello(Box::new(Hello {
+            protocol_version: 1,
+            node_id: 42,
+            head_index: 0,
+            head_hash: *genesis.hash(),
+            listen_address: Some("localhost:9090".into()),
+            wire_formats: vec![WireFormat::Binary, WireFormat::Json],
+        })));
+        roundtrip(PeerMessage::Peers {
+            addresses: vec!["localhost:9090".into()],
+        });
+    }
+
+    #[test]
+    fn test_binary_is_compact() {
+        let message = PeerMessage::Block(Box::new(test_block()));
+        let json = encode_frame(&message, WireFormat::Json).unwrap();
+        let binary = encode_frame(&message, WireFormat::Binary).unwrap();
+        assert!(binary.len() * 4 < json.len() * 3);
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let message = PeerMessage::Block(Box::new(test_block()));
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let frame = encode_frame(&message, format).unwrap();
+            assert!(matches!(
+                decode_frame(&frame, 100),
+                Err(FrameError::TooLarge { limit: 100, .. })
+            ));
+        }
+
+        let mut frame = encode_frame(&PeerMessage::GetPeers, WireFormat::Binary).unwrap();
+        frame.push(0);
+        frame[1] += 1;
+        assert!(matches!(
+            decode_frame(&frame, 100),
+            Err(FrameError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::{
+    block_forest::{EPOCH_SIZE, TARGET_BLOCK_MINING_TIME_SECONDS},
+    data::{
+        Block, BlockAttributes, BlockHash, VerifiedBlock, GENESIS_TIMESTAMP, HASH_LEN, MAX_REWARD,
+    },
+    util::{deserialize_base64_fixed, deserialize_utc, serialize_base64, serialize_utc},
+};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use std::sync::OnceLock;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Largest epoch size or moving average window.
+pub const MAX_RETARGET_SPAN: u64 = 1 << 16;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Rules all nodes of a network must agree on. The defaults describe the main network, other
+/// values start a separate network with its own genesis.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsensusParams {
+    pub max_reward: u64,
+
+    /// Block timestamps have one second resolution, so should this.
+    #[serde(with = "humantime_serde")]
+    pub target_block_time: std::time::Duration,
+
+    pub difficulty: DifficultyAdjustment,
+    pub genesis: GenesisParams,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            max_reward: MAX_REWARD,
+            target_block_time: std::time::Duration::from_secs(TARGET_BLOCK_MINING_TIME_SECONDS),
+            difficulty: DifficultyAdjustment::default(),
+            genesis: GenesisParams::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum DifficultyAdjustment {
+    /// Max hash changes once per epoch by a whole factor, based on the average block time of the
+    /// previous epoch.
+    Epoch { epoch_size: u64 },
+    /// Max hash changes every block in proportion to the average block time of the last `window`
+    /// blocks, but at most 4 times.
+    MovingAverage { window: u64 },
+}
+
+impl Default for DifficultyAdjustment {
+    fn default() -> Self {
+        Self::Epoch {
+            epoch_size: EPOCH_SIZE as u64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenesisParams {
+    #[serde(serialize_with = "serialize_utc", deserialize_with = "deserialize_utc")]
+    pub timestamp: DateTime<Utc>,
+
+    /// Initial difficulty. Genesis itself is not mined.
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub max_hash: BlockHash,
+}
+
+impl Default for GenesisParams {
+    fn default() -> Self {
+        Self {
+            timestamp: Utc.timestamp_opt(GENESIS_TIMESTAMP, 0).unwrap(),
+            max_hash: [255u8; HASH_LEN],
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl ConsensusParams {
+    pub fn genesis_block(&self) -> VerifiedBlock {
+        let mut block = Block::genesis();
+        block.timestamp = self.genesis.timestamp;
+        block.max_hash = self.genesis.max_hash;
+        VerifiedBlock::unmined_genesis(block)
+    }
+
+    pub fn block_rules(&self) -> BlockRules {
+        BlockRules::with_genesis_hash(self, *self.genesis_block().hash())
+    }
+
+    /// Number of the last blocks, ending with the parent, that determine the max hash of the
+    /// block with this index. `None` means that the block inherits the max hash of its parent.
+    pub fn retarget_window(&self, index: u64) -> Option<usize> {
+        let span = self.retarget_span();
+        match self.difficulty {
+            DifficultyAdjustment::Epoch { .. } => {
+                index.is_multiple_of(span).then_some(span as usize)
+            }
+            DifficultyAdjustment::MovingAverage { .. } => {
+                (index > span).then_some(span as usize + 1)
+            }
+        }
+    }
+
+    /// How deep descendants of a new block may depend on it through retargeting.
+    pub fn retarget_depth(&self) -> u64 {
+        2 * self.retarget_span()
+    }
+
+    /// Epoch size or window, clamped to the values retargeting works with.
+    fn retarget_span(&self) -> u64 {
+        match self.difficulty {
+            DifficultyAdjustment::Epoch { epoch_size } => epoch_size.clamp(2, MAX_RETARGET_SPAN),
+            DifficultyAdjustment::MovingAverage { window } => window.clamp(1, MAX_RETARGET_SPAN),
+        }
+    }
+
+    /// Computes the max hash of the next block from the blocks of its retarget window, the
+    /// oldest first.
+    pub fn retarget(&self, window: &[&BlockAttributes]) -> BlockHash {
+        assert!(window.len() >= 2);
+        let intervals = (window.len() - 1) as i32;
+        let target_seconds = self.target_block_time.as_secs().max(1);
+
+        match self.difficulty {
+            DifficultyAdjustment::Epoch { .. } => {
+                let avg_duration = {
+                    let mut sum_duration = Duration::zero();
+                    for (prev, cur) in window.iter().zip(window.iter().skip(1)) {
+                        assert_eq!(prev.max_hash, cur.max_hash);
+
+                        let delta = cur.timestamp - prev.timestamp;
+                        assert!(delta > Duration::zero());
+
+                        sum_duration = sum_duration
+                            .checked_add(&delta)
+                            .expect("duration add overflow");
+                    }
+                    sum_duration / intervals
+                };
+
+                let old_max_hash = BigUint::from_bytes_be(&window[0].max_hash);
+                let factor =
+                    (avg_duration.num_seconds() as f64 / target_seconds as f64).clamp(0.001, 1000.);
+
+                let max_hash = if factor > 1. {
+                    old_max_hash * factor.round() as u64
+                } else {
+                    old_max_hash / (1. / factor).round() as u64
+                };
+                to_max_hash(max_hash)
+            }
+            DifficultyAdjustment::MovingAverage { .. } => {
+                let expected = target_seconds * intervals as u64;
+                let elapsed = (window.last().unwrap().timestamp - window[0].timestamp)
+                    .num_seconds()
+                    .max(0) as u64;
+                let elapsed = elapsed.clamp(expected.div_ceil(4), expected * 4);
+
+                let old_max_hash = BigUint::from_bytes_be(&window.last().unwrap().max_hash);
+                to_max_hash(old_max_hash * elapsed / expected)
+            }
+        }
+    }
+}
+
+/// Expected number of hashes to try to mine a block with this max hash.
+pub fn block_work(max_hash: &BlockHash) -> BigUint {
+    (BigUint::from(1u8) << (8 * HASH_LEN)) / (BigUint::from_bytes_be(max_hash) + 1u8)
+}
+
+/// Saturates at the easiest possible max hash and never goes down to zero.
+fn to_max_hash(max_hash: BigUint) -> BlockHash {
+    let bytes = max_hash.to_bytes_be();
+    let prefix_size = bytes.len().saturating_sub(HASH_LEN);
+    let leading_zeros = HASH_LEN.saturating_sub(bytes.len());
+
+    if bytes.iter().take(prefix_size).any(|b| *b > 0) {
+        [255u8; HASH_LEN]
+    } else {
+        let mut result = [0u8; HASH_LEN];
+        for (i, byte) in (leading_zeros..HASH_LEN).zip(bytes.into_iter().skip(prefix_size)) {
+            result[i] = byte;
+        }
+        if result == [0u8; HASH_LEN] {
+            result[HASH_LEN - 1] = 1;
+        }
+        result
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The rules of a network which a block is checked against on its own, before it is added to a
+/// forest. The genesis hash is computed once, when the rules are built.
+#[derive(Clone, Debug)]
+pub struct BlockRules {
+    max_reward: u64,
+    genesis_timestamp: DateTime<Utc>,
+    genesis_hash: BlockHash,
+}
+
+impl BlockRules {
+    pub(crate) fn with_genesis_hash(params: &ConsensusParams, genesis_hash: BlockHash) -> Self {
+        Self {
+            max_reward: params.max_reward,
+            genesis_timestamp: params.genesis.timestamp,
+            genesis_hash,
+        }
+    }
+
+    /// Rules of the main network.
+    pub fn main() -> &'static Self {
+        static RULES: OnceLock<BlockRules> = OnceLock::new();
+        RULES.get_or_init(|| ConsensusParams::default().block_rules())
+    }
+
+    pub fn max_reward(&self) -> u64 {
+        self.max_reward
+    }
+
+    pub fn genesis_hash(&self) -> &BlockHash {
+        &self.genesis_hash
+    }
+
+    /// The checks which need no block hash, cheap enough to run before verifying transactions.
+    pub(crate) fn check_attrs(&self, attrs: &BlockAttributes) -> Result<()> {
+        if attrs.timestamp < self.genesis_timestamp {
+            bail!("block timestamp is less than genesis timestamp");
+        }
+        if attrs.reward > self.max_reward {
+            bail!("block reward is greater than max reward");
+        }
+        if attrs.index == 1 && attrs.prev_hash != self.genesis_hash {
+            bail!("block index is 1, but prev_hash != genesis");
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, block: &VerifiedBlock) -> Result<()> {
+        self.check_attrs(block)?;
+        if block.index == 0 && *block.hash() != self.genesis_hash {
+            bail!("block index is 0, but not the genesis block");
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_window(max_hash: BlockHash, block_seconds: &[i64]) -> Vec<BlockAttributes> {
+        let mut block = Block::genesis();
+        block.max_hash = max_hash;
+        let mut window = vec![block.attrs.clone()];
+        for seconds in block_seconds {
+            block.index += 1;
+            block.timestamp += Duration::seconds(*seconds);
+            window.push(block.attrs.clone());
+        }
+        window
+    }
+
+    #[test]
+    fn test_epoch_retarget() {
+        let params = ConsensusParams {
+            difficulty: DifficultyAdjustment::Epoch { epoch_size: 4 },
+            ..Default::default()
+        };
+        assert_eq!(params.retarget_window(3), None);
+        assert_eq!(params.retarget_window(4), Some(4));
+        assert_eq!(params.retarget_depth(), 8);
+
+        // Degenerate epochs are clamped the same way everywhere.
+        let degenerate = ConsensusParams {
+            difficulty: DifficultyAdjustment::Epoch { epoch_size: 0 },
+            ..Default::default()
+        };
+        assert_eq!(degenerate.retarget_window(2), Some(2));
+        assert_eq!(degenerate.retarget_depth(), 4);
+
+        let mut max_hash = [0u8; HASH_LEN];
+        max_hash[0] = 128;
+        let window = make_window(max_hash, &[5, 5, 5]);
+        let window: Vec<_> = window.iter().collect();
+        let mut expected = [0u8; HASH_LEN];
+        expected[0] = 64;
+        assert_eq!(params.retarget(&window), expected);
+    }
+
+    #[test]
+    fn test_moving_average_retarget() {
+        let params = ConsensusParams {
+            target_block_time: std::time::Duration::from_secs(2),
+            difficulty: DifficultyAdjustment::MovingAverage { window: 4 },
+            ..Default::default()
+        };
+        assert_eq!(params.retarget_window(4), None);
+        assert_eq!(params.retarget_window(5), Some(5));
+
+        let mut max_hash = [0u8; HASH_LEN];
+        max_hash[0] = 100;
+        let retarget = |block_seconds: &[i64]| {
+            let window = make_window(max_hash, block_seconds);
+            let window: Vec<_> = window.iter().collect();
+            params.retarget(&window)[0]
+        };
+        assert_eq!(retarget(&[2, 2, 2, 2]), 100);
+        assert_eq!(retarget(&[3, 3, 3, 3]), 150);
+        assert_eq!(retarget(&[1, 1, 1, 1]), 50);
+        assert_eq!(retarget(&[1, 1, 1, 9]), 150);
+
+        let slow_params = ConsensusParams {
+            target_block_time: std::time::Duration::from_secs(10),
+            ..params.clone()
+        };
+        let window = make_window(max_hash, &[1, 1, 1, 1]);
+        let window: Vec<_> = window.iter().collect();
+        assert_eq!(slow_params.retarget(&window)[0], 25);
+
+        let mut max_hash = [255u8; HASH_LEN];
+        max_hash[0] = 200;
+        let window = make_window(max_hash, &[100, 100, 100, 100]);
+        let window: Vec<_> = window.iter().collect();
+        assert_eq!(params.retarget(&window), [255u8; HASH_LEN]);
+    }
+
+    #[test]
+    fn test_block_work() {
+        assert_eq!(block_work(&[255u8; HASH_LEN]), BigUint::from(1u8));
+
+        let mut max_hash = [255u8; HASH_LEN];
+        max_hash[0] = 127;
+        assert_eq!(block_work(&max_hash), BigUint::from(2u8));
+        max_hash[0] = 63;
+        assert_eq!(block_work(&max_hash), BigUint::from(4u8));
+    }
+
+    #[test]
+    fn test_custom_genesis() {
+        let mut max_hash = [0u8; HASH_LEN];
+        max_hash[0] = 1;
+        let params = ConsensusParams {
+            genesis: GenesisParams {
+                timestamp: Utc.timestamp_opt(GENESIS_TIMESTAMP + 100, 0).unwrap(),
+                max_hash,
+            },
+            ..Default::default()
+        };
+
+        let genesis = params.genesis_block();
+        assert_ne!(genesis.hash(), VerifiedBlock::genesis().hash());
+        assert_eq!(genesis.max_hash, max_hash);
+        let rules = params.block_rules();
+        assert_eq!(rules.genesis_hash(), genesis.hash());
+        rules.check(&genesis).unwrap();
+        assert!(rules.check(&VerifiedBlock::genesis()).is_err());
+        BlockRules::main().check(&VerifiedBlock::genesis()).unwrap();
+    }
+}
@@ -1,6 +1,14 @@
-use crate::util::{
-    deserialize_base64, deserialize_base64_fixed, deserialize_utc, deserialize_wallet_id,
-    parse_pkcs8_public, serialize_base64, serialize_utc, serialize_wallet_id,
+use crate::{
+    codec::WireFormat,
+    consensus::BlockRules,
+    merkle::{compute_merkle_root, MerkleProof},
+    util::{
+        deserialize_base64, deserialize_base64_fixed, deserialize_base64_fixed_vec,
+        deserialize_optional_base64_fixed, deserialize_utc, deserialize_wallet_id,
+        deserialize_wallet_id_vec, is_default, parse_pkcs8_public, serialize_base64,
+        serialize_base64_vec, serialize_optional_base64, serialize_utc, serialize_wallet_id,
+        serialize_wallet_id_vec,
+    },
 };
 
 use anyhow::{bail, Context, Result};
@@ -21,6 +29,19 @@ pub const GENESIS_TIMESTAMP: i64 = 1626002428;
 pub const MAX_REWARD: u64 = 1000;
 pub const HASH_LEN: usize = 64;
 
+/// Transactions of version 0 are legacy ones: they have no nonce and no replay protection.
+pub const LEGACY_TRANSACTION_VERSION: u32 = 0;
+pub const TRANSACTION_VERSION: u32 = 1;
+/// Transactions of version 2 may have spending conditions: a timelock, a multisig policy and
+/// cosignatures.
+pub const CONDITIONAL_TRANSACTION_VERSION: u32 = 2;
+pub const MAX_MULTISIG_SIGNERS: usize = 16;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Peers announcing a protocol version below this one are dropped.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const MAX_PEER_ADDRESSES: usize = 64;
+
 pub type BlockHash = [u8; HASH_LEN];
 pub type TransactionHash = [u8; HASH_LEN];
 
@@ -73,14 +94,68 @@ pub enum PeerMessage {
         )]
         block_hash: BlockHash,
     },
+    /// Asks for the main chain headers following the first locator hash known to the peer.
+    GetHeaders {
+        #[serde(
+            serialize_with = "serialize_base64_vec",
+            deserialize_with = "deserialize_base64_fixed_vec::<'_, _, HASH_LEN>"
+        )]
+        locator: Vec<BlockHash>,
+        limit: u64,
+    },
+    Headers {
+        headers: Vec<BlockHeader>,
+    },
+    /// Asks for several blocks at once, the peer replies with a `Block` message for each of them.
+    GetBlocks {
+        #[serde(
+            serialize_with = "serialize_base64_vec",
+            deserialize_with = "deserialize_base64_fixed_vec::<'_, _, HASH_LEN>"
+        )]
+        block_hashes: Vec<BlockHash>,
+    },
+    /// The first message of a session. Peers which never send it are treated as legacy ones.
+    Hello(Box<Hello>),
+    GetPeers,
+    /// Listen addresses of known peers.
+    Peers {
+        addresses: Vec<String>,
+    },
 }
 
 impl PeerMessage {
-    pub fn verified(self) -> Result<VerifiedPeerMessage> {
+    /// Blocks are checked against the rules of the network the peer is on.
+    pub fn verified(self, rules: &BlockRules) -> Result<VerifiedPeerMessage> {
         match self {
-            Self::Block(block) => Ok(VerifiedPeerMessage::Block(Box::new(block.verified()?))),
+            Self::Block(block) => Ok(VerifiedPeerMessage::Block(Box::new(
+                block.verified_with(rules)?,
+            ))),
             Self::Transaction(tx) => Ok(VerifiedPeerMessage::Transaction(Box::new(tx.verified()?))),
             Self::Request { block_hash } => Ok(VerifiedPeerMessage::Request { block_hash }),
+            Self::GetHeaders { locator, limit } => {
+                Ok(VerifiedPeerMessage::GetHeaders { locator, limit })
+            }
+            Self::Headers { headers } => {
+                BlockHeader::verify_chain(&headers)?;
+                Ok(VerifiedPeerMessage::Headers { headers })
+            }
+            Self::GetBlocks { block_hashes } => Ok(VerifiedPeerMessage::GetBlocks { block_hashes }),
+            Self::Hello(hello) => {
+                if let Some(address) = hello.listen_address.as_ref() {
+                    verify_peer_address(address)?;
+                }
+                Ok(VerifiedPeerMessage::Hello(hello))
+            }
+            Self::GetPeers => Ok(VerifiedPeerMessage::GetPeers),
+            Self::Peers { addresses } => {
+                if addresses.len() > MAX_PEER_ADDRESSES {
+                    bail!("too many peer addresses: {}", addresses.len());
+                }
+                for address in addresses.iter() {
+                    verify_peer_address(address)?;
+                }
+                Ok(VerifiedPeerMessage::Peers { addresses })
+            }
         }
     }
 }
@@ -93,6 +168,16 @@ impl From<VerifiedPeerMessage> for PeerMessage {
                 PeerMessage::Transaction(Box::new((*tx).into()))
             }
             VerifiedPeerMessage::Request { block_hash } => PeerMessage::Request { block_hash },
+            VerifiedPeerMessage::GetHeaders { locator, limit } => {
+                PeerMessage::GetHeaders { locator, limit }
+            }
+            VerifiedPeerMessage::Headers { headers } => PeerMessage::Headers { headers },
+            VerifiedPeerMessage::GetBlocks { block_hashes } => {
+                PeerMessage::GetBlocks { block_hashes }
+            }
+            VerifiedPeerMessage::Hello(hello) => PeerMessage::Hello(hello),
+            VerifiedPeerMessage::GetPeers => PeerMessage::GetPeers,
+            VerifiedPeerMessage::Peers { addresses } => PeerMessage::Peers { addresses },
         }
     }
 }
@@ -103,7 +188,59 @@ impl From<VerifiedPeerMessage> for PeerMessage {
 pub enum VerifiedPeerMessage {
     Block(Box<VerifiedBlock>),
     Transaction(Box<VerifiedTransaction>),
-    Request { block_hash: BlockHash },
+    Request {
+        block_hash: BlockHash,
+    },
+    GetHeaders {
+        locator: Vec<BlockHash>,
+        limit: u64,
+    },
+    /// Consecutive headers, each one is the parent of the next.
+    Headers {
+        headers: Vec<BlockHeader>,
+    },
+    GetBlocks {
+        block_hashes: Vec<BlockHash>,
+    },
+    Hello(Box<Hello>),
+    GetPeers,
+    Peers {
+        addresses: Vec<String>,
+    },
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Random id of the node process, used to detect connections to self.
+    pub node_id: u64,
+    pub head_index: u64,
+
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub head_hash: BlockHash,
+
+    /// Address other nodes can dial, if the node accepts connections.
+    pub listen_address: Option<String>,
+
+    /// Wire formats the node can read besides JSON, the most preferred first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wire_formats: Vec<WireFormat>,
+}
+
+/// Peer addresses are `host:port`, the host is resolved when dialing.
+pub fn verify_peer_address(address: &str) -> Result<()> {
+    let Some((host, port)) = address.rsplit_once(':') else {
+        bail!("peer address {:?} has no port", address);
+    };
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        bail!("invalid peer address {:?}", address);
+    }
+    Ok(())
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -134,6 +271,84 @@ pub struct BlockAttributes {
         deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
     )]
     pub prev_hash: BlockHash,
+
+    /// Root of the Merkle tree over the transaction hashes. If set, the block hash commits to the
+    /// transactions through it, so the header alone is enough to check inclusion proofs. Legacy
+    /// blocks without it commit to every transaction hash directly.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_base64",
+        deserialize_with = "deserialize_optional_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub merkle_root: Option<BlockHash>,
+}
+
+impl BlockAttributes {
+    /// Computes the block hash from the header, if it has a Merkle root.
+    pub fn compute_hash(&self) -> Option<BlockHash> {
+        self.merkle_root?;
+        Some(Block::compute_hash_inner(self, []))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Block attributes along with the block hash. The hash can only be checked against the
+/// attributes if the block has a Merkle root, otherwise it must be checked once the block body is
+/// received.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockHeader {
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub hash: BlockHash,
+
+    #[serde(flatten)]
+    pub attrs: BlockAttributes,
+}
+
+impl Deref for BlockHeader {
+    type Target = BlockAttributes;
+
+    fn deref(&self) -> &Self::Target {
+        &self.attrs
+    }
+}
+
+impl BlockHeader {
+    pub fn verify(&self) -> Result<()> {
+        if self.hash > self.max_hash {
+            bail!("block hash is greater than max_hash");
+        }
+        if let Some(hash) = self.attrs.compute_hash() {
+            if hash != self.hash {
+                bail!("block hash does not match the header");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks every header and that they form a chain.
+    pub fn verify_chain(headers: &[BlockHeader]) -> Result<()> {
+        for header in headers.iter() {
+            header.verify().context(format!(
+                "header {} verification failed",
+                base64::encode(header.hash)
+            ))?;
+        }
+        for (prev, next) in headers.iter().zip(headers.iter().skip(1)) {
+            if next.prev_hash != prev.hash || next.index != prev.index + 1 {
+                bail!(
+                    "header {} does not follow {}",
+                    base64::encode(next.hash),
+                    base64::encode(prev.hash)
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -170,6 +385,7 @@ impl Block {
                 issuer: WalletId::genesis(),
                 max_hash: [255u8; HASH_LEN],
                 prev_hash: [0u8; HASH_LEN],
+                merkle_root: None,
             },
             transactions: vec![],
         }
@@ -182,38 +398,52 @@ impl Block {
         )
     }
 
+    /// Commits the block to its current transactions through a Merkle root.
+    pub fn update_merkle_root(&mut self) {
+        let tx_hashes: Vec<_> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.compute_hash())
+            .collect();
+        self.attrs.merkle_root = Some(compute_merkle_root(&tx_hashes));
+    }
+
+    /// Checks the proof-of-work, the transactions and the rules of the main network.
     pub fn verified(self) -> Result<VerifiedBlock> {
-        if self.timestamp.timestamp() < GENESIS_TIMESTAMP {
-            bail!("block timestamp is less than genesis timestamp");
-        }
+        self.verified_with(BlockRules::main())
+    }
+
+    /// Same as `verified`, with the rules of another network.
+    pub fn verified_with(self, rules: &BlockRules) -> Result<VerifiedBlock> {
         if self.timestamp > Utc::now() {
             bail!("block timestamp is greater than now");
         }
-        if self.reward > MAX_REWARD {
-            bail!("block reward is greater than max reward");
-        }
-        if self.index == 0 && self != Self::genesis() {
-            bail!("block index is 0, but not the genesis block");
-        }
-        if self.index == 1 && self.prev_hash != VerifiedBlock::genesis().hash {
-            bail!("block index is 1, but prev_hash != genesis");
-        }
+        rules.check_attrs(&self.attrs)?;
 
         let mut transactions = Vec::with_capacity(self.transactions.len());
         for tx in self.transactions.into_iter() {
             transactions.push(tx.verified().context("transaction verification failed")?);
         }
 
+        if let Some(merkle_root) = self.attrs.merkle_root {
+            let tx_hashes: Vec<_> = transactions.iter().map(|tx| *tx.hash()).collect();
+            if merkle_root != compute_merkle_root(&tx_hashes) {
+                bail!("block merkle root does not match its transactions");
+            }
+        }
+
         let hash = Self::compute_hash_inner(&self.attrs, transactions.iter().map(|tx| *tx.hash()));
         if hash > self.attrs.max_hash {
             bail!("block hash is greater than max_hash");
         }
 
-        Ok(VerifiedBlock {
+        let block = VerifiedBlock {
             attrs: self.attrs,
             transactions,
             hash,
-        })
+        };
+        rules.check(&block)?;
+        Ok(block)
     }
 
     fn compute_hash_inner(
@@ -231,8 +461,12 @@ impl Block {
         hasher.update(attrs.issuer.public_key.e().to_bytes_le());
         hasher.update(attrs.max_hash);
         hasher.update(attrs.prev_hash);
-        for tx_hash in transaction_hashes.into_iter() {
-            hasher.update(tx_hash);
+        if let Some(merkle_root) = attrs.merkle_root {
+            hasher.update(merkle_root);
+        } else {
+            for tx_hash in transaction_hashes.into_iter() {
+                hasher.update(tx_hash);
+            }
         }
 
         let digest = hasher.finalize();
@@ -275,6 +509,17 @@ impl VerifiedBlock {
         Block::genesis().verified().unwrap()
     }
 
+    /// Genesis is the only block which is not mined, so its max hash is the initial difficulty.
+    pub(crate) fn unmined_genesis(block: Block) -> VerifiedBlock {
+        assert!(block.index == 0 && block.transactions.is_empty());
+        let hash = block.compute_hash();
+        VerifiedBlock {
+            attrs: block.attrs,
+            transactions: vec![],
+            hash,
+        }
+    }
+
     pub fn hash(&self) -> &BlockHash {
         &self.hash
     }
@@ -283,6 +528,22 @@ impl VerifiedBlock {
         &self.transactions
     }
 
+    /// Builds a proof that the transaction is included in the block. Returns `None` if the block
+    /// has no such transaction or no Merkle root to check the proof against.
+    pub fn inclusion_proof(&self, tx_hash: &TransactionHash) -> Option<MerkleProof> {
+        self.merkle_root?;
+        let tx_hashes: Vec<_> = self.transactions.iter().map(|tx| *tx.hash()).collect();
+        let index = tx_hashes.iter().position(|hash| hash == tx_hash)?;
+        MerkleProof::build(&tx_hashes, index)
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            hash: self.hash,
+            attrs: self.attrs.clone(),
+        }
+    }
+
     pub fn to_block(&self) -> Block {
         Block {
             attrs: self.attrs.clone(),
@@ -299,8 +560,16 @@ impl VerifiedBlock {
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Transaction {
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub version: u32,
+
     pub amount: u64,
     pub fee: u64,
+
+    /// Per-sender sequence number, must be strictly increasing along the chain.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub nonce: u64,
+
     pub comment: String,
 
     #[serde(
@@ -315,26 +584,99 @@ pub struct Transaction {
     )]
     pub receiver: WalletId,
 
+    /// Empty if the transaction is cosigned.
     #[serde(
         serialize_with = "serialize_base64",
         deserialize_with = "deserialize_base64"
     )]
     pub signature: Vec<u8>,
+
+    /// The receiver can not spend the amount before the timelock expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timelock: Option<Timelock>,
+
+    /// Replaces the spending policy of the sender wallet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig_policy: Option<MultisigPolicy>,
+
+    /// Signatures of the sender multisig policy signers, used instead of the sender signature.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cosignatures: Vec<Cosignature>,
 }
 
 impl Transaction {
     pub fn verified(self) -> Result<VerifiedTransaction> {
+        if self.version > CONDITIONAL_TRANSACTION_VERSION {
+            bail!("unsupported transaction version {}", self.version);
+        }
+        if self.is_legacy() && self.nonce != 0 {
+            bail!("legacy transaction has a nonce");
+        }
+        if self.version < CONDITIONAL_TRANSACTION_VERSION && self.has_conditions() {
+            bail!(
+                "transaction version {} does not support spending conditions",
+                self.version
+            );
+        }
+        if let Some(policy) = self.multisig_policy.as_ref() {
+            policy.verify().context("invalid multisig policy")?;
+        }
+
         let hash = self.compute_hash();
 
-        self.sender.public_key.verify(
-            PaddingScheme::PKCS1v15Sign { hash: None },
-            &hash,
-            &self.signature,
-        )?;
+        if self.cosignatures.is_empty() {
+            self.sender.public_key.verify(
+                PaddingScheme::PKCS1v15Sign { hash: None },
+                &hash,
+                &self.signature,
+            )?;
+        } else {
+            if !self.signature.is_empty() {
+                bail!("cosigned transaction has a sender signature");
+            }
+            if self.cosignatures.len() > MAX_MULTISIG_SIGNERS {
+                bail!("too many cosignatures: {}", self.cosignatures.len());
+            }
+            for (i, cosignature) in self.cosignatures.iter().enumerate() {
+                if self.cosignatures[..i]
+                    .iter()
+                    .any(|other| other.signer == cosignature.signer)
+                {
+                    bail!("duplicate cosignature");
+                }
+                cosignature
+                    .signer
+                    .public_key
+                    .verify(
+                        PaddingScheme::PKCS1v15Sign { hash: None },
+                        &hash,
+                        &cosignature.signature,
+                    )
+                    .context("invalid cosignature")?;
+            }
+        }
 
         Ok(VerifiedTransaction { inner: self, hash })
     }
 
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_TRANSACTION_VERSION
+    }
+
+    pub fn has_conditions(&self) -> bool {
+        self.timelock.is_some() || self.multisig_policy.is_some() || !self.cosignatures.is_empty()
+    }
+
+    /// Signs the transaction by one of the signers of the sender multisig policy.
+    pub fn add_cosignature(&mut self, signer: &RSAPrivateKey) -> Result<()> {
+        let hash = self.compute_hash();
+        self.cosignatures.push(Cosignature {
+            signer: signer.to_public_key().into(),
+            signature: signer.sign(PaddingScheme::PKCS1v15Sign { hash: None }, &hash)?,
+        });
+        Ok(())
+    }
+
     pub fn compute_hash(&self) -> TransactionHash {
         let mut hasher = Sha3_512::new();
         hasher.write_u64::<LittleEndian>(self.amount).unwrap();
@@ -344,6 +686,44 @@ impl Transaction {
         hasher.update(self.sender.public_key.e().to_bytes_le());
         hasher.update(self.receiver.public_key.n().to_bytes_le());
         hasher.update(self.receiver.public_key.e().to_bytes_le());
+        if !self.is_legacy() {
+            hasher.write_u32::<LittleEndian>(self.version).unwrap();
+            hasher.write_u64::<LittleEndian>(self.nonce).unwrap();
+        }
+        if self.version >= CONDITIONAL_TRANSACTION_VERSION {
+            match self.timelock {
+                None => hasher.update([0]),
+                Some(Timelock::BlockIndex(index)) => {
+                    hasher.update([1]);
+                    hasher.write_u64::<LittleEndian>(index).unwrap();
+                }
+                Some(Timelock::Timestamp(timestamp)) => {
+                    hasher.update([2]);
+                    hasher
+                        .write_i64::<LittleEndian>(timestamp.timestamp())
+                        .unwrap();
+                }
+            }
+            match self.multisig_policy.as_ref() {
+                None => hasher.update([0]),
+                Some(policy) => {
+                    hasher.update([1]);
+                    hasher.write_u32::<LittleEndian>(policy.threshold).unwrap();
+                    hasher
+                        .write_u32::<LittleEndian>(policy.signers.len() as u32)
+                        .unwrap();
+                    for signer in policy.signers.iter() {
+                        for part in [signer.public_key.n(), signer.public_key.e()] {
+                            let bytes = part.to_bytes_le();
+                            hasher
+                                .write_u32::<LittleEndian>(bytes.len() as u32)
+                                .unwrap();
+                            hasher.update(bytes);
+                        }
+                    }
+                }
+            }
+        }
 
         let digest = hasher.finalize();
         assert_eq!(digest.len(), HASH_LEN);
@@ -357,6 +737,97 @@ impl Transaction {
     }
 }
 
+/// Funds received with a timelock are counted in the balance, but can not be spent before it
+/// expires.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Timelock {
+    /// Index of the first block which may spend the funds.
+    BlockIndex(u64),
+    /// Blocks with this or a later timestamp may spend the funds.
+    Timestamp(
+        #[serde(serialize_with = "serialize_utc", deserialize_with = "deserialize_utc")]
+        DateTime<Utc>,
+    ),
+}
+
+impl Timelock {
+    pub fn is_expired(&self, block_index: u64, timestamp: DateTime<Utc>) -> bool {
+        match *self {
+            Self::BlockIndex(index) => block_index >= index,
+            Self::Timestamp(unlock_time) => timestamp >= unlock_time,
+        }
+    }
+}
+
+/// Once a wallet sends a transaction with a multisig policy, its later transactions must be
+/// cosigned by at least `threshold` of the `signers` instead of being signed by the wallet key.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub threshold: u32,
+
+    #[serde(
+        serialize_with = "serialize_wallet_id_vec",
+        deserialize_with = "deserialize_wallet_id_vec"
+    )]
+    pub signers: Vec<WalletId>,
+}
+
+impl MultisigPolicy {
+    pub fn verify(&self) -> Result<()> {
+        if self.signers.len() > MAX_MULTISIG_SIGNERS {
+            bail!("too many signers: {}", self.signers.len());
+        }
+        if self.threshold == 0 || self.threshold as usize > self.signers.len() {
+            bail!(
+                "threshold {} is out of range for {} signers",
+                self.threshold,
+                self.signers.len()
+            );
+        }
+        for (i, signer) in self.signers.iter().enumerate() {
+            if self.signers[..i].contains(signer) {
+                bail!("duplicate signer");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the cosignatures, already verified to be valid and distinct, satisfy the
+    /// policy.
+    pub fn check_cosignatures(&self, cosignatures: &[Cosignature]) -> Result<()> {
+        if cosignatures
+            .iter()
+            .any(|cosignature| !self.signers.contains(&cosignature.signer))
+        {
+            bail!("transaction is cosigned by a wallet which is not a signer of the policy");
+        }
+        if cosignatures.len() < self.threshold as usize {
+            bail!(
+                "{} cosignatures are not enough, the threshold is {}",
+                cosignatures.len(),
+                self.threshold
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Cosignature {
+    #[serde(
+        serialize_with = "serialize_wallet_id",
+        deserialize_with = "deserialize_wallet_id"
+    )]
+    pub signer: WalletId,
+
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    pub signature: Vec<u8>,
+}
+
 impl From<VerifiedTransaction> for Transaction {
     fn from(other: VerifiedTransaction) -> Self {
         other.inner
@@ -389,17 +860,32 @@ impl VerifiedTransaction {
         receiver: WalletId,
         amount: u64,
         fee: u64,
+        nonce: u64,
         comment: String,
     ) -> Result<VerifiedTransaction> {
-        let mut transaction = Transaction {
-            sender: sender.to_public_key().into(),
-            signature: vec![],
-            receiver,
-            amount,
-            fee,
-            comment,
-        };
+        Self::sign_transaction(
+            sender,
+            Transaction {
+                version: TRANSACTION_VERSION,
+                sender: sender.to_public_key().into(),
+                signature: vec![],
+                receiver,
+                amount,
+                fee,
+                nonce,
+                comment,
+                timelock: None,
+                multisig_policy: None,
+                cosignatures: vec![],
+            },
+        )
+    }
 
+    /// Signs an arbitrary transaction, e.g. one with spending conditions, by the sender key.
+    pub fn sign_transaction(
+        sender: &RSAPrivateKey,
+        mut transaction: Transaction,
+    ) -> Result<VerifiedTransaction> {
         let hash = transaction.compute_hash();
         transaction.signature = sender.sign(PaddingScheme::PKCS1v15Sign { hash: None }, &hash)?;
 
@@ -415,7 +901,7 @@ impl VerifiedTransaction {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::parse_pkcs8_private;
+    use crate::{test_util::test_key, util::parse_pkcs8_private};
 
     #[test]
     fn test_genesis() {
@@ -427,8 +913,13 @@ mod tests {
     fn test_transaction_sign() {
         let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
         let genesis_key = Block::genesis().issuer.clone();
-        let tx = VerifiedTransaction::sign(&priv_key, genesis_key, 100, 5, "ping".into()).unwrap();
+        let tx =
+            VerifiedTransaction::sign(&priv_key, genesis_key, 100, 5, 1, "ping".into()).unwrap();
         (&tx as &Transaction).clone().verified().unwrap();
+
+        let mut replayed = (&tx as &Transaction).clone();
+        replayed.nonce = 2;
+        assert!(replayed.verified().is_err());
     }
 
     #[test]
@@ -450,13 +941,23 @@ mod tests {
                     issuer: priv_key.to_public_key().into(),
                     max_hash: [255u8; HASH_LEN],
                     prev_hash: *genesis.hash(),
+                    merkle_root: None,
                 },
-                transactions: vec![VerifiedTransaction::sign(
+                transactions: vec![VerifiedTransaction::sign_transaction(
                     &priv_key,
-                    genesis.issuer.clone(),
-                    500,
-                    30,
-                    "hi".into()
+                    Transaction {
+                        version: LEGACY_TRANSACTION_VERSION,
+                        amount: 500,
+                        fee: 30,
+                        nonce: 0,
+                        comment: "hi".into(),
+                        sender: priv_key.to_public_key().into(),
+                        receiver: genesis.issuer.clone(),
+                        signature: vec![],
+                        timelock: None,
+                        multisig_policy: None,
+                        cosignatures: vec![],
+                    }
                 )
                 .unwrap()
                 .into(),],
@@ -465,4 +966,45 @@ mod tests {
             .unwrap()
         );
     }
+
+    #[test]
+    fn test_merkle_root() {
+        let priv_key = test_key();
+        let genesis = VerifiedBlock::genesis();
+
+        let mut block = Block::genesis();
+        block.index = 1;
+        block.prev_hash = *genesis.hash();
+        block.timestamp = genesis.timestamp + chrono::Duration::seconds(1);
+        for nonce in 1..=3 {
+            let tx = VerifiedTransaction::sign(
+                &priv_key,
+                genesis.issuer.clone(),
+                1,
+                0,
+                nonce,
+                "".into(),
+            )
+            .unwrap();
+            block.transactions.push(tx.into());
+        }
+        assert_eq!(block.attrs.compute_hash(), None);
+
+        block.update_merkle_root();
+        let json = serde_json::to_string(&block).unwrap();
+        let verified = serde_json::from_str::<Block>(&json)
+            .unwrap()
+            .verified()
+            .unwrap();
+        assert_eq!(verified.attrs.compute_hash(), Some(*verified.hash()));
+
+        let tx_hash = *verified.transactions()[1].hash();
+        let proof = verified.inclusion_proof(&tx_hash).unwrap();
+        assert!(proof.verify(&tx_hash, &verified.merkle_root.unwrap()));
+        assert!(verified.inclusion_proof(&[0; HASH_LEN]).is_none());
+
+        let mut tampered = block.clone();
+        tampered.transactions.pop();
+        assert!(tampered.verified().is_err());
+    }
 }
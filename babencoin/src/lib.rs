@@ -1,6 +1,17 @@
 #![forbid(unsafe_code)]
 
 pub mod block_forest;
+pub mod block_store;
+pub mod chain_archive;
+pub mod codec;
+pub mod consensus;
 pub mod data;
+pub mod logging;
+pub mod mempool;
+pub mod merkle;
 pub mod node;
+pub mod simulation;
 pub mod util;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,91 @@
+use crate::data::BlockHash;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Span};
+use tracing_error::ErrorLayer;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use tracing_tree::HierarchicalLayer;
+
+use std::io;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event, prefixed with the enclosing spans and their fields.
+    #[default]
+    Plain,
+    /// Events indented under the spans they belong to, the easiest to read by eye.
+    Tree,
+    /// One json object per event with the fields of every enclosing span, for log processors.
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+
+    /// `EnvFilter` directives, such as `info,babencoin::block_forest=debug`.
+    pub filter: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            filter: "debug".into(),
+        }
+    }
+}
+
+/// Installs the global subscriber writing to stderr. Records of the `log` crate, used by some
+/// dependencies, are forwarded to it.
+pub fn init_logging(config: &LoggingConfig) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.filter)
+        .context(format!("invalid log filter {:?}", config.filter))?;
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(ErrorLayer::default());
+
+    match config.format {
+        LogFormat::Plain => registry
+            .with(fmt::layer().with_writer(io::stderr))
+            .try_init(),
+        LogFormat::Tree => registry
+            .with(
+                HierarchicalLayer::new(2)
+                    .with_writer(io::stderr)
+                    .with_targets(true)
+                    .with_bracketed_fields(true),
+            )
+            .try_init(),
+        LogFormat::Json => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer(io::stderr),
+            )
+            .try_init(),
+    }
+    .context("failed to initialize logging")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Spans share field names, so that a block can be followed from the session it arrived on,
+// through `BlockForest::add_block`, to the sessions it is relayed to.
+
+/// Everything done on behalf of a peer session.
+pub fn session_span(session_id: u64) -> Span {
+    info_span!("session", session_id)
+}
+
+/// Handling of a block, wherever it comes from.
+pub fn block_span(hash: &BlockHash) -> Span {
+    info_span!("block", block = %base64::encode(hash))
+}
@@ -1,65 +1,63 @@
 #![forbid(unsafe_code)]
 
-use babencoin::node::{run, Config};
+use babencoin::{
+    logging::init_logging,
+    node::{run, Config, ExitStatus},
+};
 
 use anyhow::{Context, Result};
-use log::*;
 use structopt::StructOpt;
 
-use std::{fs::File, io::Read};
-
-const DEFAULT_LOG_VERBOSITY: usize = 3;
+use std::path::PathBuf;
 
 #[derive(StructOpt, Debug)]
 #[structopt()]
 struct Opts {
-    /// Config path
-    #[structopt(short = "c", long = "config")]
-    config_path: String,
-}
-
-fn read_config(path: &str) -> Result<Config> {
-    let mut file = File::open(path).context(format!("failed to open {}", path))?;
-
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .context(format!("failed to read {}", path))?;
+    /// Config path, reloaded on SIGHUP
+    #[structopt(short = "c", long = "config", required_unless = "print-default-config")]
+    config_path: Option<PathBuf>,
 
-    serde_yaml::from_slice(&buffer).context("failed to parse config")
+    /// Print the default config and exit
+    #[structopt(long = "print-default-config")]
+    print_default_config: bool,
 }
 
-fn get_verbosity() -> usize {
-    let log_level = match std::env::var("LOG_LEVEL") {
-        Ok(s) => s,
-        _ => return DEFAULT_LOG_VERBOSITY,
-    };
+/// `LOG_LEVEL` overrides the filter of the config.
+fn get_log_filter() -> Option<String> {
+    let log_level = std::env::var("LOG_LEVEL").ok()?;
     match log_level.to_lowercase().as_str() {
-        "trace" => 4,
-        "debug" => 3,
-        "info" => 2,
-        "error" => 1,
-        "none" => 0,
-        _ => DEFAULT_LOG_VERBOSITY,
+        "none" => Some("off".into()),
+        level => Some(level.into()),
     }
 }
 
-async fn do_main() -> Result<()> {
+async fn do_main() -> Result<ExitStatus> {
     let opts = Opts::from_args();
 
-    stderrlog::new()
-        .verbosity(get_verbosity())
-        .timestamp(stderrlog::Timestamp::Millisecond)
-        .init()
-        .expect("failed to initialize logging");
+    let Some(config_path) = opts.config_path.filter(|_| !opts.print_default_config) else {
+        let config = serde_yaml::to_string(&Config::default())
+            .context("failed to serialize default config")?;
+        print!("{}", config);
+        return Ok(ExitStatus::Stopped);
+    };
+
+    let mut config = Config::from_file(&config_path)?;
+    if let Some(filter) = get_log_filter() {
+        config.logging.filter = filter;
+    }
+    init_logging(&config.logging)?;
 
-    let config = read_config(&opts.config_path)?;
-    run(config).await
+    run(config, Some(config_path)).await
 }
 
 #[tokio::main]
 async fn main() {
-    if let Err(err) = do_main().await {
-        error!("{:#}", err);
-        std::process::exit(1);
+    match do_main().await {
+        Ok(status) => std::process::exit(status.code()),
+        Err(err) => {
+            // Logging may be not initialized yet.
+            eprintln!("error: {:#}", err);
+            std::process::exit(1);
+        }
     }
 }
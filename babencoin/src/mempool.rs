@@ -0,0 +1,315 @@
+use crate::data::{Transaction, TransactionHash, VerifiedTransaction, WalletId};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    time::Duration,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MempoolConfig {
+    pub max_transactions: usize,
+
+    /// Transactions that have not been mined for this long are dropped.
+    #[serde(with = "humantime_serde")]
+    pub expiry: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_transactions: 10000,
+            expiry: Duration::from_secs(3600),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority {
+    pub fee_per_kib: u64,
+    pub fee: u64,
+}
+
+impl Priority {
+    pub fn of(tx: &Transaction, size: usize) -> Self {
+        let fee_per_kib = (tx.fee as u128 * 1024 / size.max(1) as u128).min(u64::MAX as u128);
+        Self {
+            fee_per_kib: fee_per_kib as u64,
+            fee: tx.fee,
+        }
+    }
+}
+
+struct Entry {
+    priority: Priority,
+    received_at: DateTime<Utc>,
+}
+
+/// Pool of pending transactions ordered by priority: fee per KiB of the serialized transaction
+/// first, then the absolute fee. Ties are broken by hash, so the order is deterministic.
+pub struct Mempool {
+    config: MempoolConfig,
+    transactions: HashMap<TransactionHash, VerifiedTransaction>,
+    entries: HashMap<TransactionHash, Entry>,
+    by_priority: BTreeSet<(Priority, TransactionHash)>,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MempoolConfig::default())
+    }
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            transactions: HashMap::new(),
+            entries: HashMap::new(),
+            by_priority: BTreeSet::new(),
+        }
+    }
+
+    pub fn config(&self) -> &MempoolConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn is_overflown(&self) -> bool {
+        self.len() > self.config.max_transactions
+    }
+
+    pub fn contains(&self, hash: &TransactionHash) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn transactions(&self) -> &HashMap<TransactionHash, VerifiedTransaction> {
+        &self.transactions
+    }
+
+    pub fn priority(&self, hash: &TransactionHash) -> Option<Priority> {
+        self.entries.get(hash).map(|entry| entry.priority)
+    }
+
+    pub fn insert(&mut self, tx: VerifiedTransaction, received_at: DateTime<Utc>) {
+        if self.contains(tx.hash()) {
+            return;
+        }
+
+        let size = serde_json::to_vec(&tx as &Transaction)
+            .map(|bytes| bytes.len())
+            .unwrap_or(usize::MAX);
+        let priority = Priority::of(&tx, size);
+        self.by_priority.insert((priority, *tx.hash()));
+        self.entries.insert(
+            *tx.hash(),
+            Entry {
+                priority,
+                received_at,
+            },
+        );
+        self.transactions.insert(*tx.hash(), tx);
+    }
+
+    pub fn remove(&mut self, hash: &TransactionHash) -> Option<VerifiedTransaction> {
+        let entry = self.entries.remove(hash)?;
+        self.by_priority.remove(&(entry.priority, *hash));
+        self.transactions.remove(hash)
+    }
+
+    pub fn retain(&mut self, mut pred: impl FnMut(&VerifiedTransaction) -> bool) {
+        let removed: Vec<_> = self
+            .transactions
+            .values()
+            .filter(|tx| !pred(tx))
+            .map(|tx| *tx.hash())
+            .collect();
+        for hash in removed {
+            self.remove(&hash);
+        }
+    }
+
+    /// Removes the transaction with the lowest priority.
+    pub fn evict(&mut self) -> Option<VerifiedTransaction> {
+        let &(_, hash) = self.by_priority.iter().next()?;
+        self.remove(&hash)
+    }
+
+    /// Removes and returns the transactions received before `now - expiry`.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<VerifiedTransaction> {
+        let Ok(expiry) = chrono::Duration::from_std(self.config.expiry) else {
+            return vec![];
+        };
+        let expired: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.received_at + expiry < now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|hash| self.remove(&hash))
+            .collect()
+    }
+
+    /// Picks up to `max_count` transactions by priority. Transactions of a single sender are
+    /// picked in nonce order. `try_apply` is called for each candidate and must apply it to the
+    /// balances if it is valid. A rejected candidate doesn't hold back the later transactions of
+    /// its sender. It is retried in the next pass, since it may depend on funds received in this
+    /// one. Passes are repeated while they pick anything.
+    pub fn select(
+        &self,
+        max_count: usize,
+        mut try_apply: impl FnMut(&VerifiedTransaction) -> Result<()>,
+    ) -> Vec<&VerifiedTransaction> {
+        let mut queues: HashMap<&WalletId, VecDeque<&VerifiedTransaction>> = HashMap::new();
+        for tx in self.transactions.values() {
+            queues.entry(&tx.sender).or_default().push_back(tx);
+        }
+        for queue in queues.values_mut() {
+            queue
+                .make_contiguous()
+                .sort_by_key(|tx| (tx.nonce, *tx.hash()));
+        }
+
+        let candidate = |tx: &VerifiedTransaction| (self.entries[tx.hash()].priority, *tx.hash());
+        let mut selected = vec![];
+        while selected.len() < max_count {
+            let mut candidates: BTreeSet<_> =
+                queues.values().map(|queue| candidate(queue[0])).collect();
+            let mut deferred: HashMap<&WalletId, VecDeque<&VerifiedTransaction>> = HashMap::new();
+            let selected_before = selected.len();
+            while selected.len() < max_count {
+                let Some((_, hash)) = candidates.pop_last() else {
+                    break;
+                };
+                let tx = &self.transactions[&hash];
+                let queue = queues.get_mut(&tx.sender).unwrap();
+                queue.pop_front();
+                if let Some(next) = queue.front() {
+                    candidates.insert(candidate(next));
+                }
+
+                if try_apply(tx).is_ok() {
+                    selected.push(tx);
+                } else {
+                    deferred.entry(&tx.sender).or_default().push_back(tx);
+                }
+            }
+            if selected.len() == selected_before {
+                break;
+            }
+            queues = deferred;
+        }
+        selected
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_key;
+
+    use anyhow::bail;
+
+    fn sign(fee: u64, nonce: u64, comment: &str) -> VerifiedTransaction {
+        let key = test_key();
+        VerifiedTransaction::sign(&key, WalletId::genesis(), 1, fee, nonce, comment.into()).unwrap()
+    }
+
+    #[test]
+    fn test_partial_config() {
+        let config: MempoolConfig = serde_yaml::from_str("max_transactions: 5").unwrap();
+        assert_eq!(config.max_transactions, 5);
+        assert_eq!(config.expiry, MempoolConfig::default().expiry);
+    }
+
+    #[test]
+    fn test_eviction_and_expiry() {
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_transactions: 2,
+            expiry: Duration::from_secs(60),
+        });
+
+        let now = Utc::now();
+        let cheap = sign(1, 1, "cheap");
+        let expensive = sign(100, 2, "expensive");
+        let stale = sign(50, 3, "stale");
+        mempool.insert(cheap.clone(), now);
+        mempool.insert(expensive.clone(), now);
+        mempool.insert(stale.clone(), now - chrono::Duration::minutes(2));
+        assert!(mempool.is_overflown());
+
+        assert_eq!(mempool.evict(), Some(cheap));
+        assert!(!mempool.is_overflown());
+        assert_eq!(mempool.expire(now), vec![stale]);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(expensive.hash()));
+    }
+
+    #[test]
+    fn test_select() {
+        let mut mempool = Mempool::default();
+        let now = Utc::now();
+        let first = sign(1, 1, "first");
+        let second = sign(100, 2, "second");
+        let unaffordable = sign(1000, 3, "unaffordable");
+        for tx in [&second, &unaffordable, &first] {
+            mempool.insert(tx.clone(), now);
+        }
+
+        let mut balance = 105;
+        let mut try_apply = |tx: &VerifiedTransaction| {
+            if tx.fee + tx.amount > balance {
+                bail!("insufficient funds");
+            }
+            balance -= tx.fee + tx.amount;
+            Ok(())
+        };
+        assert_eq!(mempool.select(10, &mut try_apply), vec![&first, &second]);
+        assert_eq!(mempool.select(1, |_| Ok(())), vec![&first]);
+    }
+
+    #[test]
+    fn test_select_rejected() {
+        let mut mempool = Mempool::default();
+        let now = Utc::now();
+        let unaffordable = sign(1000, 1, "unaffordable");
+        let funded_later = sign(1, 2, "funded_later");
+        let funding = sign(10, 3, "funding");
+        for tx in [&unaffordable, &funded_later, &funding] {
+            mempool.insert(tx.clone(), now);
+        }
+
+        let mut applied = vec![];
+        let mut calls = 0;
+        let selected = mempool.select(10, |tx| {
+            calls += 1;
+            if tx == &unaffordable || (tx == &funded_later && !applied.contains(&funding)) {
+                bail!("insufficient funds");
+            }
+            applied.push(tx.clone());
+            Ok(())
+        });
+        assert_eq!(selected, vec![&funding, &funded_later]);
+        // Rejected candidates are retried while the previous pass has picked anything.
+        assert_eq!(calls, 6);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{
+    data::{BlockHash, TransactionHash, HASH_LEN},
+    util::{deserialize_base64_fixed_vec, serialize_base64_vec},
+};
+
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Leaves and inner nodes are hashed with distinct prefixes, so an inner node can't be passed off
+// as a transaction hash.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn hash_leaf(tx_hash: &TransactionHash) -> BlockHash {
+    finalize(
+        Sha3_512::new()
+            .chain_update([LEAF_PREFIX])
+            .chain_update(tx_hash),
+    )
+}
+
+fn hash_node(left: &BlockHash, right: &BlockHash) -> BlockHash {
+    finalize(
+        Sha3_512::new()
+            .chain_update([NODE_PREFIX])
+            .chain_update(left)
+            .chain_update(right),
+    )
+}
+
+fn finalize(hasher: Sha3_512) -> BlockHash {
+    let digest = hasher.finalize();
+    assert_eq!(digest.len(), HASH_LEN);
+
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&digest);
+    hash
+}
+
+/// Computes the next tree level. The last node of an odd-sized level is moved up as is.
+fn next_level(level: &[BlockHash]) -> Vec<BlockHash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Returns the root of the Merkle tree over the transaction hashes, all zeros if there are none.
+pub fn compute_merkle_root(tx_hashes: &[TransactionHash]) -> BlockHash {
+    if tx_hashes.is_empty() {
+        return [0u8; HASH_LEN];
+    }
+
+    let mut level: Vec<_> = tx_hashes.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Proof that a transaction is committed to by a Merkle root.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// Position of the transaction in the block.
+    pub index: usize,
+    pub tx_count: usize,
+
+    /// Sibling hashes from the leaf level up to the root.
+    #[serde(
+        serialize_with = "serialize_base64_vec",
+        deserialize_with = "deserialize_base64_fixed_vec::<'_, _, HASH_LEN>"
+    )]
+    pub path: Vec<BlockHash>,
+}
+
+impl MerkleProof {
+    /// Builds a proof for the transaction at `index`, if there is one.
+    pub fn build(tx_hashes: &[TransactionHash], index: usize) -> Option<Self> {
+        if index >= tx_hashes.len() {
+            return None;
+        }
+
+        let mut path = vec![];
+        let mut level: Vec<_> = tx_hashes.iter().map(hash_leaf).collect();
+        let mut position = index;
+        while level.len() > 1 {
+            let sibling = position ^ 1;
+            if sibling < level.len() {
+                path.push(level[sibling]);
+            }
+            level = next_level(&level);
+            position /= 2;
+        }
+
+        Some(Self {
+            index,
+            tx_count: tx_hashes.len(),
+            path,
+        })
+    }
+
+    /// Checks that the transaction is included in the tree with the given root.
+    pub fn verify(&self, tx_hash: &TransactionHash, merkle_root: &BlockHash) -> bool {
+        if self.index >= self.tx_count {
+            return false;
+        }
+
+        let mut path = self.path.iter();
+        let mut hash = hash_leaf(tx_hash);
+        let mut position = self.index;
+        let mut level_len = self.tx_count;
+        while level_len > 1 {
+            let sibling = position ^ 1;
+            if sibling < level_len {
+                let Some(sibling_hash) = path.next() else {
+                    return false;
+                };
+                hash = if position.is_multiple_of(2) {
+                    hash_node(&hash, sibling_hash)
+                } else {
+                    hash_node(sibling_hash, &hash)
+                };
+            }
+            position /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+
+        path.next().is_none() && &hash == merkle_root
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_hashes(count: u8) -> Vec<TransactionHash> {
+        (0..count).map(|i| [i; HASH_LEN]).collect()
+    }
+
+    #[test]
+    fn test_proofs() {
+        for count in 1..=9 {
+            let hashes = make_hashes(count);
+            let root = compute_merkle_root(&hashes);
+            for (index, hash) in hashes.iter().enumerate() {
+                let proof = MerkleProof::build(&hashes, index).unwrap();
+                assert!(proof.verify(hash, &root));
+                assert!(!proof.verify(&[255; HASH_LEN], &root));
+
+                let json = serde_json::to_string(&proof).unwrap();
+                assert_eq!(serde_json::from_str::<MerkleProof>(&json).unwrap(), proof);
+            }
+            assert!(MerkleProof::build(&hashes, hashes.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_tampered_proof() {
+        let hashes = make_hashes(5);
+        let root = compute_merkle_root(&hashes);
+        let proof = MerkleProof::build(&hashes, 2).unwrap();
+
+        let mut wrong_index = proof.clone();
+        wrong_index.index = 3;
+        assert!(!wrong_index.verify(&hashes[2], &root));
+
+        let mut extra_node = proof.clone();
+        extra_node.path.push(root);
+        assert!(!extra_node.verify(&hashes[2], &root));
+
+        // A single leaf is not the root of a one-element tree over itself.
+        assert_ne!(compute_merkle_root(&hashes[..1]), hashes[0]);
+        assert_eq!(compute_merkle_root(&[]), [0; HASH_LEN]);
+    }
+}
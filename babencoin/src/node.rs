@@ -1,21 +1,48 @@
+mod chain_sync;
+mod config_reload;
 mod gossip_service;
+mod mining_metrics;
 mod mining_service;
+mod peer_exchange;
+mod peer_reputation;
 mod peer_service;
+mod rpc_service;
+mod supervisor;
+mod work_service;
 
-use gossip_service::{GossipService, GossipServiceConfig};
-use log::error;
+use crate::{
+    block_forest::{BlockForest, BlockForestConfig},
+    block_store::FileBlockStore,
+    consensus::{DifficultyAdjustment, MAX_RETARGET_SPAN},
+    data::verify_peer_address,
+    logging::LoggingConfig,
+};
+
+use config_reload::ConfigReload;
+
+use gossip_service::{GossipChannels, GossipService, GossipServiceConfig};
+use mining_metrics::MiningMetrics;
 use mining_service::{MiningService, MiningServiceConfig};
 use peer_service::{PeerService, PeerServiceConfig};
+use rpc_service::{RpcService, RpcServiceConfig};
+use supervisor::{ShutdownStage, Supervisor, SupervisorConfig};
+use tracing::{error, info};
+use work_service::{WorkService, WorkServiceConfig};
 
-use anyhow::{bail, Result};
+pub use supervisor::ExitStatus;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    select,
-    sync::{mpsc::channel, oneshot},
-    task::JoinHandle,
-};
+use tokio::sync::mpsc::channel;
+use tracing_subscriber::EnvFilter;
 
-use std::future::Future;
+use std::{
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -24,6 +51,28 @@ pub struct Config {
     pub peer_app: AppConfig<PeerServiceConfig>,
     pub gossip_app: AppConfig<GossipServiceConfig>,
     pub mining_app: AppConfig<MiningServiceConfig>,
+
+    /// Local query API, disabled if not set.
+    #[serde(default)]
+    pub rpc_app: Option<AppConfig<RpcServiceConfig>>,
+
+    /// Work for external miners, disabled if not set.
+    #[serde(default)]
+    pub work_app: Option<AppConfig<WorkServiceConfig>>,
+
+    #[serde(default)]
+    pub block_forest: BlockForestConfig,
+
+    /// Path to the block log. Blocks are kept only in memory if not set.
+    #[serde(default)]
+    pub storage_path: Option<PathBuf>,
+
+    /// Restarts of failed services and the shutdown on SIGINT and SIGTERM.
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
 impl Default for Config {
@@ -41,88 +90,389 @@ impl Default for Config {
                 thread_count: 1,
                 service: Default::default(),
             },
+            rpc_app: None,
+            work_app: None,
+            block_forest: Default::default(),
+            storage_path: None,
+            supervisor: Default::default(),
+            logging: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads a YAML config and validates it.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let buffer = fs::read(path).context(format!("failed to read {}", path.display()))?;
+        let config: Self = serde_yaml::from_slice(&buffer)
+            .context(format!("failed to parse {}", path.display()))?;
+        config
+            .validate()
+            .context(format!("invalid config {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Catches the mistakes which would otherwise surface only once the node is running. Every
+    /// problem is reported, prefixed with the path of its field.
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        let mut check = |field: &str, result: Result<()>| {
+            if let Err(err) = result {
+                errors.push(format!("{}: {:#}", field, err));
+            }
+        };
+
+        let thread_counts = [
+            ("peer_app", Some(self.peer_app.thread_count)),
+            ("gossip_app", Some(self.gossip_app.thread_count)),
+            ("mining_app", Some(self.mining_app.thread_count)),
+            ("rpc_app", self.rpc_app.as_ref().map(|app| app.thread_count)),
+            (
+                "work_app",
+                self.work_app.as_ref().map(|app| app.thread_count),
+            ),
+        ];
+        for (app, thread_count) in thread_counts {
+            if let Some(thread_count) = thread_count {
+                check(
+                    &format!("{}.thread_count", app),
+                    ensure_positive(thread_count),
+                );
+            }
+        }
+
+        let peer = &self.peer_app.service;
+        if let Some(address) = &peer.listen_address {
+            check(
+                "peer_app.service.listen_address",
+                verify_peer_address(address),
+            );
+        }
+        for (i, address) in peer.dial_addresses.iter().enumerate() {
+            check(
+                &format!("peer_app.service.dial_addresses[{}]", i),
+                verify_peer_address(address),
+            );
+        }
+        check(
+            "peer_app.service.max_frame_size",
+            ensure_positive(peer.max_frame_size),
+        );
+
+        let mining = &self.mining_app.service;
+        if mining.mining_thread_count > 0 {
+            check(
+                "mining_app.service.max_tx_per_block",
+                ensure_positive(mining.max_tx_per_block),
+            );
+        }
+
+        let forest = &self.block_forest;
+        match forest.consensus.difficulty {
+            DifficultyAdjustment::Epoch { epoch_size } => check(
+                "block_forest.consensus.difficulty.epoch_size",
+                ensure_in_range(epoch_size, 2..=MAX_RETARGET_SPAN),
+            ),
+            DifficultyAdjustment::MovingAverage { window } => check(
+                "block_forest.consensus.difficulty.window",
+                ensure_in_range(window, 1..=MAX_RETARGET_SPAN),
+            ),
+        }
+        check(
+            "block_forest.consensus.target_block_time",
+            ensure_in_range(
+                forest.consensus.target_block_time.as_secs(),
+                1..=u32::MAX as u64,
+            ),
+        );
+        check(
+            "block_forest.max_reorg_depth",
+            ensure_positive(forest.max_reorg_depth),
+        );
+        check(
+            "block_forest.pruning.snapshot_interval",
+            ensure_positive(forest.pruning.snapshot_interval),
+        );
+        check(
+            "block_forest.mempool.max_transactions",
+            ensure_positive(forest.mempool.max_transactions),
+        );
+
+        if let Some(rpc_app) = &self.rpc_app {
+            check(
+                "rpc_app.service.listen_address",
+                verify_peer_address(&rpc_app.service.listen_address),
+            );
+        }
+        if let Some(work_app) = &self.work_app {
+            check(
+                "work_app.service.listen_address",
+                verify_peer_address(&work_app.service.listen_address),
+            );
+        }
+
+        check(
+            "supervisor.max_backoff",
+            if self.supervisor.max_backoff < self.supervisor.initial_backoff {
+                Err(anyhow!("is less than initial_backoff"))
+            } else {
+                Ok(())
+            },
+        );
+        check(
+            "logging.filter",
+            EnvFilter::try_new(&self.logging.filter)
+                .map(drop)
+                .map_err(Into::into),
+        );
+
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
         }
+        Ok(())
     }
 }
 
+fn ensure_positive(value: impl TryInto<u64>) -> Result<()> {
+    ensure!(
+        value.try_into().is_ok_and(|value| value > 0),
+        "must be positive"
+    );
+    Ok(())
+}
+
+fn ensure_in_range(value: u64, range: RangeInclusive<u64>) -> Result<()> {
+    ensure!(
+        range.contains(&value),
+        "must be between {} and {}",
+        range.start(),
+        range.end()
+    );
+    Ok(())
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct AppConfig<T> {
     pub thread_count: usize,
     pub service: T,
 }
 
-pub async fn run(config: Config) -> Result<()> {
+/// With `config_path` set, dial addresses, the eager requests interval and the mining thread
+/// count are reloaded from it on SIGHUP.
+pub async fn run(config: Config, config_path: Option<PathBuf>) -> Result<ExitStatus> {
+    let mining_metrics = Arc::new(MiningMetrics::new(
+        config.mining_app.service.mining_thread_count,
+    ));
+    let config_reload = ConfigReload::new(&config, mining_metrics.clone());
+
+    let block_forest = match &config.storage_path {
+        Some(path) => {
+            BlockForest::with_store(config.block_forest, Box::new(FileBlockStore::open(path)?))
+                .context(format!("failed to restore blocks from {}", path.display()))?
+        }
+        None => BlockForest::with_config(config.block_forest),
+    };
+    let block_rules = block_forest.block_rules().clone();
+    let block_forest = Arc::new(RwLock::new(block_forest));
+    let sessions = Arc::new(RwLock::new(Default::default()));
+
     let (peer_event_sender, peer_event_receiver) = channel(1000);
     let (command_sender, command_receiver) = channel(1000);
     let (block_sender, block_receiver) = channel(1000);
-    let (mining_info_sender, mining_info_receiver) = channel(1000);
+    let (mining_info_sender, mut mining_info_receiver) = channel(1000);
 
-    let mut peer_service =
-        PeerService::new(config.peer_app.service, peer_event_sender, command_receiver);
-    let mut peer_service_handle = start_runtime(config.peer_app.thread_count, async move {
-        peer_service.run().await
-    });
+    let mut supervisor = Supervisor::new(config.supervisor);
 
-    let mut gossip_service = GossipService::new(
+    let peer_service = PeerService::new(
+        config.peer_app.service,
+        block_forest.clone(),
+        sessions.clone(),
+        peer_event_sender,
+        command_receiver,
+        config_reload.dial_addresses(),
+        supervisor.shutdown(ShutdownStage::Intake),
+    );
+    supervisor.spawn(
+        "peer",
+        ShutdownStage::Intake,
+        config.peer_app.thread_count,
+        peer_service,
+        |service| service.run().boxed(),
+    );
+
+    let gossip_service = GossipService::new(
         config.gossip_app.service,
-        peer_event_receiver,
-        command_sender,
-        block_receiver,
-        mining_info_sender,
+        block_forest.clone(),
+        GossipChannels {
+            event_receiver: peer_event_receiver,
+            command_sender,
+            block_receiver,
+            mining_info_sender,
+        },
+        config_reload.eager_requests_interval(),
+        supervisor.shutdown(ShutdownStage::Gossip),
     );
-    let mut gossip_service_handle = start_runtime(config.gossip_app.thread_count, async move {
-        gossip_service.run().await
-    });
+    supervisor.spawn(
+        "gossip",
+        ShutdownStage::Gossip,
+        config.gossip_app.thread_count,
+        gossip_service,
+        |service| service.run().boxed(),
+    );
+
+    // The work service gets the mining info first and passes it on to the mining service.
+    if let Some(work_app) = config.work_app {
+        let (info_sender, info_receiver) = channel(1000);
+        let work_service = WorkService::new(
+            work_app.service,
+            std::mem::replace(&mut mining_info_receiver, info_receiver),
+            info_sender,
+            block_sender.clone(),
+            block_rules,
+            supervisor.shutdown(ShutdownStage::Intake),
+        );
+        supervisor.spawn(
+            "work",
+            ShutdownStage::Intake,
+            work_app.thread_count,
+            work_service,
+            |service| service.run().boxed(),
+        );
+    }
 
-    let mut mining_service = MiningService::new(
+    let mining_service = MiningService::new(
         config.mining_app.service,
         mining_info_receiver,
         block_sender,
+        mining_metrics.clone(),
+        config_reload.mining_thread_count(),
+        supervisor.shutdown(ShutdownStage::Mining),
+    );
+    supervisor.spawn(
+        "mining",
+        ShutdownStage::Mining,
+        config.mining_app.thread_count,
+        mining_service,
+        |service| service.run().boxed(),
     );
-    let mut mining_service_handle = start_runtime(config.mining_app.thread_count, async move {
-        mining_service.run().await
-    });
 
-    select! {
-        result = &mut peer_service_handle => {
-            error!("peer service terminated: {:?}", result);
-        }
-        result = &mut gossip_service_handle => {
-            error!("gossip service terminated: {:?}", result);
-        }
-        result = &mut mining_service_handle => {
-            error!("mining service terminated: {:?}", result);
-        }
+    if let Some(rpc_app) = config.rpc_app {
+        let rpc_service = RpcService::new(
+            rpc_app.service,
+            block_forest.clone(),
+            sessions,
+            mining_metrics,
+            supervisor.shutdown(ShutdownStage::Intake),
+        );
+        supervisor.spawn(
+            "rpc",
+            ShutdownStage::Intake,
+            rpc_app.thread_count,
+            rpc_service,
+            |service| service.run().boxed(),
+        );
     }
 
-    let handles = [
-        peer_service_handle,
-        gossip_service_handle,
-        mining_service_handle,
-    ];
-    for handle in handles.iter() {
-        handle.abort();
+    let reload_task = config_path.map(|path| tokio::spawn(config_reload.run(path)));
+    let mut status = supervisor.run().await;
+    if let Some(task) = reload_task {
+        task.abort();
     }
 
-    bail!("node terminated");
+    let mut block_forest = block_forest.write().unwrap_or_else(PoisonError::into_inner);
+    if let Err(err) = block_forest.flush() {
+        error!("failed to flush blocks: {:#}", err);
+        status = ExitStatus::Failed;
+    }
+    info!("node has stopped: {:?}", status);
+    Ok(status)
 }
 
-fn start_runtime<F>(thread_count: usize, future: F) -> JoinHandle<F::Output>
-where
-    F: Future + Send + 'static,
-    F::Output: Send,
-{
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .worker_threads(thread_count)
-        .build()
-        .expect("failed to build a Tokio runtime");
-    let (sender, receiver) = oneshot::channel();
-    let handle = runtime.spawn(async move {
-        let res = future.await;
-        let _ = sender.send(());
-        res
-    });
-    std::thread::spawn(move || runtime.block_on(receiver));
-    handle
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config() {
+        let config = Config::default();
+        config.validate().unwrap();
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        let parsed: Config = serde_yaml::from_str(&yaml).unwrap();
+        parsed.validate().unwrap();
+        assert_eq!(serde_yaml::to_string(&parsed).unwrap(), yaml);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        config.peer_app.service.listen_address = Some("0.0.0.0:9090".into());
+        config.peer_app.service.dial_addresses = vec!["localhost:9091".into(), "[::1]:9092".into()];
+        config.rpc_app = Some(AppConfig {
+            thread_count: 1,
+            service: RpcServiceConfig {
+                listen_address: "127.0.0.1:9093".into(),
+                ..Default::default()
+            },
+        });
+        config.mining_app.service.mining_thread_count = 2;
+        config.validate().unwrap();
+
+        config.peer_app.service.dial_addresses[1] = "localhost;9092".into();
+        config.rpc_app.as_mut().unwrap().thread_count = 0;
+        config.mining_app.service.max_tx_per_block = 0;
+        config.logging.filter = "info,babencoin=loud".into();
+        config.block_forest.consensus.difficulty = DifficultyAdjustment::Epoch { epoch_size: 1 };
+        config.block_forest.consensus.target_block_time = std::time::Duration::ZERO;
+        config.block_forest.max_reorg_depth = 0;
+        config.block_forest.pruning.snapshot_interval = 0;
+        config.block_forest.mempool.max_transactions = 0;
+        let err = format!("{:#}", config.validate().unwrap_err());
+        for field in [
+            "block_forest.consensus.difficulty.epoch_size: must be between 2 and 65536",
+            "block_forest.consensus.target_block_time: must be between 1 and",
+            "block_forest.max_reorg_depth: must be positive",
+            "block_forest.pruning.snapshot_interval: must be positive",
+            "block_forest.mempool.max_transactions: must be positive",
+            "rpc_app.thread_count: must be positive",
+            "peer_app.service.dial_addresses[1]: peer address \"localhost;9092\" has no port",
+            "mining_app.service.max_tx_per_block: must be positive",
+            "logging.filter",
+        ] {
+            assert!(err.contains(field), "{}", err);
+        }
+        assert!(!err.contains("dial_addresses[0]"), "{}", err);
+
+        // Nothing to mine with, no transactions needed.
+        let mut config = Config::default();
+        config.mining_app.service.max_tx_per_block = 0;
+        config.validate().unwrap();
+
+        config.block_forest.consensus.difficulty = DifficultyAdjustment::MovingAverage {
+            window: MAX_RETARGET_SPAN + 1,
+        };
+        let err = format!("{:#}", config.validate().unwrap_err());
+        assert!(
+            err.contains("block_forest.consensus.difficulty.window: must be between 1 and 65536"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_unknown_difficulty_mode() {
+        let mut yaml = serde_yaml::to_value(Config::default()).unwrap();
+        yaml["block_forest"]["consensus"]["difficulty"]["mode"] = "epok".into();
+        let Err(err) = serde_yaml::from_value::<Config>(yaml) else {
+            panic!("unknown difficulty mode accepted");
+        };
+        assert!(
+            err.to_string()
+                .contains("unknown variant `epok`, expected `epoch` or `moving_average`"),
+            "{}",
+            err
+        );
+    }
 }
@@ -0,0 +1,541 @@
+use crate::{
+    block_forest::BlockForest,
+    data::{BlockHash, BlockHeader, VerifiedPeerMessage},
+    node::{
+        peer_reputation::Misbehaviour,
+        peer_service::{PeerCommand, PeerCommandKind, SessionId},
+    },
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainSyncConfig {
+    /// A block this far ahead of the head makes the node sync headers instead of requesting
+    /// missing parents one by one.
+    pub headers_sync_threshold: u64,
+    pub max_headers_per_message: usize,
+    pub max_blocks_per_request: usize,
+    pub max_requests_per_session: usize,
+    /// A block that has not arrived in this many requests is dropped from the queue.
+    pub max_request_attempts: usize,
+    /// Headers sync pauses while this many blocks are waiting to be downloaded.
+    pub max_queued_blocks: usize,
+
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+}
+
+impl Default for ChainSyncConfig {
+    fn default() -> Self {
+        Self {
+            headers_sync_threshold: 8,
+            max_headers_per_message: 32,
+            max_blocks_per_request: 8,
+            max_requests_per_session: 4,
+            max_request_attempts: 3,
+            max_queued_blocks: 1024,
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct HeadersRequest {
+    session_id: SessionId,
+    /// Hash of the last received header, the next request continues from it.
+    last_hash: Option<BlockHash>,
+    deadline: Option<Instant>,
+}
+
+struct BlocksRequest {
+    block_hashes: Vec<BlockHash>,
+    deadline: Instant,
+}
+
+/// Headers-first chain download. Headers are requested from the session that announced a
+/// far-ahead block, then the missing bodies are fetched in batches from all connected sessions in
+/// parallel.
+///
+/// The owner feeds it peer events and sends the commands returned by `poll`.
+pub struct ChainSync {
+    config: ChainSyncConfig,
+    sessions: BTreeSet<SessionId>,
+    headers_request: Option<HeadersRequest>,
+    queue: VecDeque<BlockHash>,
+    queued: HashSet<BlockHash>,
+    /// Number of failed requests of the queued blocks.
+    attempts: HashMap<BlockHash, usize>,
+    requests: HashMap<SessionId, Vec<BlocksRequest>>,
+}
+
+impl ChainSync {
+    pub fn new(config: ChainSyncConfig) -> Self {
+        Self {
+            config,
+            sessions: BTreeSet::new(),
+            headers_request: None,
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            attempts: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.headers_request.is_some() || !self.queued.is_empty()
+    }
+
+    pub fn add_session(&mut self, session_id: SessionId) {
+        self.sessions.insert(session_id);
+    }
+
+    pub fn remove_session(&mut self, session_id: SessionId) {
+        self.sessions.remove(&session_id);
+        if let Some(requests) = self.requests.remove(&session_id) {
+            self.requeue(requests);
+        }
+        if self
+            .headers_request
+            .as_ref()
+            .is_some_and(|request| request.session_id == session_id)
+        {
+            debug!("headers sync from session {} is aborted", session_id);
+            self.headers_request = None;
+        }
+    }
+
+    /// Called for a block whose parent is unknown. Returns true if its ancestors are going to be
+    /// downloaded by the headers sync, so they should not be requested one by one.
+    pub fn on_orphan_block(
+        &mut self,
+        session_id: SessionId,
+        block_index: u64,
+        block_forest: &BlockForest,
+    ) -> bool {
+        if self.headers_request.is_some() {
+            return true;
+        }
+        if block_index <= block_forest.head().index + self.config.headers_sync_threshold {
+            return false;
+        }
+
+        info!(
+            "block {} is far ahead of the head {}, syncing headers from session {}",
+            block_index,
+            block_forest.head().index,
+            session_id
+        );
+        self.headers_request = Some(HeadersRequest {
+            session_id,
+            last_hash: None,
+            deadline: None,
+        });
+        true
+    }
+
+    /// Queues the bodies of the received headers. Headers which don't continue from the last
+    /// received one or from a known block abort the sync, the error is the sender's fault.
+    pub fn on_headers(
+        &mut self,
+        session_id: SessionId,
+        headers: Vec<BlockHeader>,
+        block_forest: &BlockForest,
+    ) -> Result<()> {
+        let Some(request) = self.headers_request.as_ref() else {
+            return Ok(());
+        };
+        if request.session_id != session_id || request.deadline.is_none() {
+            debug!("ignoring unsolicited headers from session {}", session_id);
+            return Ok(());
+        }
+        if let Some(first) = headers.first() {
+            let prev_hash = first.prev_hash;
+            if request.last_hash != Some(prev_hash) && block_forest.find_block(&prev_hash).is_none()
+            {
+                self.headers_request = None;
+                return Err(anyhow!(
+                    "headers from session {} don't connect to a known block",
+                    session_id
+                )
+                .context(Misbehaviour::InvalidMessage));
+            }
+        }
+
+        let mut is_complete = headers.len() < self.config.max_headers_per_message;
+        let mut last_hash = request.last_hash;
+        for header in headers {
+            if self.queued.len() >= self.config.max_queued_blocks {
+                // The rest is requested again once the queue drains.
+                is_complete = false;
+                break;
+            }
+            let hash = header.hash;
+            last_hash = Some(hash);
+            if block_forest.find_block(&hash).is_some()
+                || self.queued.contains(&hash)
+                || self.is_requested(&hash)
+            {
+                continue;
+            }
+            self.queued.insert(hash);
+            self.queue.push_back(hash);
+        }
+
+        if is_complete {
+            debug!("headers sync from session {} is complete", session_id);
+            self.headers_request = None;
+        } else if let Some(request) = self.headers_request.as_mut() {
+            request.last_hash = last_hash;
+            request.deadline = None;
+        }
+        Ok(())
+    }
+
+    pub fn on_block(&mut self, block_hash: &BlockHash) {
+        self.queued.remove(block_hash);
+        self.attempts.remove(block_hash);
+        for requests in self.requests.values_mut() {
+            for request in requests.iter_mut() {
+                request.block_hashes.retain(|hash| hash != block_hash);
+            }
+            requests.retain(|request| !request.block_hashes.is_empty());
+        }
+    }
+
+    /// Expires timed out requests and issues new ones.
+    pub fn poll(&mut self, now: Instant, block_forest: &BlockForest) -> Vec<PeerCommand> {
+        let mut commands = vec![];
+        self.poll_headers(now, block_forest, &mut commands);
+        self.poll_blocks(now, block_forest, &mut commands);
+        commands
+    }
+
+    /// Builds the replies to a sync request, returns `None` for other messages.
+    pub fn respond(
+        &self,
+        message: &VerifiedPeerMessage,
+        block_forest: &BlockForest,
+    ) -> Option<Vec<VerifiedPeerMessage>> {
+        match message {
+            VerifiedPeerMessage::GetHeaders { locator, limit } => {
+                let limit = (*limit as usize).min(self.config.max_headers_per_message);
+                let headers = block_forest.headers_after(locator, limit);
+                Some(vec![VerifiedPeerMessage::Headers { headers }])
+            }
+            VerifiedPeerMessage::GetBlocks { block_hashes } => Some(
+                block_hashes
+                    .iter()
+                    .take(self.config.max_blocks_per_request)
+                    .filter_map(|hash| block_forest.find_block(hash))
+                    .map(|block| VerifiedPeerMessage::Block(Box::new((**block).clone())))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn poll_headers(
+        &mut self,
+        now: Instant,
+        block_forest: &BlockForest,
+        commands: &mut Vec<PeerCommand>,
+    ) {
+        let Some(request) = self.headers_request.as_mut() else {
+            return;
+        };
+        match request.deadline {
+            Some(deadline) if deadline <= now => {
+                warn!(
+                    "headers request to session {} has timed out",
+                    request.session_id
+                );
+                self.headers_request = None;
+            }
+            Some(_) => {}
+            None if self.queued.len() >= self.config.max_queued_blocks => {}
+            None => {
+                let mut locator = block_forest.block_locator();
+                if let Some(last_hash) = request.last_hash {
+                    locator.insert(0, last_hash);
+                }
+                request.deadline = Some(now + self.config.request_timeout);
+                commands.push(PeerCommand {
+                    session_id: request.session_id,
+                    command_kind: PeerCommandKind::SendMessage(VerifiedPeerMessage::GetHeaders {
+                        locator,
+                        limit: self.config.max_headers_per_message as u64,
+                    }),
+                });
+            }
+        }
+    }
+
+    fn poll_blocks(
+        &mut self,
+        now: Instant,
+        block_forest: &BlockForest,
+        commands: &mut Vec<PeerCommand>,
+    ) {
+        let mut expired = vec![];
+        for (session_id, requests) in self.requests.iter_mut() {
+            let (timed_out, pending) = requests
+                .drain(..)
+                .partition(|request| request.deadline <= now);
+            *requests = pending;
+            if !timed_out.is_empty() {
+                warn!("block requests to session {} have timed out", session_id);
+            }
+            expired.extend(timed_out);
+        }
+        self.requeue(expired);
+
+        // Deal batches out to sessions in turn, so the bodies are downloaded in parallel.
+        let mut has_capacity = true;
+        while has_capacity {
+            has_capacity = false;
+            for session_id in self.sessions.iter() {
+                let requests = self.requests.entry(*session_id).or_default();
+                if requests.len() >= self.config.max_requests_per_session {
+                    continue;
+                }
+
+                let mut block_hashes = vec![];
+                while block_hashes.len() < self.config.max_blocks_per_request {
+                    let Some(hash) = self.queue.pop_front() else {
+                        break;
+                    };
+                    if block_forest.find_block(&hash).is_some() {
+                        self.queued.remove(&hash);
+                        self.attempts.remove(&hash);
+                    } else {
+                        block_hashes.push(hash);
+                    }
+                }
+                if block_hashes.is_empty() {
+                    return;
+                }
+
+                requests.push(BlocksRequest {
+                    block_hashes: block_hashes.clone(),
+                    deadline: now + self.config.request_timeout,
+                });
+                commands.push(PeerCommand {
+                    session_id: *session_id,
+                    command_kind: PeerCommandKind::SendMessage(VerifiedPeerMessage::GetBlocks {
+                        block_hashes,
+                    }),
+                });
+                has_capacity = true;
+            }
+        }
+    }
+
+    fn is_requested(&self, hash: &BlockHash) -> bool {
+        self.requests
+            .values()
+            .flatten()
+            .any(|request| request.block_hashes.contains(hash))
+    }
+
+    /// Puts the blocks of failed requests back to the front of the queue, unless they have run
+    /// out of attempts.
+    fn requeue(&mut self, requests: Vec<BlocksRequest>) {
+        for request in requests.into_iter().rev() {
+            for hash in request.block_hashes.into_iter().rev() {
+                let attempts = self.attempts.entry(hash).or_default();
+                *attempts += 1;
+                if *attempts < self.config.max_request_attempts {
+                    self.queue.push_front(hash);
+                    continue;
+                }
+
+                warn!(
+                    "block {} was not received in {} requests, dropping it",
+                    base64::encode(hash),
+                    attempts
+                );
+                self.attempts.remove(&hash);
+                self.queued.remove(&hash);
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::make_chain;
+
+    fn sent_messages(commands: Vec<PeerCommand>) -> Vec<(SessionId, VerifiedPeerMessage)> {
+        commands
+            .into_iter()
+            .map(|command| match command.command_kind {
+                PeerCommandKind::SendMessage(message) => (command.session_id, message),
+                command_kind => panic!("unexpected command {:?}", command_kind),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sync() {
+        let config = ChainSyncConfig {
+            max_headers_per_message: 8,
+            max_blocks_per_request: 3,
+            max_requests_per_session: 2,
+            ..Default::default()
+        };
+        let chain = make_chain(20);
+        let mut remote = BlockForest::new();
+        for block in chain.iter().skip(1) {
+            remote.add_block(block.clone()).unwrap();
+        }
+        let responder = ChainSync::new(config.clone());
+
+        let mut local = BlockForest::new();
+        local.add_block(chain[1].clone()).unwrap();
+        let mut sync = ChainSync::new(config);
+        sync.add_session(1);
+        sync.add_session(2);
+
+        assert!(!sync.on_orphan_block(1, 5, &local));
+        assert!(sync.on_orphan_block(1, 20, &local));
+
+        let now = Instant::now();
+        let mut rounds = 0;
+        while local.head().index < 20 {
+            rounds += 1;
+            assert!(rounds < 20, "sync is stuck");
+
+            let mut sessions = HashSet::new();
+            for (session_id, message) in sent_messages(sync.poll(now, &local)) {
+                sessions.insert(session_id);
+                for reply in responder.respond(&message, &remote).unwrap() {
+                    match reply {
+                        VerifiedPeerMessage::Headers { headers } => {
+                            sync.on_headers(session_id, headers, &local).unwrap()
+                        }
+                        VerifiedPeerMessage::Block(block) => {
+                            sync.on_block(block.hash());
+                            local.add_block(*block).unwrap();
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+            }
+            if sessions.len() > 1 {
+                assert_eq!(sessions, HashSet::from([1, 2]));
+            }
+        }
+        assert_eq!(local.head().hash(), chain[20].hash());
+        assert!(!sync.is_syncing());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let config = ChainSyncConfig {
+            max_headers_per_message: 4,
+            max_blocks_per_request: 4,
+            ..Default::default()
+        };
+        let chain = make_chain(4);
+        let headers: Vec<_> = chain.iter().skip(1).map(|block| block.header()).collect();
+        let local = BlockForest::new();
+
+        let mut sync = ChainSync::new(config.clone());
+        sync.add_session(1);
+        assert!(sync.on_orphan_block(1, 100, &local));
+        let now = Instant::now();
+        assert_eq!(sent_messages(sync.poll(now, &local)).len(), 1);
+
+        // Headers from another session are ignored.
+        sync.on_headers(2, headers.clone(), &local).unwrap();
+        assert!(sync.poll(now, &local).is_empty());
+
+        sync.on_headers(1, headers.clone(), &local).unwrap();
+        let messages = sent_messages(sync.poll(now, &local));
+        assert!(matches!(
+            &messages[..],
+            [
+                (1, VerifiedPeerMessage::GetHeaders { .. }),
+                (1, VerifiedPeerMessage::GetBlocks { block_hashes }),
+            ] if block_hashes.len() == 4
+        ));
+
+        // The session is gone: the bodies are requested from another one.
+        sync.remove_session(1);
+        sync.add_session(2);
+        let later = now + config.request_timeout;
+        let messages = sent_messages(sync.poll(later, &local));
+        assert!(matches!(
+            &messages[..],
+            [(2, VerifiedPeerMessage::GetBlocks { block_hashes })] if block_hashes.len() == 4
+        ));
+        assert!(sync.is_syncing());
+
+        // Headers that don't connect to a known block abort the sync.
+        let mut sync = ChainSync::new(config);
+        sync.add_session(1);
+        assert!(sync.on_orphan_block(1, 100, &local));
+        assert_eq!(sent_messages(sync.poll(now, &local)).len(), 1);
+        let err = sync
+            .on_headers(1, headers[1..].to_vec(), &local)
+            .unwrap_err();
+        assert_eq!(Misbehaviour::of(&err), Some(Misbehaviour::InvalidMessage));
+        assert!(!sync.is_syncing());
+        assert!(sync.poll(now, &local).is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = ChainSyncConfig {
+            max_headers_per_message: 4,
+            max_blocks_per_request: 4,
+            max_request_attempts: 2,
+            max_queued_blocks: 2,
+            ..Default::default()
+        };
+        let chain = make_chain(4);
+        let headers: Vec<_> = chain.iter().skip(1).map(|block| block.header()).collect();
+        let local = BlockForest::new();
+
+        let mut sync = ChainSync::new(config.clone());
+        sync.add_session(1);
+        assert!(sync.on_orphan_block(1, 100, &local));
+        let now = Instant::now();
+        assert_eq!(sent_messages(sync.poll(now, &local)).len(), 1);
+
+        // Headers sync waits while the queue is full.
+        sync.on_headers(1, headers, &local).unwrap();
+        let messages = sent_messages(sync.poll(now, &local));
+        assert!(matches!(
+            &messages[..],
+            [(1, VerifiedPeerMessage::GetBlocks { block_hashes })] if block_hashes.len() == 2
+        ));
+
+        let mut later = now + config.request_timeout;
+        assert_eq!(sent_messages(sync.poll(later, &local)).len(), 1);
+
+        // The blocks are out of attempts.
+        later += config.request_timeout;
+        assert!(sync.poll(later, &local).is_empty());
+        let messages = sent_messages(sync.poll(later, &local));
+        assert!(matches!(
+            &messages[..],
+            [(1, VerifiedPeerMessage::GetHeaders { locator, .. })] if locator[0] == *chain[2].hash()
+        ));
+    }
+}
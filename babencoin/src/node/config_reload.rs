@@ -0,0 +1,155 @@
+use crate::node::{mining_metrics::MiningMetrics, Config};
+
+use anyhow::Result;
+use tokio::sync::watch;
+use tracing::*;
+
+use std::{fmt::Debug, path::PathBuf, sync::Arc, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Hands the settings which can change without a restart to the services. Other settings of a
+/// reloaded config take effect on the next start of the node.
+pub struct ConfigReload {
+    dial_addresses: watch::Sender<Vec<String>>,
+    eager_requests_interval: watch::Sender<Duration>,
+    mining_thread_count: watch::Sender<usize>,
+    mining_metrics: Arc<MiningMetrics>,
+}
+
+impl ConfigReload {
+    pub fn new(config: &Config, mining_metrics: Arc<MiningMetrics>) -> Self {
+        Self {
+            dial_addresses: watch::channel(config.peer_app.service.dial_addresses.clone()).0,
+            eager_requests_interval: watch::channel(
+                config.gossip_app.service.eager_requests_interval,
+            )
+            .0,
+            mining_thread_count: watch::channel(config.mining_app.service.mining_thread_count).0,
+            mining_metrics,
+        }
+    }
+
+    pub fn dial_addresses(&self) -> watch::Receiver<Vec<String>> {
+        self.dial_addresses.subscribe()
+    }
+
+    pub fn eager_requests_interval(&self) -> watch::Receiver<Duration> {
+        self.eager_requests_interval.subscribe()
+    }
+
+    pub fn mining_thread_count(&self) -> watch::Receiver<usize> {
+        self.mining_thread_count.subscribe()
+    }
+
+    /// Passes the reloadable settings of the config on to the services. Returns the names of
+    /// those that have changed.
+    pub fn apply(&self, config: &Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if update(
+            &self.dial_addresses,
+            config.peer_app.service.dial_addresses.clone(),
+        ) {
+            changed.push("dial_addresses");
+        }
+        if update(
+            &self.eager_requests_interval,
+            config.gossip_app.service.eager_requests_interval,
+        ) {
+            changed.push("eager_requests_interval");
+        }
+        let thread_count = config.mining_app.service.mining_thread_count;
+        if update(&self.mining_thread_count, thread_count) {
+            self.mining_metrics.set_thread_count(thread_count);
+            changed.push("mining_thread_count");
+        }
+        changed
+    }
+
+    /// Rereads the config on every SIGHUP until the task is aborted. A config which fails to load
+    /// is logged and the current settings are kept.
+    pub async fn run(self, config_path: PathBuf) {
+        loop {
+            if let Err(err) = wait_for_hangup().await {
+                error!("failed to listen for SIGHUP, reload is disabled: {:#}", err);
+                return;
+            }
+
+            info!("received SIGHUP, reloading {}", config_path.display());
+            match Config::from_file(&config_path) {
+                Ok(config) => {
+                    let changed = self.apply(&config);
+                    if changed.is_empty() {
+                        info!("no reloadable settings have changed");
+                    } else {
+                        info!("applied {}", changed.join(", "));
+                    }
+                }
+                Err(err) => error!(
+                    "failed to reload config, keeping the current one: {:#}",
+                    err
+                ),
+            }
+        }
+    }
+}
+
+fn update<T: PartialEq + Debug>(sender: &watch::Sender<T>, value: T) -> bool {
+    sender.send_if_modified(|current| {
+        if *current == value {
+            return false;
+        }
+        debug!("{:?} -> {:?}", current, value);
+        *current = value;
+        true
+    })
+}
+
+#[cfg(unix)]
+async fn wait_for_hangup() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_hangup() -> Result<()> {
+    futures::future::pending().await
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut config = Config::default();
+        let metrics = Arc::new(MiningMetrics::new(0));
+        let reload = ConfigReload::new(&config, metrics.clone());
+        let mut dial_addresses = reload.dial_addresses();
+        let mut thread_count = reload.mining_thread_count();
+        assert!(reload.apply(&config).is_empty());
+        assert!(!dial_addresses.has_changed().unwrap());
+
+        config.peer_app.service.dial_addresses = vec!["localhost:9090".into()];
+        config.mining_app.service.mining_thread_count = 2;
+        config.storage_path = Some("blocks.log".into());
+        assert_eq!(
+            reload.apply(&config),
+            vec!["dial_addresses", "mining_thread_count"]
+        );
+        assert_eq!(
+            *dial_addresses.borrow_and_update(),
+            vec!["localhost:9090".to_string()]
+        );
+        assert_eq!(*thread_count.borrow_and_update(), 2);
+        assert_eq!(metrics.thread_count(), 2);
+
+        config.gossip_app.service.eager_requests_interval = Duration::from_secs(5);
+        assert_eq!(reload.apply(&config), vec!["eager_requests_interval"]);
+        assert!(!dial_addresses.has_changed().unwrap());
+    }
+}
@@ -1,23 +1,30 @@
 use crate::{
     block_forest::BlockForest,
     data::{BlockHash, TransactionHash, VerifiedBlock, VerifiedPeerMessage, VerifiedTransaction},
+    logging::{block_span, session_span},
+    node::chain_sync::{ChainSync, ChainSyncConfig},
     node::mining_service::MiningInfo,
     node::peer_service::{PeerCommand, PeerCommandKind, PeerEvent, PeerEventKind, SessionId},
+    node::supervisor::Shutdown,
 };
 
 use anyhow::{Context, Result};
 use futures::{future::pending, stream, Stream, StreamExt};
-use log::*;
 use rand::{seq::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
 use tokio::{
     select,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        watch,
+    },
 };
+use tracing::*;
 
 use std::{
     collections::{HashMap, HashSet},
     pin::pin,
+    sync::{Arc, RwLock},
     time::Duration,
 };
 
@@ -25,10 +32,31 @@ use std::{
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct GossipServiceConfig {
+    /// Applied on SIGHUP without restarting the node.
     #[serde(with = "humantime_serde")]
     pub eager_requests_interval: Duration,
+
+    /// Headers-first download, used instead of eager parent requests when far behind a peer.
+    #[serde(default)]
+    pub sync: ChainSyncConfig,
+}
+
+/// Channels connecting the gossip service to the peer and mining services.
+pub struct GossipChannels {
+    pub event_receiver: Receiver<PeerEvent>,
+    pub command_sender: Sender<PeerCommand>,
+    /// Blocks mined by this node.
+    pub block_receiver: Receiver<VerifiedBlock>,
+    pub mining_info_sender: Sender<MiningInfo>,
 }
 
+/// Peer events are handled within the `session_span` of their session, and blocks within their
+/// `block_span`, so that the logs show where a block came from and where it was relayed to.
+///
+/// `eager_requests_interval` holds the current interval, it changes when the config is reloaded.
+///
+/// Once `shutdown` is triggered, the service handles the events and blocks left in its channels
+/// and returns from `run`.
 pub struct GossipService {
     // TODO: your code here.
 }
@@ -36,10 +64,10 @@ pub struct GossipService {
 impl GossipService {
     pub fn new(
         config: GossipServiceConfig,
-        event_receiver: Receiver<PeerEvent>,
-        command_sender: Sender<PeerCommand>,
-        block_receiver: Receiver<VerifiedBlock>,
-        mining_info_sender: Sender<MiningInfo>,
+        block_forest: Arc<RwLock<BlockForest>>,
+        channels: GossipChannels,
+        eager_requests_interval: watch::Receiver<Duration>,
+        shutdown: Shutdown,
     ) -> Self {
         // TODO: your code here.
         unimplemented!()
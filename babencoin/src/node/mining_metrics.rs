@@ -0,0 +1,236 @@
+use serde::Serialize;
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Counters updated by the mining threads. Hashrates are computed by `update_report`, which the
+/// mining service calls once per `metrics_interval`.
+pub struct MiningMetrics {
+    thread_hashes: RwLock<Vec<AtomicU64>>,
+    /// Hashes of the threads removed by `set_thread_count`, so that the total never decreases.
+    retired_hashes: AtomicU64,
+    blocks_found: AtomicU64,
+    stale_work: AtomicU64,
+    total_block_time_ms: AtomicU64,
+    last_block_time_ms: AtomicU64,
+    last_update: Mutex<(Instant, Vec<u64>)>,
+    report: Mutex<MiningReport>,
+}
+
+/// Mining statistics as of the last `MiningMetrics::update_report` call.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MiningReport {
+    /// Hashes per second of every mining thread over the last report interval.
+    pub thread_hashrates: Vec<f64>,
+    pub total_hashrate: f64,
+    pub total_hashes: u64,
+    pub blocks_found: u64,
+    /// Number of times the threads dropped their work because a new `MiningInfo` arrived.
+    pub stale_work: u64,
+
+    /// Average time from receiving the work to finding a block.
+    #[serde(with = "humantime_serde")]
+    pub avg_block_time: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub last_block_time: Option<Duration>,
+}
+
+impl fmt::Display for MiningReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hashrate {:.0} H/s (", self.total_hashrate)?;
+        for (i, hashrate) in self.thread_hashrates.iter().enumerate() {
+            let separator = if i > 0 { ", " } else { "" };
+            write!(f, "{}{:.0}", separator, hashrate)?;
+        }
+        write!(
+            f,
+            " per thread), {} blocks found, {} stale work",
+            self.blocks_found, self.stale_work
+        )?;
+        if let Some(avg_block_time) = self.avg_block_time {
+            write!(
+                f,
+                ", {:.1}s per block on average",
+                avg_block_time.as_secs_f64()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl MiningMetrics {
+    pub fn new(thread_count: usize) -> Self {
+        Self {
+            thread_hashes: RwLock::new((0..thread_count).map(|_| AtomicU64::new(0)).collect()),
+            retired_hashes: AtomicU64::new(0),
+            blocks_found: AtomicU64::new(0),
+            stale_work: AtomicU64::new(0),
+            total_block_time_ms: AtomicU64::new(0),
+            last_block_time_ms: AtomicU64::new(0),
+            last_update: Mutex::new((Instant::now(), vec![0; thread_count])),
+            report: Mutex::new(MiningReport {
+                thread_hashrates: vec![0.; thread_count],
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.thread_hashes.read().unwrap().len()
+    }
+
+    /// Called when the mining thread count is reloaded. Counters of the remaining threads are kept.
+    pub fn set_thread_count(&self, thread_count: usize) {
+        let mut last_update = self.last_update.lock().unwrap();
+        let mut thread_hashes = self.thread_hashes.write().unwrap();
+        let kept = thread_count.min(thread_hashes.len());
+        for counter in thread_hashes.drain(kept..) {
+            self.retired_hashes
+                .fetch_add(counter.into_inner(), Ordering::Relaxed);
+        }
+        thread_hashes.resize_with(thread_count, || AtomicU64::new(0));
+        last_update.1.resize(thread_count, 0);
+    }
+
+    /// Threads should call it once per batch of hashes rather than for every hash. Hashes of a
+    /// thread removed by `set_thread_count` are dropped.
+    pub fn record_hashes(&self, thread: usize, count: u64) {
+        if let Some(counter) = self.thread_hashes.read().unwrap().get(thread) {
+            counter.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_block_found(&self, time_to_find: Duration) {
+        let ms = time_to_find.as_millis() as u64;
+        self.blocks_found.fetch_add(1, Ordering::Relaxed);
+        self.total_block_time_ms.fetch_add(ms, Ordering::Relaxed);
+        self.last_block_time_ms.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn record_stale_work(&self) {
+        self.stale_work.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> MiningReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Computes hashrates since the previous call and stores the new report.
+    pub fn update_report(&self, now: Instant) -> MiningReport {
+        let mut last_update = self.last_update.lock().unwrap();
+        let (last_time, last_hashes) = &mut *last_update;
+        let elapsed = now.saturating_duration_since(*last_time).as_secs_f64();
+
+        let hashes: Vec<u64> = self
+            .thread_hashes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .collect();
+        let thread_hashrates: Vec<f64> = hashes
+            .iter()
+            .zip(last_hashes.iter())
+            .map(|(current, last)| {
+                if elapsed > 0. {
+                    (current - last) as f64 / elapsed
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        *last_time = now;
+        *last_hashes = hashes.clone();
+
+        let blocks_found = self.blocks_found.load(Ordering::Relaxed);
+        let block_time = |ms| (blocks_found > 0).then(|| Duration::from_millis(ms));
+        let report = MiningReport {
+            total_hashrate: thread_hashrates.iter().sum(),
+            thread_hashrates,
+            total_hashes: hashes.iter().sum::<u64>() + self.retired_hashes.load(Ordering::Relaxed),
+            blocks_found,
+            stale_work: self.stale_work.load(Ordering::Relaxed),
+            avg_block_time: block_time(
+                self.total_block_time_ms.load(Ordering::Relaxed) / blocks_found.max(1),
+            ),
+            last_block_time: block_time(self.last_block_time_ms.load(Ordering::Relaxed)),
+        };
+        *self.report.lock().unwrap() = report.clone();
+        report
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let metrics = MiningMetrics::new(2);
+        let start = metrics.last_update.lock().unwrap().0;
+        assert_eq!(metrics.report().thread_hashrates, vec![0., 0.]);
+        assert_eq!(metrics.report().avg_block_time, None);
+
+        metrics.record_hashes(0, 1000);
+        metrics.record_hashes(1, 500);
+        metrics.record_hashes(1, 500);
+        metrics.record_stale_work();
+        metrics.record_block_found(Duration::from_secs(4));
+        metrics.record_block_found(Duration::from_secs(2));
+
+        let report = metrics.update_report(start + Duration::from_secs(2));
+        assert_eq!(report.thread_hashrates, vec![500., 500.]);
+        assert_eq!(report.total_hashrate, 1000.);
+        assert_eq!(report.total_hashes, 2000);
+        assert_eq!(report.blocks_found, 2);
+        assert_eq!(report.stale_work, 1);
+        assert_eq!(report.avg_block_time, Some(Duration::from_secs(3)));
+        assert_eq!(report.last_block_time, Some(Duration::from_secs(2)));
+        assert_eq!(metrics.report(), report);
+        assert_eq!(
+            report.to_string(),
+            "hashrate 1000 H/s (500, 500 per thread), 2 blocks found, 1 stale work, \
+             3.0s per block on average"
+        );
+
+        // Rates cover the last interval only.
+        metrics.record_hashes(0, 400);
+        let report = metrics.update_report(start + Duration::from_secs(4));
+        assert_eq!(report.thread_hashrates, vec![200., 0.]);
+        assert_eq!(report.total_hashes, 2400);
+    }
+
+    #[test]
+    fn test_set_thread_count() {
+        let metrics = MiningMetrics::new(2);
+        let start = metrics.last_update.lock().unwrap().0;
+        metrics.record_hashes(0, 100);
+        metrics.record_hashes(1, 200);
+        metrics.update_report(start + Duration::from_secs(1));
+
+        metrics.set_thread_count(3);
+        assert_eq!(metrics.thread_count(), 3);
+        metrics.record_hashes(0, 100);
+        metrics.record_hashes(2, 300);
+        let report = metrics.update_report(start + Duration::from_secs(2));
+        assert_eq!(report.thread_hashrates, vec![100., 0., 300.]);
+        assert_eq!(report.total_hashes, 700);
+
+        // Threads which are gone keep counting towards the total.
+        metrics.set_thread_count(1);
+        metrics.record_hashes(2, 1000);
+        let report = metrics.update_report(start + Duration::from_secs(3));
+        assert_eq!(report.thread_hashrates, vec![0.]);
+        assert_eq!(report.total_hashes, 700);
+    }
+}
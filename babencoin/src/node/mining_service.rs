@@ -3,19 +3,23 @@ use crate::{
         Block, BlockAttributes, BlockHash, Transaction, VerifiedBlock, VerifiedTransaction,
         WalletId, MAX_REWARD,
     },
+    node::{mining_metrics::MiningMetrics, supervisor::Shutdown},
     util::{deserialize_wallet_id, serialize_wallet_id},
 };
 
 use anyhow::{Context, Result};
 use chrono::Utc;
 use futures::{stream, Stream, StreamExt};
-use log::*;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use tokio::{
     select,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        watch,
+    },
 };
+use tracing::*;
 
 use std::{
     pin::pin,
@@ -24,13 +28,18 @@ use std::{
         Arc, RwLock,
     },
     thread,
+    time::Duration,
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Serialize, Deserialize)]
 pub struct MiningServiceConfig {
+    /// Mining is disabled if zero. Applied on SIGHUP without restarting the node.
+    #[serde(default)]
     pub mining_thread_count: usize,
+    /// Mined blocks must fit into the `max_frame_size` of the peers.
+    #[serde(default = "default_max_tx_per_block")]
     pub max_tx_per_block: usize,
 
     #[serde(
@@ -38,18 +47,31 @@ pub struct MiningServiceConfig {
         deserialize_with = "deserialize_wallet_id"
     )]
     pub public_key: WalletId,
+
+    /// Hashrates are averaged over this interval and logged at its end.
+    #[serde(default = "default_metrics_interval", with = "humantime_serde")]
+    pub metrics_interval: Duration,
 }
 
 impl Default for MiningServiceConfig {
     fn default() -> Self {
         Self {
             mining_thread_count: 0,
-            max_tx_per_block: 0,
+            max_tx_per_block: default_max_tx_per_block(),
             public_key: WalletId::genesis(),
+            metrics_interval: default_metrics_interval(),
         }
     }
 }
 
+fn default_max_tx_per_block() -> usize {
+    10
+}
+
+fn default_metrics_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Clone, Debug)]
@@ -60,6 +82,10 @@ pub struct MiningInfo {
     pub transactions: Vec<VerifiedTransaction>,
 }
 
+/// `thread_count` starts at `mining_thread_count` and changes when the config is reloaded. The
+/// service then restarts its mining threads on the current work and resizes `metrics`.
+///
+/// Once `shutdown` is triggered, the service stops the mining threads and returns from `run`.
 pub struct MiningService {
     // TODO: your code here.
 }
@@ -69,6 +95,9 @@ impl MiningService {
         config: MiningServiceConfig,
         info_receiver: Receiver<MiningInfo>,
         block_sender: Sender<VerifiedBlock>,
+        metrics: Arc<MiningMetrics>,
+        thread_count: watch::Receiver<usize>,
+        shutdown: Shutdown,
     ) -> Self {
         // TODO: your code here.
         unimplemented!()
@@ -94,4 +123,3 @@ impl MiningService {
 
     // TODO: your code here.
 }
-
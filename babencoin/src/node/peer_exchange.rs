@@ -0,0 +1,295 @@
+use crate::{
+    codec::WireFormat,
+    data::{Hello, VerifiedBlock, MAX_PEER_ADDRESSES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+};
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_DIAL_FAILURES: u32 = 3;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerExchangeConfig {
+    /// Known addresses are dialed until there are this many outbound sessions.
+    pub target_outbound_sessions: usize,
+    pub max_known_addresses: usize,
+
+    /// How often connected peers are asked for the addresses they know.
+    #[serde(with = "humantime_serde")]
+    pub exchange_interval: Duration,
+
+    /// Sessions that have not sent `Hello` in time are treated as legacy ones.
+    #[serde(with = "humantime_serde")]
+    pub handshake_timeout: Duration,
+}
+
+impl Default for PeerExchangeConfig {
+    fn default() -> Self {
+        Self {
+            target_outbound_sessions: 8,
+            max_known_addresses: 1000,
+            exchange_interval: Duration::from_secs(60),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    IncompatibleVersion { protocol_version: u32 },
+    SelfConnection,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IncompatibleVersion { protocol_version } => write!(
+                f,
+                "peer protocol version {} is older than the minimal supported {}",
+                protocol_version, MIN_PROTOCOL_VERSION
+            ),
+            Self::SelfConnection => write!(f, "connected to self"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+pub struct Handshake {
+    node_id: u64,
+    listen_address: Option<String>,
+    wire_format: WireFormat,
+}
+
+impl Handshake {
+    pub fn new(listen_address: Option<String>, wire_format: WireFormat) -> Self {
+        Self {
+            node_id: rand::random(),
+            listen_address,
+            wire_format,
+        }
+    }
+
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    pub fn hello(&self, head: &VerifiedBlock) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            node_id: self.node_id,
+            head_index: head.index,
+            head_hash: *head.hash(),
+            listen_address: self.listen_address.clone(),
+            wire_formats: match self.wire_format {
+                WireFormat::Json => vec![],
+                WireFormat::Binary => vec![WireFormat::Binary],
+            },
+        }
+    }
+
+    /// Returns the format to write messages to the peer in. Every session starts with JSON, so
+    /// that legacy peers can read the handshake.
+    pub fn negotiate_format(&self, remote: &Hello) -> WireFormat {
+        if self.wire_format == WireFormat::Binary
+            && remote.wire_formats.contains(&WireFormat::Binary)
+        {
+            WireFormat::Binary
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    pub fn check(&self, remote: &Hello) -> Result<(), HandshakeError> {
+        if remote.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::IncompatibleVersion {
+                protocol_version: remote.protocol_version,
+            });
+        }
+        if remote.node_id == self.node_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct AddressEntry {
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// Listen addresses of other nodes, learned from handshakes and `Peers` messages.
+pub struct AddressBook {
+    max_addresses: usize,
+    entries: HashMap<String, AddressEntry>,
+}
+
+impl AddressBook {
+    pub fn new(config: &PeerExchangeConfig) -> Self {
+        Self {
+            max_addresses: config.max_known_addresses,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.entries.contains_key(address)
+    }
+
+    /// Remembers the address, evicting the least reliable one if the book is full.
+    pub fn add(&mut self, address: &str, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(address) {
+            entry.last_seen = entry.last_seen.max(now);
+            return;
+        }
+        if self.max_addresses == 0 {
+            return;
+        }
+        if self.entries.len() >= self.max_addresses {
+            let worst = self
+                .entries
+                .iter()
+                .max_by_key(|(address, entry)| {
+                    (entry.failures, std::cmp::Reverse(entry.last_seen), *address)
+                })
+                .map(|(address, _)| address.clone());
+            if let Some(worst) = worst {
+                self.entries.remove(&worst);
+            }
+        }
+        self.entries.insert(
+            address.to_owned(),
+            AddressEntry {
+                last_seen: now,
+                failures: 0,
+            },
+        );
+    }
+
+    pub fn mark_connected(&mut self, address: &str, now: Instant) {
+        self.add(address, now);
+        if let Some(entry) = self.entries.get_mut(address) {
+            entry.failures = 0;
+        }
+    }
+
+    /// Forgets the address after several failed dials in a row.
+    pub fn mark_failed(&mut self, address: &str) {
+        let Some(entry) = self.entries.get_mut(address) else {
+            return;
+        };
+        entry.failures += 1;
+        if entry.failures >= MAX_DIAL_FAILURES {
+            self.entries.remove(address);
+        }
+    }
+
+    /// Returns up to `count` addresses to dial, the most reliable and recently seen first.
+    pub fn dial_candidates(&self, count: usize, exclude: &HashSet<String>) -> Vec<String> {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(address, _)| !exclude.contains(*address))
+            .collect();
+        candidates.sort_by_key(|(address, entry)| {
+            (entry.failures, std::cmp::Reverse(entry.last_seen), *address)
+        });
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    /// Returns the addresses to send in a `Peers` message.
+    pub fn addresses_to_share(&self) -> Vec<String> {
+        self.dial_candidates(MAX_PEER_ADDRESSES, &HashSet::new())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let local = Handshake::new(Some("localhost:9090".into()), WireFormat::Binary);
+        let remote = Handshake::new(None, WireFormat::Binary);
+        let genesis = VerifiedBlock::genesis();
+
+        let hello = remote.hello(&genesis);
+        assert_eq!(hello.head_hash, *genesis.hash());
+        assert_eq!(local.check(&hello), Ok(()));
+        assert_eq!(local.negotiate_format(&hello), WireFormat::Binary);
+
+        let json_only = Handshake::new(None, WireFormat::Json);
+        assert_eq!(
+            local.negotiate_format(&json_only.hello(&genesis)),
+            WireFormat::Json
+        );
+        assert_eq!(json_only.negotiate_format(&hello), WireFormat::Json);
+        assert_eq!(
+            local.check(&local.hello(&genesis)),
+            Err(HandshakeError::SelfConnection)
+        );
+
+        let outdated = Hello {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            ..hello
+        };
+        assert_eq!(
+            local.check(&outdated),
+            Err(HandshakeError::IncompatibleVersion {
+                protocol_version: MIN_PROTOCOL_VERSION - 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_address_book() {
+        let mut book = AddressBook::new(&PeerExchangeConfig {
+            max_known_addresses: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        book.add("a:1", now);
+        book.add("b:1", now + Duration::from_secs(1));
+        book.mark_failed("a:1");
+        book.add("c:1", now + Duration::from_secs(2));
+        assert!(!book.contains("a:1"));
+        assert_eq!(book.len(), 2);
+
+        let exclude = HashSet::from(["c:1".to_owned()]);
+        assert_eq!(book.dial_candidates(5, &exclude), vec!["b:1".to_owned()]);
+        assert_eq!(book.addresses_to_share(), vec!["c:1", "b:1"]);
+
+        for _ in 0..MAX_DIAL_FAILURES {
+            book.mark_failed("b:1");
+        }
+        assert_eq!(book.addresses_to_share(), vec!["c:1"]);
+    }
+}
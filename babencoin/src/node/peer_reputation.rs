@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerReputationConfig {
+    /// An address is banned once its score reaches this value.
+    pub ban_threshold: u64,
+    pub invalid_message_penalty: u64,
+    pub bad_block_penalty: u64,
+    pub oversized_frame_penalty: u64,
+
+    #[serde(with = "humantime_serde")]
+    pub ban_duration: Duration,
+
+    /// The score is decreased by one every such period of good behaviour.
+    #[serde(with = "humantime_serde")]
+    pub score_decay_period: Duration,
+}
+
+impl Default for PeerReputationConfig {
+    fn default() -> Self {
+        Self {
+            ban_threshold: 100,
+            invalid_message_penalty: 20,
+            bad_block_penalty: 50,
+            oversized_frame_penalty: 100,
+            ban_duration: Duration::from_secs(3600),
+            score_decay_period: Duration::from_secs(10),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// A message that can't be parsed or fails verification.
+    InvalidMessage,
+    /// A block rejected by the block forest. Neither a `ReorgTooDeep` nor a `BlockNotPersisted`
+    /// one is the sender's fault.
+    BadBlock,
+    OversizedFrame,
+}
+
+impl Misbehaviour {
+    /// Extracts the misbehaviour attached to an error as context, if any.
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        err.downcast_ref::<Self>().copied()
+    }
+
+    fn penalty(&self, config: &PeerReputationConfig) -> u64 {
+        match self {
+            Self::InvalidMessage => config.invalid_message_penalty,
+            Self::BadBlock => config.bad_block_penalty,
+            Self::OversizedFrame => config.oversized_frame_penalty,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMessage => write!(f, "invalid message"),
+            Self::BadBlock => write!(f, "bad block"),
+            Self::OversizedFrame => write!(f, "oversized frame"),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Score {
+    value: u64,
+    updated_at: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Misbehaviour scores of remote IP addresses. Addresses reaching the ban threshold are banned
+/// for a while, after which they start over with a clean score.
+pub struct PeerReputation {
+    config: PeerReputationConfig,
+    scores: HashMap<IpAddr, Score>,
+}
+
+impl PeerReputation {
+    pub fn new(config: PeerReputationConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the address has been banned because of this misbehaviour.
+    pub fn penalize(&mut self, ip: IpAddr, misbehaviour: Misbehaviour, now: Instant) -> bool {
+        if self.is_banned(ip, now) {
+            return false;
+        }
+
+        let score = self.decayed_score(ip, now);
+        let value = score.saturating_add(misbehaviour.penalty(&self.config));
+        let banned_until = if value >= self.config.ban_threshold {
+            warn!(
+                "banning {} for {:?}: score {} after {}",
+                ip, self.config.ban_duration, value, misbehaviour
+            );
+            Some(now + self.config.ban_duration)
+        } else {
+            debug!("penalizing {} for {}: score {}", ip, misbehaviour, value);
+            None
+        };
+        self.scores.insert(
+            ip,
+            Score {
+                value,
+                updated_at: now,
+                banned_until,
+            },
+        );
+        banned_until.is_some()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.scores
+            .get(&ip)
+            .and_then(|score| score.banned_until)
+            .is_some_and(|banned_until| now < banned_until)
+    }
+
+    pub fn score(&self, ip: IpAddr, now: Instant) -> u64 {
+        if self.is_banned(ip, now) {
+            return self.scores[&ip].value;
+        }
+        self.decayed_score(ip, now)
+    }
+
+    /// Forgets the addresses whose bans have expired and whose scores have decayed to zero.
+    pub fn cleanup(&mut self, now: Instant) {
+        let decay_period = self.config.score_decay_period;
+        self.scores.retain(|_, score| match score.banned_until {
+            Some(banned_until) => now < banned_until,
+            None => decay(score, decay_period, now) > 0,
+        });
+    }
+
+    fn decayed_score(&self, ip: IpAddr, now: Instant) -> u64 {
+        match self.scores.get(&ip) {
+            // An expired ban starts over with a clean score.
+            Some(Score {
+                banned_until: Some(_),
+                ..
+            }) => 0,
+            Some(score) => decay(score, self.config.score_decay_period, now),
+            None => 0,
+        }
+    }
+}
+
+fn decay(score: &Score, decay_period: Duration, now: Instant) -> u64 {
+    if decay_period.is_zero() {
+        return 0;
+    }
+    let elapsed = now.saturating_duration_since(score.updated_at);
+    let periods = elapsed.as_nanos() / decay_period.as_nanos();
+    score
+        .value
+        .saturating_sub(periods.try_into().unwrap_or(u64::MAX))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_ban() {
+        let config = PeerReputationConfig {
+            ban_threshold: 100,
+            invalid_message_penalty: 40,
+            ban_duration: Duration::from_secs(60),
+            score_decay_period: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut reputation = PeerReputation::new(config);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(!reputation.penalize(ip, Misbehaviour::InvalidMessage, now));
+        assert!(!reputation.penalize(ip, Misbehaviour::InvalidMessage, now));
+        assert_eq!(reputation.score(ip, now + Duration::from_secs(5)), 75);
+        assert!(reputation.penalize(ip, Misbehaviour::BadBlock, now + Duration::from_secs(5)));
+        assert!(reputation.is_banned(ip, now + Duration::from_secs(6)));
+        assert!(!reputation.is_banned(other, now + Duration::from_secs(6)));
+
+        let later = now + Duration::from_secs(70);
+        assert!(!reputation.is_banned(ip, later));
+        assert_eq!(reputation.score(ip, later), 0);
+        reputation.cleanup(later);
+        assert!(reputation.scores.is_empty());
+
+        assert!(reputation.penalize(ip, Misbehaviour::OversizedFrame, later));
+    }
+
+    #[test]
+    fn test_error_context() {
+        let err = anyhow!("message is too large").context(Misbehaviour::OversizedFrame);
+        assert_eq!(Misbehaviour::of(&err), Some(Misbehaviour::OversizedFrame));
+        assert_eq!(Misbehaviour::of(&anyhow!("peer has disconnected")), None);
+    }
+}
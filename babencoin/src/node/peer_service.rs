@@ -1,11 +1,21 @@
-use crate::data::{PeerMessage, VerifiedPeerMessage};
+use crate::{
+    block_forest::BlockForest,
+    codec::{decode_frame, FrameError, WireFormat},
+    consensus::BlockRules,
+    data::{Hello, PeerMessage, VerifiedPeerMessage},
+    logging::session_span,
+    node::{
+        peer_exchange::{AddressBook, Handshake, HandshakeError, PeerExchangeConfig},
+        peer_reputation::{Misbehaviour, PeerReputation, PeerReputationConfig},
+        supervisor::Shutdown,
+    },
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures::{
     stream::{self, FuturesUnordered},
     FutureExt, Stream, StreamExt,
 };
-use log::*;
 use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
@@ -14,34 +24,72 @@ use tokio::{
         TcpListener, TcpStream,
     },
     select,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        watch,
+    },
     task::JoinHandle,
 };
+use tracing::*;
 
 use std::{
     collections::HashMap,
     pin::pin,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Mutex,
+        Arc, Mutex, RwLock,
     },
     time::Duration,
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
-const BUF_SIZE: usize = 65536;
+const READ_CHUNK_SIZE: usize = 65536;
 
 pub type SessionId = u64;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PeerServiceConfig {
     #[serde(with = "humantime_serde")]
     pub dial_cooldown: Duration,
+    /// Applied on SIGHUP without restarting the node.
     pub dial_addresses: Vec<String>,
     pub listen_address: Option<String>,
+
+    /// Format of outgoing messages to peers which support it, incoming ones are accepted in any.
+    #[serde(default)]
+    pub wire_format: WireFormat,
+
+    /// Peers sending larger frames are penalized and disconnected.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+
+    #[serde(default)]
+    pub peer_exchange: PeerExchangeConfig,
+
+    /// Misbehaving peers are banned by IP, both existing and new sessions from it are dropped.
+    #[serde(default)]
+    pub reputation: PeerReputationConfig,
+}
+
+impl Default for PeerServiceConfig {
+    fn default() -> Self {
+        Self {
+            dial_cooldown: Duration::default(),
+            dial_addresses: vec![],
+            listen_address: None,
+            wire_format: WireFormat::default(),
+            max_frame_size: default_max_frame_size(),
+            peer_exchange: PeerExchangeConfig::default(),
+            reputation: PeerReputationConfig::default(),
+        }
+    }
+}
+
+fn default_max_frame_size() -> usize {
+    64 << 10
 }
 
 #[derive(Debug, Clone)]
@@ -54,9 +102,27 @@ pub struct PeerEvent {
 pub enum PeerEventKind {
     Connected,
     Disconnected,
+    /// The peer has sent a compatible `Hello`.
+    Handshake(Box<Hello>),
+    /// The peer has sent a `Hello` with an unsupported protocol version, the session is dropped.
+    IncompatibleVersion {
+        protocol_version: u32,
+    },
     NewMessage(VerifiedPeerMessage),
 }
 
+/// Sessions that are currently connected, shared with the read-only query API.
+pub type SessionRegistry = Arc<RwLock<HashMap<SessionId, SessionInfo>>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub session_id: SessionId,
+    pub address: String,
+    pub is_outbound: bool,
+    /// Set once the peer has completed the handshake.
+    pub hello: Option<Hello>,
+}
+
 #[derive(Debug, Clone)]
 pub struct PeerCommand {
     pub session_id: SessionId,
@@ -66,11 +132,20 @@ pub struct PeerCommand {
 #[derive(Debug, Clone)]
 pub enum PeerCommandKind {
     SendMessage(VerifiedPeerMessage),
+    /// Lowers the reputation of the peer address, dropping the session if it gets banned.
+    Penalize(Misbehaviour),
     Drop,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Every session runs within its `session_span`.
+///
+/// `dial_addresses` holds the addresses to keep dialing. When the config is reloaded, new addresses
+/// are dialed right away and removed ones are no longer redialed, their sessions stay open.
+///
+/// Once `shutdown` is triggered, the service stops accepting and dialing peers, flushes and closes
+/// every session and returns from `run`.
 pub struct PeerService {
     // TODO: your code here.
 }
@@ -78,8 +153,12 @@ pub struct PeerService {
 impl PeerService {
     pub fn new(
         config: PeerServiceConfig,
+        block_forest: Arc<RwLock<BlockForest>>,
+        sessions: SessionRegistry,
         peer_event_sender: Sender<PeerEvent>,
         command_receiver: Receiver<PeerCommand>,
+        dial_addresses: watch::Receiver<Vec<String>>,
+        shutdown: Shutdown,
     ) -> Self {
         // TODO: your code here.
         unimplemented!()
@@ -97,16 +176,19 @@ impl PeerService {
 
 struct MessageReader<'a> {
     inner: ReadHalf<'a>,
-    buffer: Box<[u8; BUF_SIZE]>,
-    len: usize,
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    /// Rules of the forest, so that blocks breaking them are rejected as invalid messages.
+    rules: BlockRules,
 }
 
 impl<'a> MessageReader<'a> {
-    fn new(inner: ReadHalf<'a>) -> Self {
+    fn new(inner: ReadHalf<'a>, max_frame_size: usize, rules: BlockRules) -> Self {
         Self {
             inner,
-            buffer: Box::new([0u8; BUF_SIZE]),
-            len: 0,
+            buffer: Vec::new(),
+            max_frame_size,
+            rules,
         }
     }
 
@@ -120,35 +202,40 @@ impl<'a> MessageReader<'a> {
     }
 
     async fn next_message(&mut self) -> Result<VerifiedPeerMessage> {
-        if let Some(msg) = self.try_parse_message()? {
-            return Ok(msg);
-        }
-        while self.len < BUF_SIZE {
-            let bytes_read = self.inner.read(&mut self.buffer[self.len..]).await?;
-            if bytes_read == 0 {
-                bail!("peer has disconnected");
-            }
-            self.len += bytes_read;
+        loop {
             if let Some(msg) = self.try_parse_message()? {
                 return Ok(msg);
             }
+            let len = self.buffer.len();
+            self.buffer.resize(len + READ_CHUNK_SIZE, 0);
+            let read_result = self.inner.read(&mut self.buffer[len..]).await;
+            let bytes_read = *read_result.as_ref().unwrap_or(&0);
+            self.buffer.truncate(len + bytes_read);
+            if read_result? == 0 {
+                bail!("peer has disconnected");
+            }
         }
-        bail!("message is larger than {} bytes", BUF_SIZE);
     }
 
     fn try_parse_message(&mut self) -> Result<Option<VerifiedPeerMessage>> {
-        let Some(zero_pos) = self.buffer[..self.len].iter().position(|b| *b == 0) else {
-            return Ok(None);
+        let (msg, frame_len) = match decode_frame(&self.buffer, self.max_frame_size) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(None),
+            Err(err @ FrameError::TooLarge { .. }) => {
+                return Err(anyhow!(err).context(Misbehaviour::OversizedFrame))
+            }
+            Err(FrameError::Invalid(err)) => return Err(err.context(Misbehaviour::InvalidMessage)),
         };
-        let data_str = std::str::from_utf8(&self.buffer[..zero_pos])
-            .context("message is not a valid utf-8")?;
-        let msg: PeerMessage =
-            serde_json::from_str(data_str).context("failed to deserialize message")?;
-        let verified_msg = msg.verified().context("message verification failed")?;
-
-        self.buffer[..self.len].rotate_left(zero_pos + 1);
-        self.len -= zero_pos + 1;
+        self.buffer.drain(..frame_len);
 
+        let verified_msg = self
+            .verify_message(msg)
+            .context(Misbehaviour::InvalidMessage)?;
         Ok(Some(verified_msg))
     }
+
+    fn verify_message(&self, msg: PeerMessage) -> Result<VerifiedPeerMessage> {
+        msg.verified(&self.rules)
+            .context("message verification failed")
+    }
 }
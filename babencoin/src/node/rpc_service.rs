@@ -0,0 +1,630 @@
+use crate::{
+    block_forest::{BlockForest, WalletEventKind},
+    data::{
+        BlockAttributes, BlockHash, Transaction, VerifiedBlock, VerifiedTransaction, WalletId,
+        HASH_LEN,
+    },
+    node::{mining_metrics::MiningMetrics, peer_service::SessionRegistry, supervisor::Shutdown},
+    util::decode_wallet_id,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    time::timeout,
+};
+use tracing::*;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_REQUEST_SIZE: usize = 16384;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize)]
+pub struct RpcServiceConfig {
+    pub listen_address: String,
+
+    /// Clients which have not sent a whole request in time are disconnected.
+    #[serde(default = "default_read_timeout", with = "humantime_serde")]
+    pub read_timeout: Duration,
+}
+
+impl Default for RpcServiceConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: String::new(),
+            read_timeout: default_read_timeout(),
+        }
+    }
+}
+
+fn default_read_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Read-only HTTP API over the node state. Every response is a json document.
+///
+/// * `GET /head` - the current head;
+/// * `GET /block?hash=<base64>` or `GET /block?index=<n>` - a block by hash, or by index on the
+///   main chain;
+/// * `GET /balance?wallet=<base64>[&block=<base64>]` - the wallet balance and nonce at the head
+///   or at the given block;
+/// * `GET /history?wallet=<base64>` - the main chain transfers and mining rewards of the wallet;
+/// * `GET /confirmations?tx=<base64>` - the confirmation depth of a transaction;
+/// * `GET /proof?tx=<base64>` - the header of the main chain block containing a transaction
+///   and the Merkle proof of its inclusion;
+/// * `GET /pending` - the pending transaction pool;
+/// * `GET /peers` - the connected peer sessions;
+/// * `GET /mining` - the hashrates and block statistics of the mining threads.
+pub struct RpcService {
+    config: RpcServiceConfig,
+    block_forest: Arc<RwLock<BlockForest>>,
+    sessions: SessionRegistry,
+    mining_metrics: Arc<MiningMetrics>,
+    shutdown: Shutdown,
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+
+    fn status_text(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl RpcService {
+    pub fn new(
+        config: RpcServiceConfig,
+        block_forest: Arc<RwLock<BlockForest>>,
+        sessions: SessionRegistry,
+        mining_metrics: Arc<MiningMetrics>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            config,
+            block_forest,
+            sessions,
+            mining_metrics,
+            shutdown,
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let listener = TcpListener::bind(&self.config.listen_address)
+            .await
+            .context(format!("failed to bind {}", self.config.listen_address))?;
+        info!("rpc service is listening on {}", self.config.listen_address);
+
+        loop {
+            let (stream, addr) = select! {
+                accepted = listener.accept() => accepted.context("failed to accept")?,
+                _ = self.shutdown.wait() => return Ok(()),
+            };
+            let block_forest = self.block_forest.clone();
+            let sessions = self.sessions.clone();
+            let mining_metrics = self.mining_metrics.clone();
+            let read_timeout = self.config.read_timeout;
+            let span = info_span!("rpc", client = %addr);
+            tokio::spawn(
+                async move {
+                    if let Err(err) = Self::serve(
+                        stream,
+                        read_timeout,
+                        &block_forest,
+                        &sessions,
+                        &mining_metrics,
+                    )
+                    .await
+                    {
+                        debug!("rpc request failed: {:#}", err);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        read_timeout: Duration,
+        block_forest: &RwLock<BlockForest>,
+        sessions: &SessionRegistry,
+        mining_metrics: &MiningMetrics,
+    ) -> Result<()> {
+        let request = timeout(read_timeout, Self::read_request(&mut stream))
+            .await
+            .context("request has timed out")?;
+        let response = match request {
+            Ok((method, target)) if method == "GET" => {
+                Self::handle(&target, block_forest, sessions, mining_metrics)
+            }
+            Ok(_) => Response::error(405, "only GET requests are supported"),
+            Err(err) => Response::error(400, format!("{:#}", err)),
+        };
+
+        let body = serde_json::to_vec(&response.body)?;
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.status_text(),
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Reads the request head, returns the method and the target.
+    async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Result<(String, String)> {
+        let mut buffer = vec![0u8; MAX_REQUEST_SIZE];
+        let mut len = 0;
+        let head_end = loop {
+            if let Some(pos) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos;
+            }
+            if len == buffer.len() {
+                bail!("request is larger than {} bytes", MAX_REQUEST_SIZE);
+            }
+            let bytes_read = stream.read(&mut buffer[len..]).await?;
+            if bytes_read == 0 {
+                bail!("client has disconnected");
+            }
+            len += bytes_read;
+        };
+
+        let head = std::str::from_utf8(&buffer[..head_end]).context("request is not utf-8")?;
+        let request_line = head.lines().next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+                Ok((method.to_owned(), target.to_owned()))
+            }
+            _ => bail!("malformed request line: {:?}", request_line),
+        }
+    }
+
+    fn handle(
+        target: &str,
+        block_forest: &RwLock<BlockForest>,
+        sessions: &SessionRegistry,
+        mining_metrics: &MiningMetrics,
+    ) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params = match parse_query(query) {
+            Ok(params) => params,
+            Err(err) => return Response::error(400, format!("{:#}", err)),
+        };
+
+        let block_forest = block_forest.read().unwrap_or_else(PoisonError::into_inner);
+        let result = match path {
+            "/head" => Ok(Some(block_to_json(&block_forest, block_forest.head()))),
+            "/block" => Self::handle_block(&block_forest, &params),
+            "/balance" => Self::handle_balance(&block_forest, &params),
+            "/history" => Self::handle_history(&block_forest, &params).map(Some),
+            "/confirmations" => Self::handle_confirmations(&block_forest, &params),
+            "/proof" => Self::handle_proof(&block_forest, &params),
+            "/pending" => Ok(Some(Value::Array(
+                block_forest
+                    .pending_transactions()
+                    .values()
+                    .map(transaction_to_json)
+                    .collect(),
+            ))),
+            "/peers" => {
+                let sessions = sessions.read().unwrap_or_else(PoisonError::into_inner);
+                let mut sessions: Vec<_> = sessions.values().collect();
+                sessions.sort_by_key(|session| session.session_id);
+                serde_json::to_value(sessions).map(Some).map_err(Into::into)
+            }
+            "/mining" => serde_json::to_value(mining_metrics.report())
+                .map(Some)
+                .map_err(Into::into),
+            _ => return Response::error(404, format!("unknown path {}", path)),
+        };
+
+        match result {
+            Ok(Some(body)) => Response::ok(body),
+            Ok(None) => Response::error(404, "not found"),
+            Err(err) => Response::error(400, format!("{:#}", err)),
+        }
+    }
+
+    fn handle_block(
+        block_forest: &BlockForest,
+        params: &HashMap<String, String>,
+    ) -> Result<Option<Value>> {
+        let block = match (params.get("hash"), params.get("index")) {
+            (Some(hash), None) => block_forest.find_block(&parse_hash(hash)?),
+            (None, Some(index)) => {
+                let index = index.parse().context("invalid block index")?;
+                block_forest.find_main_chain_block(index)
+            }
+            _ => bail!("exactly one of 'hash' and 'index' must be set"),
+        };
+        Ok(block.map(|block| block_to_json(block_forest, block)))
+    }
+
+    fn handle_balance(
+        block_forest: &BlockForest,
+        params: &HashMap<String, String>,
+    ) -> Result<Option<Value>> {
+        let wallet_id = parse_wallet_id(params)?;
+        let block = match params.get("block") {
+            Some(hash) => match block_forest.find_block(&parse_hash(hash)?) {
+                Some(block) => block,
+                None => return Ok(None),
+            },
+            None => block_forest.head(),
+        };
+        let Some(account) = block_forest.account_at(&wallet_id, block.hash()) else {
+            return Ok(None);
+        };
+        let mut body = json!({
+            "balance": account.balance,
+            "spendable_balance": account.spendable_balance(block.index + 1, block.timestamp),
+            "last_nonce": account.last_nonce,
+            "block_index": block.index,
+            "block_hash": base64::encode(block.hash()),
+        });
+        if !params.contains_key("block") {
+            body["next_nonce"] = block_forest.next_nonce(&wallet_id).into();
+        }
+        Ok(Some(body))
+    }
+
+    fn handle_history(
+        block_forest: &BlockForest,
+        params: &HashMap<String, String>,
+    ) -> Result<Value> {
+        let wallet_id = parse_wallet_id(params)?;
+        let events = block_forest
+            .wallet_history(&wallet_id)
+            .into_iter()
+            .map(|event| {
+                let (kind, details) = match &event.kind {
+                    WalletEventKind::Sent(tx) => ("sent", transaction_to_json(tx)),
+                    WalletEventKind::Received(tx) => ("received", transaction_to_json(tx)),
+                    WalletEventKind::MiningReward(amount) => {
+                        ("mining_reward", json!({ "amount": amount }))
+                    }
+                };
+                json!({
+                    "kind": kind,
+                    "block_index": event.block_index,
+                    "block_hash": base64::encode(event.block_hash),
+                    "details": details,
+                })
+            })
+            .collect();
+        Ok(Value::Array(events))
+    }
+
+    fn handle_confirmations(
+        block_forest: &BlockForest,
+        params: &HashMap<String, String>,
+    ) -> Result<Option<Value>> {
+        let tx_hash = parse_hash(params.get("tx").context("'tx' is not set")?)?;
+        Ok(block_forest
+            .confirmation_depth(&tx_hash)
+            .map(|depth| json!({ "confirmations": depth })))
+    }
+
+    fn handle_proof(
+        block_forest: &BlockForest,
+        params: &HashMap<String, String>,
+    ) -> Result<Option<Value>> {
+        let tx_hash = parse_hash(params.get("tx").context("'tx' is not set")?)?;
+        let Some(block) = block_forest.find_transaction_block(&tx_hash) else {
+            return Ok(None);
+        };
+        let proof = block
+            .inclusion_proof(&tx_hash)
+            .context("block has no merkle root")?;
+        Ok(Some(json!({
+            "block_hash": base64::encode(block.hash()),
+            "header": &**block as &BlockAttributes,
+            "proof": proof,
+        })))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn block_to_json(block_forest: &BlockForest, block: &VerifiedBlock) -> Value {
+    let mut body = json!({
+        "hash": base64::encode(block.hash()),
+        "block": block.to_block(),
+    });
+    if let Some(work) = block_forest.chain_work(block.hash()) {
+        body["chain_work"] = format!("{:x}", work).into();
+    }
+    body
+}
+
+fn transaction_to_json(tx: &VerifiedTransaction) -> Value {
+    json!({
+        "hash": base64::encode(tx.hash()),
+        "transaction": tx as &Transaction,
+    })
+}
+
+fn parse_wallet_id(params: &HashMap<String, String>) -> Result<WalletId> {
+    let wallet = params.get("wallet").context("'wallet' is not set")?;
+    decode_wallet_id(wallet).context("invalid wallet id")
+}
+
+fn parse_hash(encoded: &str) -> Result<BlockHash> {
+    let bytes = base64::decode(encoded)
+        .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE))
+        .context("invalid base64")?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow!(
+            "invalid hash length: expected {}, got {}",
+            HASH_LEN,
+            bytes.len()
+        )
+    })
+}
+
+fn parse_query(query: &str) -> Result<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(key)?;
+        if params.contains_key(&key) {
+            bail!("duplicate query parameter {:?}", key);
+        }
+        params.insert(key, percent_decode(value)?);
+    }
+    Ok(params)
+}
+
+/// Unlike in HTML forms, `+` is not a space: base64 values contain it.
+fn percent_decode(encoded: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let [Some(high), Some(low)] = [iter.next(), iter.next()] else {
+            bail!("truncated percent escape");
+        };
+        let digits = [high, low].map(|digit| (digit as char).to_digit(16));
+        let [Some(high), Some(low)] = digits else {
+            bail!("invalid percent escape");
+        };
+        bytes.push((high * 16 + low) as u8);
+    }
+    String::from_utf8(bytes).context("query is not utf-8")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        node::peer_service::SessionInfo,
+        test_util::{make_chain, test_key},
+        util::encode_wallet_id,
+    };
+
+    struct Fixture {
+        block_forest: RwLock<BlockForest>,
+        sessions: SessionRegistry,
+        mining_metrics: MiningMetrics,
+    }
+
+    impl Fixture {
+        fn new(chain: &[VerifiedBlock]) -> Self {
+            let mut block_forest = BlockForest::new();
+            for block in chain.iter().skip(1) {
+                block_forest.add_block(block.clone()).unwrap();
+            }
+            Self {
+                block_forest: RwLock::new(block_forest),
+                sessions: SessionRegistry::default(),
+                mining_metrics: MiningMetrics::new(0),
+            }
+        }
+
+        fn get(&self, target: &str) -> (u16, Value) {
+            let response = RpcService::handle(
+                target,
+                &self.block_forest,
+                &self.sessions,
+                &self.mining_metrics,
+            );
+            (response.status, response.body)
+        }
+    }
+
+    async fn read_request(request: &[u8]) -> Result<(String, String)> {
+        RpcService::read_request(&mut &request[..]).await
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        assert_eq!(
+            read_request(b"GET /head HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap(),
+            ("GET".to_string(), "/head".to_string())
+        );
+        for request in [
+            &b"GET /head\r\n\r\n"[..],
+            b"GET /head FTP/1.0\r\n\r\n",
+            b"\r\n\r\n",
+            b"GET /head HTTP/1.1\r\n",
+        ] {
+            assert!(read_request(request).await.is_err());
+        }
+
+        let mut huge = b"GET /head HTTP/1.1\r\nX-Padding: ".to_vec();
+        huge.resize(MAX_REQUEST_SIZE + 1, b'a');
+        huge.extend_from_slice(b"\r\n\r\n");
+        let err = read_request(&huge).await.unwrap_err();
+        assert!(err.to_string().contains("larger than"));
+    }
+
+    #[test]
+    fn test_handle_blocks() {
+        let chain = make_chain(3);
+        let fixture = Fixture::new(&chain);
+        let hash = |block: &VerifiedBlock| base64::encode(block.hash());
+
+        let (status, body) = fixture.get("/head");
+        assert_eq!(status, 200);
+        assert_eq!(body["hash"], hash(&chain[3]));
+        assert_eq!(body["block"]["index"], 3);
+
+        let (status, body) = fixture.get(&format!("/block?hash={}", hash(&chain[2])));
+        assert_eq!(status, 200);
+        assert_eq!(body["hash"], hash(&chain[2]));
+        let (status, body) = fixture.get("/block?index=1");
+        assert_eq!(status, 200);
+        assert_eq!(body["hash"], hash(&chain[1]));
+
+        for target in [
+            "/block?hash=not-base64",
+            "/block?hash=AAAA",
+            "/block?index=-1",
+            "/block?index=1&hash=AAAA",
+        ] {
+            let (status, body) = fixture.get(target);
+            assert_eq!(status, 400, "{}", target);
+            assert!(body["error"].is_string(), "{}", target);
+        }
+
+        let unknown = base64::encode([0u8; HASH_LEN]);
+        for target in [format!("/block?hash={}", unknown), "/block?index=4".into()] {
+            assert_eq!(fixture.get(&target), (404, json!({ "error": "not found" })));
+        }
+        assert_eq!(fixture.get("/tail").0, 404);
+    }
+
+    #[test]
+    fn test_handle_balance_and_pending() {
+        let key = test_key();
+        let wallet: WalletId = key.to_public_key().into();
+        let chain = make_chain(2);
+        let fixture = Fixture::new(&chain);
+        let tx =
+            VerifiedTransaction::sign(&key, WalletId::genesis(), 30, 5, 1, "hi".into()).unwrap();
+        fixture
+            .block_forest
+            .write()
+            .unwrap()
+            .add_transaction(tx.clone())
+            .unwrap();
+
+        let encoded = encode_wallet_id(&wallet).unwrap();
+        let (status, body) = fixture.get(&format!("/balance?wallet={}", encoded));
+        assert_eq!(status, 200);
+        assert_eq!(body["balance"], 200);
+        assert_eq!(body["block_index"], 2);
+        assert_eq!(body["next_nonce"], 2);
+
+        let target = format!(
+            "/balance?wallet={}&block={}",
+            encoded,
+            base64::encode(chain[1].hash())
+        );
+        let (status, body) = fixture.get(&target);
+        assert_eq!(status, 200);
+        assert_eq!(body["balance"], 100);
+        assert!(body.get("next_nonce").is_none());
+
+        assert_eq!(fixture.get("/balance").0, 400);
+        assert_eq!(fixture.get("/balance?wallet=garbage").0, 400);
+
+        let (status, body) = fixture.get("/pending");
+        assert_eq!(status, 200);
+        assert_eq!(body, json!([transaction_to_json(&tx)]));
+    }
+
+    #[test]
+    fn test_handle_peers() {
+        let fixture = Fixture::new(&make_chain(0));
+        assert_eq!(fixture.get("/peers"), (200, json!([])));
+
+        for session_id in [2, 1] {
+            fixture.sessions.write().unwrap().insert(
+                session_id,
+                SessionInfo {
+                    session_id,
+                    address: format!("10.0.0.{}:9090", session_id),
+                    is_outbound: session_id == 1,
+                    hello: None,
+                },
+            );
+        }
+        let (status, body) = fixture.get("/peers");
+        assert_eq!(status, 200);
+        let ids: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|session| session["session_id"].clone())
+            .collect();
+        assert_eq!(ids, vec![json!(1), json!(2)]);
+        assert_eq!(body[0]["address"], "10.0.0.1:9090");
+        assert_eq!(body[0]["is_outbound"], true);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("wallet=a+b%2Fc%3d&&block=&tx").unwrap();
+        assert_eq!(params.len(), 3);
+        assert_eq!(params["wallet"], "a+b/c=");
+        assert_eq!(params["block"], "");
+        assert_eq!(params["tx"], "");
+        assert!(parse_query("").unwrap().is_empty());
+
+        assert!(parse_query("tx=1&tx=2").is_err());
+        assert!(parse_query("tx=1&t%78=2").is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("%41%62c").unwrap(), "Abc");
+        assert_eq!(percent_decode("a+b").unwrap(), "a+b");
+        for encoded in ["%zz", "%4", "%", "%+1", "%ff"] {
+            assert!(percent_decode(encoded).is_err(), "{}", encoded);
+        }
+    }
+}
@@ -0,0 +1,550 @@
+use anyhow::{anyhow, Context, Error, Result};
+use futures::{
+    future::{pending, select_all, BoxFuture},
+    FutureExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::{sleep, timeout_at, Instant},
+};
+use tracing::*;
+
+use std::{any::Any, future::Future, panic::AssertUnwindSafe, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    /// A failed service is restarted after a delay which starts at `initial_backoff` and doubles
+    /// with every consecutive failure up to `max_backoff`.
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+
+    /// The node shuts down once a service fails more times in a row. Zero disables restarts.
+    pub max_restarts: u32,
+
+    /// Failures of a service which has been running this long are no longer counted as
+    /// consecutive.
+    #[serde(with = "humantime_serde")]
+    pub restart_window: Duration,
+
+    /// Services still running this long after their shutdown stage has begun are aborted.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 5,
+            restart_window: Duration::from_secs(600),
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl SupervisorConfig {
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff)
+    }
+}
+
+/// Services are stopped stage by stage, so that the services of later stages can drain the
+/// channels the earlier ones were sending to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownStage {
+    /// Services talking to peers and clients, which stop accepting input first.
+    Intake,
+    Mining,
+    Gossip,
+}
+
+const SHUTDOWN_STAGES: [ShutdownStage; 3] = [
+    ShutdownStage::Intake,
+    ShutdownStage::Mining,
+    ShutdownStage::Gossip,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Stopped on a signal, every service has finished in time.
+    Stopped,
+    /// A service has terminated or failed more times than allowed.
+    Failed,
+    /// Stopped on a signal, but some services had to be aborted.
+    Aborted,
+}
+
+impl ExitStatus {
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Stopped => 0,
+            Self::Failed => 1,
+            Self::Aborted => 2,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Tells a service to finish its work and return from `run`.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    fn new() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self(receiver))
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the shutdown is triggered or the supervisor is gone.
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+struct StageTrigger {
+    stage: ShutdownStage,
+    sender: watch::Sender<bool>,
+    shutdown: Shutdown,
+}
+
+struct SupervisedService {
+    name: &'static str,
+    stage: ShutdownStage,
+    handle: JoinHandle<Result<()>>,
+    finished: bool,
+}
+
+/// Runs every service on its own runtime, restarts the failed ones and stops them all on SIGINT,
+/// SIGTERM or a fatal failure.
+pub struct Supervisor {
+    config: SupervisorConfig,
+    stopping: (watch::Sender<bool>, Shutdown),
+    stages: Vec<StageTrigger>,
+    services: Vec<SupervisedService>,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig) -> Self {
+        let stages = SHUTDOWN_STAGES
+            .iter()
+            .map(|stage| {
+                let (sender, shutdown) = Shutdown::new();
+                StageTrigger {
+                    stage: *stage,
+                    sender,
+                    shutdown,
+                }
+            })
+            .collect();
+        Self {
+            config,
+            stopping: Shutdown::new(),
+            stages,
+            services: vec![],
+        }
+    }
+
+    /// Returns the signal the services of the stage must stop on.
+    pub fn shutdown(&self, stage: ShutdownStage) -> Shutdown {
+        self.stage(stage).shutdown.clone()
+    }
+
+    fn stage(&self, stage: ShutdownStage) -> &StageTrigger {
+        self.stages.iter().find(|s| s.stage == stage).unwrap()
+    }
+
+    /// Starts the service on a runtime with `thread_count` threads. `run` is called again on the
+    /// same service after a failure, so the service keeps its channels across restarts.
+    pub fn spawn<S>(
+        &mut self,
+        name: &'static str,
+        stage: ShutdownStage,
+        thread_count: usize,
+        mut service: S,
+        run: for<'a> fn(&'a mut S) -> BoxFuture<'a, Result<()>>,
+    ) where
+        S: Send + 'static,
+    {
+        let config = self.config.clone();
+        let stopping = self.stopping.1.clone();
+        let span = info_span!("service", name);
+        let handle = start_runtime(
+            thread_count,
+            async move { supervise(name, &config, &stopping, &mut service, run).await }
+                .instrument(span),
+        );
+        self.services.push(SupervisedService {
+            name,
+            stage,
+            handle,
+            finished: false,
+        });
+    }
+
+    /// Waits for a shutdown signal or a fatal service failure, then stops the services.
+    pub async fn run(mut self) -> ExitStatus {
+        let mut status = ExitStatus::Stopped;
+        if self.services.is_empty() {
+            return status;
+        }
+
+        let first_finished = select! {
+            signal = wait_for_signal() => {
+                info!("received {}, shutting down", signal);
+                None
+            }
+            (result, index, _) = select_all(self.services.iter_mut().map(|s| &mut s.handle)) => {
+                Some((index, result))
+            }
+        };
+        if let Some((index, result)) = first_finished {
+            let service = &mut self.services[index];
+            service.finished = true;
+            match flatten_join_result(result) {
+                Ok(()) => error!("{} service has terminated, shutting down", service.name),
+                Err(err) => error!(
+                    "{} service has failed, shutting down: {:#}",
+                    service.name, err
+                ),
+            }
+            status = ExitStatus::Failed;
+        }
+
+        let _ = self.stopping.0.send(true);
+        let mut aborted = false;
+        let mut interrupted = false;
+        for stage in SHUTDOWN_STAGES {
+            let _ = self.stage(stage).sender.send(true);
+            let deadline = Instant::now() + self.config.shutdown_timeout;
+            for service in self.services.iter_mut() {
+                if service.stage != stage || service.finished {
+                    continue;
+                }
+                service.finished = true;
+
+                // Another signal aborts all the services which are still running.
+                let result = if interrupted {
+                    None
+                } else {
+                    select! {
+                        result = timeout_at(deadline, &mut service.handle) => {
+                            if result.is_err() {
+                                warn!("{} service did not stop in time", service.name);
+                            }
+                            result.ok()
+                        }
+                        signal = wait_for_signal() => {
+                            warn!("received {} again, aborting", signal);
+                            interrupted = true;
+                            None
+                        }
+                    }
+                };
+                match result {
+                    Some(Ok(Ok(()))) => debug!("{} service has stopped", service.name),
+                    Some(result) => warn!(
+                        "{} service has failed on shutdown: {:#}",
+                        service.name,
+                        flatten_join_result(result).unwrap_err()
+                    ),
+                    None => {
+                        service.handle.abort();
+                        aborted = true;
+                    }
+                }
+            }
+        }
+
+        if aborted && status == ExitStatus::Stopped {
+            status = ExitStatus::Aborted;
+        }
+        status
+    }
+}
+
+async fn supervise<S>(
+    name: &str,
+    config: &SupervisorConfig,
+    stopping: &Shutdown,
+    service: &mut S,
+    run: for<'a> fn(&'a mut S) -> BoxFuture<'a, Result<()>>,
+) -> Result<()> {
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let result = AssertUnwindSafe(run(service))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|panic| Err(panic_error(panic)));
+        let err = match result {
+            Ok(()) if stopping.is_triggered() => return Ok(()),
+            Ok(()) => return Err(anyhow!("service has returned unexpectedly")),
+            Err(err) => err,
+        };
+        if stopping.is_triggered() {
+            return Err(err);
+        }
+
+        if started.elapsed() >= config.restart_window {
+            failures = 0;
+        }
+        failures += 1;
+        if failures > config.max_restarts {
+            return Err(err.context(format!("gave up after {} restarts", config.max_restarts)));
+        }
+
+        let backoff = config.backoff(failures);
+        warn!(
+            "{} service has failed, restarting in {:?}: {:#}",
+            name, backoff, err
+        );
+        let mut stopping = stopping.clone();
+        select! {
+            _ = sleep(backoff) => {}
+            _ = stopping.wait() => return Err(err),
+        }
+    }
+}
+
+fn panic_error(panic: Box<dyn Any + Send>) -> Error {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    anyhow!("service has panicked: {}", message)
+}
+
+fn flatten_join_result(result: Result<Result<()>, tokio::task::JoinError>) -> Result<()> {
+    result.context("service task has been cancelled")?
+}
+
+/// Never resolves if the signal handlers cannot be installed.
+async fn wait_for_signal() -> &'static str {
+    match listen_for_signals().await {
+        Ok(signal) => signal,
+        Err(err) => {
+            error!("failed to listen for signals: {:#}", err);
+            pending().await
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn listen_for_signals() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn listen_for_signals() -> Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
+
+fn start_runtime<F>(thread_count: usize, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .worker_threads(thread_count)
+        .build()
+        .expect("failed to build a Tokio runtime");
+    let (sender, receiver) = oneshot::channel();
+    let handle = runtime.spawn(async move {
+        let res = future.await;
+        let _ = sender.send(());
+        res
+    });
+    std::thread::spawn(move || runtime.block_on(receiver));
+    handle
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::bail;
+
+    use std::sync::{Arc, Mutex};
+
+    fn test_config() -> SupervisorConfig {
+        SupervisorConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            max_restarts: 3,
+            restart_window: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_millis(200),
+        }
+    }
+
+    struct FlakyService {
+        runs: u32,
+        failures: u32,
+        stopping: Option<watch::Sender<bool>>,
+    }
+
+    impl FlakyService {
+        async fn run(&mut self) -> Result<()> {
+            self.runs += 1;
+            if self.runs == 1 {
+                panic!("first run");
+            }
+            if self.runs <= self.failures {
+                bail!("run {}", self.runs);
+            }
+            if let Some(stopping) = &self.stopping {
+                stopping.send(true).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    struct StoppingService {
+        name: &'static str,
+        shutdown: Shutdown,
+        stopped: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl StoppingService {
+        async fn run(&mut self) -> Result<()> {
+            self.shutdown.wait().await;
+            self.stopped.lock().unwrap().push(self.name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = SupervisorConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let backoffs: Vec<_> = [1, 2, 3, 4, 5, 100]
+            .into_iter()
+            .map(|failures| config.backoff(failures).as_secs())
+            .collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 10, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_restarts() {
+        let config = test_config();
+        let run: fn(&mut FlakyService) -> BoxFuture<'_, Result<()>> =
+            |service| service.run().boxed();
+
+        let (sender, stopping) = Shutdown::new();
+        let mut service = FlakyService {
+            runs: 0,
+            failures: 3,
+            stopping: Some(sender),
+        };
+        supervise("flaky", &config, &stopping, &mut service, run)
+            .await
+            .unwrap();
+        assert_eq!(service.runs, 4);
+
+        let (_sender, stopping) = Shutdown::new();
+        let mut service = FlakyService {
+            runs: 0,
+            failures: 10,
+            stopping: None,
+        };
+        let err = supervise("flaky", &config, &stopping, &mut service, run)
+            .await
+            .unwrap_err();
+        assert_eq!(service.runs, 4);
+        assert_eq!(format!("{:#}", err), "gave up after 3 restarts: run 4");
+
+        // A service must not return before the shutdown.
+        let (_sender, stopping) = Shutdown::new();
+        let mut service = FlakyService {
+            runs: 1,
+            failures: 0,
+            stopping: None,
+        };
+        assert!(supervise("flaky", &config, &stopping, &mut service, run)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stages() {
+        let mut supervisor = Supervisor::new(SupervisorConfig {
+            max_restarts: 0,
+            ..test_config()
+        });
+        let stopped = Arc::new(Mutex::new(vec![]));
+        let run: fn(&mut StoppingService) -> BoxFuture<'_, Result<()>> =
+            |service| service.run().boxed();
+        for (name, stage) in [
+            ("gossip", ShutdownStage::Gossip),
+            ("peer", ShutdownStage::Intake),
+            ("mining", ShutdownStage::Mining),
+        ] {
+            let service = StoppingService {
+                name,
+                shutdown: supervisor.shutdown(stage),
+                stopped: stopped.clone(),
+            };
+            supervisor.spawn(name, stage, 1, service, run);
+        }
+
+        // A stuck service is aborted and does not hold up the rest.
+        let stuck = StoppingService {
+            name: "stuck",
+            shutdown: Shutdown::new().1,
+            stopped: stopped.clone(),
+        };
+        supervisor.spawn("stuck", ShutdownStage::Intake, 1, stuck, |_| {
+            pending().boxed()
+        });
+
+        let failing = FlakyService {
+            runs: 1,
+            failures: 2,
+            stopping: None,
+        };
+        supervisor.spawn("failing", ShutdownStage::Intake, 1, failing, |service| {
+            service.run().boxed()
+        });
+
+        assert_eq!(supervisor.run().await, ExitStatus::Failed);
+        assert_eq!(*stopped.lock().unwrap(), vec!["peer", "mining", "gossip"]);
+    }
+}
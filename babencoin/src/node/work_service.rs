@@ -0,0 +1,350 @@
+use crate::{
+    consensus::BlockRules,
+    data::{Block, BlockAttributes, VerifiedBlock, WalletId},
+    logging::block_span,
+    node::{mining_service::MiningInfo, supervisor::Shutdown},
+    util::{deserialize_wallet_id, serialize_wallet_id},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc::{Receiver, Sender},
+};
+use tracing::*;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Older work of the current mining info is forgotten, submissions for it are rejected.
+const MAX_WORK_ITEMS: usize = 64;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize)]
+pub struct WorkServiceConfig {
+    pub listen_address: String,
+
+    /// Receives the rewards of the blocks mined by external miners.
+    #[serde(
+        serialize_with = "serialize_wallet_id",
+        deserialize_with = "deserialize_wallet_id"
+    )]
+    pub public_key: WalletId,
+}
+
+/// A request of an external miner, one json document per line.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum WorkRequest {
+    GetWork,
+    /// The nonce makes the header of the work hash below its `max_hash`.
+    Submit {
+        work_id: u64,
+        nonce: u64,
+    },
+}
+
+/// A response to a `WorkRequest`, one json document per line.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum WorkResponse {
+    /// The header commits to the transactions through the Merkle root, so the miner only needs
+    /// to vary the nonce and check `BlockAttributes::compute_hash`.
+    Work {
+        work_id: u64,
+        header: Box<BlockAttributes>,
+    },
+    /// The node has nothing to mine on yet.
+    NoWork,
+    Accepted,
+    Rejected {
+        reason: String,
+    },
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Serves mining work to external miners over a line-based json protocol, see `WorkRequest`.
+///
+/// The service sits between the gossip and the mining services: it passes every `MiningInfo` on
+/// to the mining threads, so that local and external miners work on the same blocks. Solved
+/// blocks are sent to the gossip service through the same channel the mining threads use.
+pub struct WorkService {
+    config: WorkServiceConfig,
+    info_receiver: Receiver<MiningInfo>,
+    info_sender: Sender<MiningInfo>,
+    block_sender: Sender<VerifiedBlock>,
+    shutdown: Shutdown,
+    state: Arc<Mutex<WorkState>>,
+}
+
+impl WorkService {
+    pub fn new(
+        config: WorkServiceConfig,
+        info_receiver: Receiver<MiningInfo>,
+        info_sender: Sender<MiningInfo>,
+        block_sender: Sender<VerifiedBlock>,
+        rules: BlockRules,
+        shutdown: Shutdown,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(WorkState::new(config.public_key.clone(), rules)));
+        Self {
+            config,
+            info_receiver,
+            info_sender,
+            block_sender,
+            shutdown,
+            state,
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let listener = TcpListener::bind(&self.config.listen_address)
+            .await
+            .context(format!("failed to bind {}", self.config.listen_address))?;
+        info!(
+            "work service is listening on {}",
+            self.config.listen_address
+        );
+
+        loop {
+            select! {
+                info = self.info_receiver.recv() => {
+                    let info = info.context("mining info channel is closed")?;
+                    self.state.lock().unwrap().set_info(info.clone());
+                    self.info_sender
+                        .send(info)
+                        .await
+                        .context("mining service has terminated")?;
+                }
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted.context("failed to accept")?;
+                    let state = self.state.clone();
+                    let block_sender = self.block_sender.clone();
+                    let span = info_span!("miner", address = %addr);
+                    tokio::spawn(
+                        async move {
+                            if let Err(err) = Self::serve(stream, &state, &block_sender).await {
+                                debug!("miner session failed: {:#}", err);
+                            }
+                        }
+                        .instrument(span),
+                    );
+                }
+                _ = self.shutdown.wait() => return Ok(()),
+            }
+        }
+    }
+
+    async fn serve(
+        stream: TcpStream,
+        state: &Mutex<WorkState>,
+        block_sender: &Sender<VerifiedBlock>,
+    ) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str(&line) {
+                Ok(WorkRequest::GetWork) => state.lock().unwrap().get_work(),
+                Ok(WorkRequest::Submit { work_id, nonce }) => {
+                    let result = state.lock().unwrap().submit(work_id, nonce);
+                    match result {
+                        Ok(block) => {
+                            block_span(block.hash())
+                                .in_scope(|| info!("external miner has found a block"));
+                            block_sender
+                                .send(block)
+                                .await
+                                .context("gossip service has terminated")?;
+                            WorkResponse::Accepted
+                        }
+                        Err(err) => WorkResponse::Rejected {
+                            reason: format!("{:#}", err),
+                        },
+                    }
+                }
+                Err(err) => WorkResponse::Rejected {
+                    reason: format!("invalid request: {}", err),
+                },
+            };
+
+            let mut data = serde_json::to_vec(&response)?;
+            data.push(b'\n');
+            writer.write_all(&data).await?;
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Block templates handed out for the current mining info.
+struct WorkState {
+    issuer: WalletId,
+    /// Solved blocks are checked against them before they are sent on.
+    rules: BlockRules,
+    info: Option<MiningInfo>,
+    works: HashMap<u64, Block>,
+    work_ids: VecDeque<u64>,
+    next_work_id: u64,
+}
+
+impl WorkState {
+    fn new(issuer: WalletId, rules: BlockRules) -> Self {
+        Self {
+            issuer,
+            rules,
+            info: None,
+            works: HashMap::new(),
+            work_ids: VecDeque::new(),
+            next_work_id: 0,
+        }
+    }
+
+    /// Makes all the work handed out so far stale.
+    fn set_info(&mut self, info: MiningInfo) {
+        self.info = Some(info);
+        self.works.clear();
+        self.work_ids.clear();
+    }
+
+    fn get_work(&mut self) -> WorkResponse {
+        let Some(info) = self.info.as_ref() else {
+            return WorkResponse::NoWork;
+        };
+
+        let mut block = Block::genesis();
+        block.index = info.block_index;
+        block.reward = self.rules.max_reward();
+        block.timestamp = Utc::now();
+        block.issuer = self.issuer.clone();
+        block.max_hash = info.max_hash;
+        block.prev_hash = info.prev_hash;
+        block.transactions = info.transactions.iter().cloned().map(Into::into).collect();
+        block.update_merkle_root();
+
+        let work_id = self.next_work_id;
+        self.next_work_id += 1;
+        let header = Box::new(block.attrs.clone());
+        self.works.insert(work_id, block);
+        self.work_ids.push_back(work_id);
+        if self.work_ids.len() > MAX_WORK_ITEMS {
+            let stale_id = self.work_ids.pop_front().unwrap();
+            self.works.remove(&stale_id);
+        }
+
+        WorkResponse::Work { work_id, header }
+    }
+
+    fn submit(&mut self, work_id: u64, nonce: u64) -> Result<VerifiedBlock> {
+        let Some(block) = self.works.get(&work_id) else {
+            bail!("work {} is unknown or stale", work_id);
+        };
+        let mut block = block.clone();
+        block.nonce = nonce;
+        let block = block
+            .verified_with(&self.rules)
+            .context("block verification failed")?;
+        self.works.remove(&work_id);
+        Ok(block)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{BlockHash, VerifiedTransaction, HASH_LEN},
+        test_util::test_key,
+    };
+
+    fn make_info(max_hash: BlockHash) -> MiningInfo {
+        let key = test_key();
+        let tx = VerifiedTransaction::sign(&key, WalletId::genesis(), 1, 1, 1, "".into()).unwrap();
+        MiningInfo {
+            block_index: 1,
+            prev_hash: *VerifiedBlock::genesis().hash(),
+            max_hash,
+            transactions: vec![tx],
+        }
+    }
+
+    fn solve(header: &BlockAttributes) -> u64 {
+        let mut header = header.clone();
+        loop {
+            if header.compute_hash().unwrap() <= header.max_hash {
+                return header.nonce;
+            }
+            header.nonce += 1;
+        }
+    }
+
+    #[test]
+    fn test_work() {
+        let mut state = WorkState::new(WalletId::genesis(), BlockRules::main().clone());
+        assert!(matches!(state.get_work(), WorkResponse::NoWork));
+
+        let mut max_hash = [255u8; HASH_LEN];
+        max_hash[0] = 15;
+        state.set_info(make_info(max_hash));
+        let WorkResponse::Work { work_id, header } = state.get_work() else {
+            panic!("no work");
+        };
+        assert_eq!(header.max_hash, max_hash);
+        assert_eq!(header.issuer, WalletId::genesis());
+
+        let nonce = solve(&header);
+        let wrong_nonce = (nonce + 1..)
+            .find(|nonce| {
+                let mut header = header.clone();
+                header.nonce = *nonce;
+                header.compute_hash().unwrap() > max_hash
+            })
+            .unwrap();
+        assert!(state.submit(work_id, wrong_nonce).is_err());
+
+        let block = state.submit(work_id, nonce).unwrap();
+        assert_eq!(block.nonce, nonce);
+        assert_eq!(block.transactions().len(), 1);
+        assert!(state.submit(work_id, nonce).is_err());
+
+        // New mining info makes the old work stale.
+        let WorkResponse::Work { work_id, header } = state.get_work() else {
+            panic!("no work");
+        };
+        state.set_info(make_info(max_hash));
+        assert!(state.submit(work_id, solve(&header)).is_err());
+    }
+
+    #[test]
+    fn test_protocol_json() {
+        let request: WorkRequest =
+            serde_json::from_str(r#"{"method": "submit", "work_id": 3, "nonce": 42}"#).unwrap();
+        assert!(matches!(
+            request,
+            WorkRequest::Submit {
+                work_id: 3,
+                nonce: 42
+            }
+        ));
+        let response = serde_json::to_value(WorkResponse::Rejected {
+            reason: "stale".into(),
+        })
+        .unwrap();
+        assert_eq!(
+            response,
+            serde_json::json!({ "result": "rejected", "reason": "stale" })
+        );
+    }
+}
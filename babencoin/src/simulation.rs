@@ -0,0 +1,399 @@
+use crate::{
+    block_forest::{BlockForest, BlockForestConfig},
+    data::{Block, BlockHash, VerifiedBlock, VerifiedPeerMessage, VerifiedTransaction, WalletId},
+};
+
+use anyhow::{Context, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use tracing::*;
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_BLOCK_TRANSACTIONS: usize = 100;
+
+pub type NodeId = usize;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    pub node_count: usize,
+    pub seed: u64,
+
+    /// Every message is delivered after `latency` plus a uniformly random part of `jitter`.
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Probability of a message being lost, from 0 to 1.
+    pub drop_rate: f64,
+
+    /// How often nodes request unknown blocks from all reachable peers. Zero disables it.
+    pub eager_requests_interval: Duration,
+
+    pub block_forest: BlockForestConfig,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            node_count: 3,
+            seed: 0,
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(50),
+            drop_rate: 0.,
+            eager_requests_interval: Duration::from_secs(1),
+            block_forest: BlockForestConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimulationStats {
+    pub sent: u64,
+    pub delivered: u64,
+    /// Messages lost randomly or cut off by a partition.
+    pub dropped: u64,
+}
+
+struct Envelope {
+    from: NodeId,
+    to: NodeId,
+    message: VerifiedPeerMessage,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Deterministic in-process network of nodes for tests.
+///
+/// Every node owns a block forest and speaks the basic gossip protocol: blocks and transactions
+/// are flooded to all reachable peers, and unknown parents are requested from the peer that sent
+/// the orphan. Messages travel over an in-memory transport on a virtual clock, so latency, drops
+/// and partitions are scripted, and runs with the same seed are reproducible.
+pub struct Simulation {
+    config: SimulationConfig,
+    rng: ChaCha20Rng,
+    now: Duration,
+    nodes: Vec<BlockForest>,
+    /// Nodes can talk to each other only if they are in the same partition group.
+    groups: Vec<usize>,
+    /// Messages in flight, ordered by delivery time and then by send order.
+    queue: BTreeMap<(Duration, u64), Envelope>,
+    next_seq: u64,
+    next_tick: Option<Duration>,
+    stats: SimulationStats,
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig) -> Self {
+        let nodes = (0..config.node_count)
+            .map(|_| BlockForest::with_config(config.block_forest.clone()))
+            .collect();
+        let next_tick =
+            (!config.eager_requests_interval.is_zero()).then_some(config.eager_requests_interval);
+        Self {
+            rng: ChaCha20Rng::seed_from_u64(config.seed),
+            now: Duration::ZERO,
+            nodes,
+            groups: vec![0; config.node_count],
+            queue: BTreeMap::new(),
+            next_seq: 0,
+            next_tick,
+            stats: SimulationStats::default(),
+            config,
+        }
+    }
+
+    /// Virtual time since the start of the simulation.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn block_forest(&self, node: NodeId) -> &BlockForest {
+        &self.nodes[node]
+    }
+
+    pub fn head(&self, node: NodeId) -> &Arc<VerifiedBlock> {
+        self.nodes[node].head()
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        self.stats
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns true if all nodes have the same head.
+    pub fn is_converged(&self) -> bool {
+        let head_hash = self.head(0).hash();
+        self.nodes
+            .iter()
+            .all(|node| node.head().hash() == head_hash)
+    }
+
+    pub fn set_latency(&mut self, latency: Duration, jitter: Duration) {
+        self.config.latency = latency;
+        self.config.jitter = jitter;
+    }
+
+    pub fn set_drop_rate(&mut self, drop_rate: f64) {
+        self.config.drop_rate = drop_rate;
+    }
+
+    /// Splits the network into isolated groups, nodes not listed are isolated individually.
+    /// Messages in flight between different groups are lost.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        let mut new_groups: Vec<_> = (0..self.nodes.len())
+            .map(|node| groups.len() + node)
+            .collect();
+        for (group_id, group) in groups.iter().enumerate() {
+            for node in group.iter() {
+                new_groups[*node] = group_id;
+            }
+        }
+        self.set_groups(new_groups);
+    }
+
+    /// Reconnects all nodes.
+    pub fn heal(&mut self) {
+        self.set_groups(vec![0; self.nodes.len()]);
+    }
+
+    pub fn is_connected(&self, lhs: NodeId, rhs: NodeId) -> bool {
+        lhs != rhs && self.groups[lhs] == self.groups[rhs]
+    }
+
+    /// Builds a block with pending transactions on top of the node head, mines it and gossips it
+    /// as if the node has mined it. The timestamp follows the virtual clock.
+    pub fn mine_block(&mut self, node: NodeId, issuer: &WalletId) -> Result<VerifiedBlock> {
+        let forest = &self.nodes[node];
+        let head = forest.head();
+
+        let mut block = Block::genesis();
+        block.index = head.index + 1;
+        block.prev_hash = *head.hash();
+        block.issuer = issuer.clone();
+        block.reward = forest.consensus().max_reward;
+        block.max_hash = forest.next_max_hash();
+        block.timestamp = (forest.genesis().timestamp
+            + chrono::Duration::seconds(self.now.as_secs() as i64))
+        .max(head.timestamp + chrono::Duration::seconds(1));
+        block.transactions = forest
+            .select_transactions(MAX_BLOCK_TRANSACTIONS)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        block.update_merkle_root();
+
+        // Random starting nonces make blocks mined by different nodes at the same height differ.
+        block.nonce = self.rng.gen();
+        while block.compute_hash() > block.max_hash {
+            block.nonce = block.nonce.wrapping_add(1);
+        }
+
+        let block = block
+            .verified_with(forest.block_rules())
+            .context("mined block is invalid")?;
+        self.submit_block(node, block.clone())?;
+        Ok(block)
+    }
+
+    /// Adds the block to the node and gossips it to the peers.
+    pub fn submit_block(&mut self, node: NodeId, block: VerifiedBlock) -> Result<()> {
+        self.nodes[node]
+            .add_block(block.clone())
+            .context(format!("failed to add block to node {}", node))?;
+        self.broadcast(node, None, VerifiedPeerMessage::Block(Box::new(block)));
+        Ok(())
+    }
+
+    /// Adds the transaction to the node mempool and gossips it to the peers.
+    pub fn submit_transaction(&mut self, node: NodeId, tx: VerifiedTransaction) -> Result<()> {
+        self.nodes[node]
+            .add_transaction(tx.clone())
+            .context(format!("failed to add transaction to node {}", node))?;
+        self.broadcast(node, None, VerifiedPeerMessage::Transaction(Box::new(tx)));
+        Ok(())
+    }
+
+    /// Processes the next message delivery or timer. Returns false if there is nothing to do.
+    pub fn step(&mut self) -> bool {
+        let next_delivery = self.queue.keys().next().map(|(at, _)| *at);
+        match (next_delivery, self.next_tick) {
+            (Some(at), Some(tick)) if tick < at => self.tick(tick),
+            (Some(_), _) => self.deliver_next(),
+            (None, Some(tick)) => self.tick(tick),
+            (None, None) => return false,
+        }
+        true
+    }
+
+    /// Runs the simulation until the virtual clock advances by `duration`.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = self.now + duration;
+        while let Some(at) = self.next_event_time() {
+            if at > deadline {
+                break;
+            }
+            self.step();
+        }
+        self.now = deadline;
+    }
+
+    /// Runs the simulation until no messages are in flight.
+    pub fn run_until_idle(&mut self) {
+        while !self.queue.is_empty() {
+            self.step();
+        }
+    }
+
+    /// Runs the simulation until the predicate holds or `timeout` of virtual time passes.
+    pub fn run_until(&mut self, timeout: Duration, mut pred: impl FnMut(&Self) -> bool) -> bool {
+        let deadline = self.now + timeout;
+        while !pred(self) {
+            match self.next_event_time() {
+                Some(at) if at <= deadline => {
+                    self.step();
+                }
+                _ => {
+                    self.now = self.now.max(deadline);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn next_event_time(&self) -> Option<Duration> {
+        let next_delivery = self.queue.keys().next().map(|(at, _)| *at);
+        match (next_delivery, self.next_tick) {
+            (Some(at), Some(tick)) => Some(at.min(tick)),
+            (at, tick) => at.or(tick),
+        }
+    }
+
+    fn set_groups(&mut self, groups: Vec<usize>) {
+        let old_groups = std::mem::replace(&mut self.groups, groups);
+
+        // Newly connected nodes greet each other with their heads, as on a new session.
+        for from in 0..self.nodes.len() {
+            for to in 0..self.nodes.len() {
+                if self.is_connected(from, to) && old_groups[from] != old_groups[to] {
+                    let head = (**self.head(from)).clone();
+                    self.send(from, to, VerifiedPeerMessage::Block(Box::new(head)));
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, at: Duration) {
+        self.now = at;
+        self.next_tick = Some(at + self.config.eager_requests_interval);
+
+        for node in 0..self.nodes.len() {
+            let mut unknown: Vec<BlockHash> = self.nodes[node]
+                .unknown_block_hashes()
+                .iter()
+                .copied()
+                .collect();
+            unknown.sort_unstable();
+            for block_hash in unknown {
+                self.broadcast(node, None, VerifiedPeerMessage::Request { block_hash });
+            }
+        }
+    }
+
+    fn deliver_next(&mut self) {
+        let Some(((at, _), envelope)) = self.queue.pop_first() else {
+            return;
+        };
+        self.now = at;
+
+        let Envelope { from, to, message } = envelope;
+        if !self.is_connected(from, to) {
+            trace!("message from {} to {} is cut off by a partition", from, to);
+            self.stats.dropped += 1;
+            return;
+        }
+        self.stats.delivered += 1;
+        self.handle_message(to, from, message);
+    }
+
+    fn handle_message(&mut self, node: NodeId, from: NodeId, message: VerifiedPeerMessage) {
+        let forest = &mut self.nodes[node];
+        match message {
+            VerifiedPeerMessage::Block(block) => {
+                if forest.find_block(block.hash()).is_some() {
+                    return;
+                }
+                let prev_hash = block.prev_hash;
+                if let Err(err) = forest.add_block((*block).clone()) {
+                    debug!("node {} rejected block from {}: {:#}", node, from, err);
+                    return;
+                }
+                if forest.unknown_block_hashes().contains(&prev_hash) {
+                    self.send(
+                        node,
+                        from,
+                        VerifiedPeerMessage::Request {
+                            block_hash: prev_hash,
+                        },
+                    );
+                }
+                self.broadcast(node, Some(from), VerifiedPeerMessage::Block(block));
+            }
+            VerifiedPeerMessage::Transaction(tx) => {
+                if forest.mempool().contains(tx.hash()) {
+                    return;
+                }
+                if let Err(err) = forest.add_transaction((*tx).clone()) {
+                    debug!(
+                        "node {} rejected transaction from {}: {:#}",
+                        node, from, err
+                    );
+                    return;
+                }
+                self.broadcast(node, Some(from), VerifiedPeerMessage::Transaction(tx));
+            }
+            VerifiedPeerMessage::Request { block_hash } => {
+                if let Some(block) = forest.find_block(&block_hash) {
+                    let block = (**block).clone();
+                    self.send(node, from, VerifiedPeerMessage::Block(Box::new(block)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn broadcast(&mut self, from: NodeId, except: Option<NodeId>, message: VerifiedPeerMessage) {
+        for to in 0..self.nodes.len() {
+            if Some(to) != except && self.is_connected(from, to) {
+                self.send(from, to, message.clone());
+            }
+        }
+    }
+
+    fn send(&mut self, from: NodeId, to: NodeId, message: VerifiedPeerMessage) {
+        self.stats.sent += 1;
+        if !self.is_connected(from, to) || self.rng.gen_bool(self.config.drop_rate) {
+            trace!("message from {} to {} is lost", from, to);
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let jitter = self.config.jitter.as_nanos() as u64;
+        let delay = self.config.latency + Duration::from_nanos(self.rng.gen_range(0..=jitter));
+        self.queue.insert(
+            (self.now + delay, self.next_seq),
+            Envelope { from, to, message },
+        );
+        self.next_seq += 1;
+    }
+}
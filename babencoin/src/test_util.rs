@@ -0,0 +1,46 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{
+    data::{Block, VerifiedBlock, VerifiedTransaction},
+    util::parse_pkcs8_private,
+};
+
+use chrono::Duration;
+use rsa::RSAPrivateKey;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn test_key() -> RSAPrivateKey {
+    parse_pkcs8_private(include_str!("../data/test.pem")).unwrap()
+}
+
+/// Returns a child of `parent` issued by the test key 10 seconds later, with a reward of 100.
+/// It is not verified, so that tests may alter it first.
+pub fn make_unverified_child(
+    parent: &VerifiedBlock,
+    transactions: Vec<VerifiedTransaction>,
+) -> Block {
+    let mut block = Block::genesis();
+    block.index = parent.index + 1;
+    block.prev_hash = *parent.hash();
+    block.timestamp = parent.timestamp + Duration::seconds(10);
+    block.reward = 100;
+    block.issuer = test_key().to_public_key().into();
+    block.transactions = transactions.into_iter().map(Into::into).collect();
+    block
+}
+
+pub fn make_child(parent: &VerifiedBlock, transactions: Vec<VerifiedTransaction>) -> VerifiedBlock {
+    make_unverified_child(parent, transactions)
+        .verified()
+        .unwrap()
+}
+
+/// Returns the genesis block followed by `len` empty blocks.
+pub fn make_chain(len: usize) -> Vec<VerifiedBlock> {
+    let mut chain = vec![VerifiedBlock::genesis()];
+    for _ in 0..len {
+        chain.push(make_child(chain.last().unwrap(), vec![]));
+    }
+    chain
+}
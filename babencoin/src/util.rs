@@ -2,7 +2,7 @@ use crate::data::WalletId;
 
 use anyhow::{Context, Result};
 use chrono::{DateTime, LocalResult, TimeZone, Utc};
-use rsa::{PublicKeyEncoding, RSAPrivateKey, RSAPublicKey};
+use rsa::{PrivateKeyEncoding, PublicKeyEncoding, RSAPrivateKey, RSAPublicKey};
 use serde::{
     de::{self, Deserializer},
     ser::{self, Serializer},
@@ -32,6 +32,27 @@ pub fn parse_pkcs8_private(raw: &str) -> Result<RSAPrivateKey> {
     RSAPrivateKey::from_pkcs8(&der_bytes).context("failed to decode pkcs8 bytes")
 }
 
+fn encode_pkcs8_plaintext(label: &str, der_bytes: &[u8]) -> String {
+    let mut raw = format!("-----BEGIN {}-----\n", label);
+    let encoded = base64::encode(der_bytes);
+    for line in encoded.as_bytes().chunks(64) {
+        raw.push_str(std::str::from_utf8(line).unwrap());
+        raw.push('\n');
+    }
+    raw.push_str(&format!("-----END {}-----\n", label));
+    raw
+}
+
+pub fn format_pkcs8_public(key: &RSAPublicKey) -> Result<String> {
+    let der_bytes = key.to_pkcs8().context("failed to encode key as pkcs8")?;
+    Ok(encode_pkcs8_plaintext("PUBLIC KEY", &der_bytes))
+}
+
+pub fn format_pkcs8_private(key: &RSAPrivateKey) -> Result<String> {
+    let der_bytes = key.to_pkcs8().context("failed to encode key as pkcs8")?;
+    Ok(encode_pkcs8_plaintext("PRIVATE KEY", &der_bytes))
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn serialize_base64<T, S>(array: &T, serializer: S) -> Result<S::Ok, S::Error>
@@ -57,6 +78,59 @@ where
     D: Deserializer<'de>,
 {
     let bytes = deserialize_base64(deserializer)?;
+    into_fixed(bytes)
+}
+
+pub fn serialize_optional_base64<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    match value {
+        Some(array) => serialize_base64(array, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_optional_base64_fixed<'de, D, const SIZE: usize>(
+    deserializer: D,
+) -> Result<Option<[u8; SIZE]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(string) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let bytes = base64::decode(string)
+        .map_err(|err| de::Error::custom(format!("invalid base64: {}", err)))?;
+    into_fixed(bytes).map(Some)
+}
+
+pub fn serialize_base64_vec<T, S>(arrays: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    serializer.collect_seq(arrays.iter().map(|array| base64::encode(array.as_ref())))
+}
+
+pub fn deserialize_base64_fixed_vec<'de, D, const SIZE: usize>(
+    deserializer: D,
+) -> Result<Vec<[u8; SIZE]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|string| {
+            let bytes = base64::decode(string)
+                .map_err(|err| de::Error::custom(format!("invalid base64: {}", err)))?;
+            into_fixed(bytes)
+        })
+        .collect()
+}
+
+fn into_fixed<E: de::Error, const SIZE: usize>(bytes: Vec<u8>) -> Result<[u8; SIZE], E> {
     if bytes.len() != SIZE {
         return Err(de::Error::custom(format!(
             "invalid length: expected {}, got {}",
@@ -74,25 +148,72 @@ where
 
 ////////////////////////////////////////////////////////////////////////////////
 
+pub fn wallet_id_to_pkcs8(wallet: &WalletId) -> Result<Vec<u8>> {
+    wallet
+        .public_key
+        .to_pkcs8()
+        .context("failed to encode key as PKCS8")
+}
+
+pub fn wallet_id_from_pkcs8(bytes: &[u8]) -> Result<WalletId> {
+    let public_key = RSAPublicKey::from_pkcs8(bytes).context("invalid PKCS8")?;
+    Ok(WalletId { public_key })
+}
+
+pub fn encode_wallet_id(wallet: &WalletId) -> Result<String> {
+    Ok(base64::encode(wallet_id_to_pkcs8(wallet)?))
+}
+
+pub fn decode_wallet_id(encoded: &str) -> Result<WalletId> {
+    let bytes = base64::decode(encoded).context("invalid base64")?;
+    wallet_id_from_pkcs8(&bytes)
+}
+
 pub fn serialize_wallet_id<S>(wallet: &WalletId, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let bytes = wallet
-        .public_key
-        .to_pkcs8()
-        .map_err(|err| ser::Error::custom(format!("failed to encode key as PKCS8: {}", err)))?;
-    serialize_base64(&bytes, serializer)
+    let encoded =
+        encode_wallet_id(wallet).map_err(|err| ser::Error::custom(format!("{:#}", err)))?;
+    serializer.serialize_str(&encoded)
 }
 
 pub fn deserialize_wallet_id<'de, D>(deserializer: D) -> Result<WalletId, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let bytes = deserialize_base64(deserializer)?;
-    RSAPublicKey::from_pkcs8(&bytes)
-        .map(|public_key| WalletId { public_key })
-        .map_err(|err| de::Error::custom(format!("invalid PKCS8: {}", err)))
+    let string = String::deserialize(deserializer)?;
+    decode_wallet_id(&string).map_err(|err| de::Error::custom(format!("{:#}", err)))
+}
+
+pub fn serialize_wallet_id_vec<S>(wallets: &[WalletId], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let encoded = wallets
+        .iter()
+        .map(encode_wallet_id)
+        .collect::<Result<Vec<_>>>()
+        .map_err(|err| ser::Error::custom(format!("{:#}", err)))?;
+    serializer.collect_seq(encoded)
+}
+
+pub fn deserialize_wallet_id_vec<'de, D>(deserializer: D) -> Result<Vec<WalletId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|string| {
+            decode_wallet_id(string).map_err(|err| de::Error::custom(format!("{:#}", err)))
+        })
+        .collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -115,3 +236,25 @@ where
         LocalResult::Ambiguous(_, _) => Err(de::Error::custom("ambiguous timestamp")),
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkcs8_roundtrip() {
+        let key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
+        let formatted = format_pkcs8_private(&key).unwrap();
+        assert_eq!(parse_pkcs8_private(&formatted).unwrap(), key);
+
+        let public_key = key.to_public_key();
+        let formatted = format_pkcs8_public(&public_key).unwrap();
+        assert_eq!(parse_pkcs8_public(&formatted).unwrap(), public_key);
+
+        let wallet_id = WalletId::from(public_key);
+        let encoded = encode_wallet_id(&wallet_id).unwrap();
+        assert_eq!(decode_wallet_id(&encoded).unwrap(), wallet_id);
+    }
+}
@@ -64,8 +64,8 @@ fn test_tx_send() {
     let env = test_env!("test_tx_send");
 
     let key = generate_private_key();
-    let tx =
-        VerifiedTransaction::sign(&key, generate_public_key().into(), 0, 0, "Test".into()).unwrap();
+    let tx = VerifiedTransaction::sign(&key, generate_public_key().into(), 0, 0, 1, "Test".into())
+        .unwrap();
 
     let mut conn_one = env.connect_to_node().unwrap();
     send_message(
@@ -89,8 +89,15 @@ fn test_tx_discard() {
     let env = test_env!("test_tx_discard");
 
     let key = generate_private_key();
-    let tx = VerifiedTransaction::sign(&key, generate_public_key().into(), 100, 100, "Test".into())
-        .unwrap();
+    let tx = VerifiedTransaction::sign(
+        &key,
+        generate_public_key().into(),
+        100,
+        100,
+        1,
+        "Test".into(),
+    )
+    .unwrap();
 
     let mut conn_one = env.connect_to_node().unwrap();
     send_message(
@@ -149,6 +156,7 @@ fn test_head_switch() {
         generate_public_key().into(),
         0,
         0,
+        1,
         "Test".into(),
     )
     .unwrap();
@@ -158,6 +166,7 @@ fn test_head_switch() {
         generate_public_key().into(),
         0,
         0,
+        1,
         "Test".into(),
     )
     .unwrap();
@@ -107,6 +107,7 @@ fn test_mining_difficulty() {
                 issuer: generate_public_key().into(),
                 max_hash: [255; HASH_LEN],
                 prev_hash: prev_block.compute_hash(),
+                merkle_root: None,
             },
             transactions: vec![],
         });
@@ -152,6 +153,7 @@ fn test_max_tx_per_block() {
                 generate_public_key().into(),
                 0,
                 0,
+                1,
                 format!("tx #{}", i),
             )
             .unwrap()
@@ -4,7 +4,10 @@ mod helpers;
 use helpers::send_message;
 
 use babencoin::{
-    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction, MAX_REWARD},
+    data::{
+        Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction, MAX_REWARD,
+        TRANSACTION_VERSION,
+    },
     node,
     util::parse_pkcs8_private,
 };
@@ -27,7 +30,8 @@ fn test_simple() {
 
     let genesis_key = Block::genesis().attrs.issuer;
     let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
-    let tx = VerifiedTransaction::sign(&priv_key, genesis_key, 100, 10, "comment".into()).unwrap();
+    let tx =
+        VerifiedTransaction::sign(&priv_key, genesis_key, 100, 10, 1, "comment".into()).unwrap();
     send_message(&mut conn, PeerMessage::Transaction(Box::new(tx.into()))).unwrap();
 
     send_message(
@@ -63,12 +67,17 @@ fn test_invalid_messages() {
     let genesis_key = Block::genesis().attrs.issuer;
 
     let invalid_tx = Transaction {
+        version: TRANSACTION_VERSION,
         amount: 1000,
         fee: 30,
+        nonce: 1,
         comment: "foo".into(),
         sender: genesis_key.clone(),
         receiver: genesis_key,
         signature: vec![0; 64],
+        timelock: None,
+        multisig_policy: None,
+        cosignatures: vec![],
     };
 
     let cases: &[(&str, String)] = &[
@@ -0,0 +1,119 @@
+use babencoin::{
+    data::{VerifiedTransaction, WalletId},
+    simulation::{Simulation, SimulationConfig},
+    util::parse_pkcs8_private,
+};
+
+use rsa::RSAPrivateKey;
+
+use std::time::Duration;
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn test_key() -> RSAPrivateKey {
+    parse_pkcs8_private(include_str!("../data/test.pem")).unwrap()
+}
+
+fn run_random_mining(seed: u64) -> Simulation {
+    let wallet: WalletId = test_key().to_public_key().into();
+    let mut sim = Simulation::new(SimulationConfig {
+        node_count: 5,
+        seed,
+        drop_rate: 0.3,
+        ..Default::default()
+    });
+    for i in 0..6 {
+        sim.mine_block(i * 7 % 5, &wallet).unwrap();
+        sim.run_for(Duration::from_secs(10));
+    }
+    sim
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_partition_reorg() {
+    let key = test_key();
+    let wallet: WalletId = key.to_public_key().into();
+    let mut sim = Simulation::new(SimulationConfig::default());
+
+    sim.mine_block(0, &wallet).unwrap();
+    sim.run_until_idle();
+    assert!(sim.is_converged());
+    assert_eq!(sim.head(2).index, 1);
+
+    sim.partition(&[&[0, 1], &[2]]);
+    let tx = VerifiedTransaction::sign(&key, WalletId::genesis(), 10, 1, 1, "fork".into()).unwrap();
+    sim.submit_transaction(2, tx.clone()).unwrap();
+    let minority_block = sim.mine_block(2, &wallet).unwrap();
+    assert!(minority_block
+        .transactions()
+        .iter()
+        .any(|t| t.hash() == tx.hash()));
+    sim.run_for(Duration::from_secs(10));
+    sim.mine_block(0, &wallet).unwrap();
+    sim.run_for(Duration::from_secs(10));
+    sim.mine_block(1, &wallet).unwrap();
+    sim.run_for(Duration::from_secs(10));
+
+    assert!(!sim.is_converged());
+    assert_eq!(sim.head(0).index, 3);
+    assert_eq!(sim.head(2).hash(), minority_block.hash());
+    assert!(sim
+        .block_forest(0)
+        .find_block(minority_block.hash())
+        .is_none());
+
+    sim.heal();
+    assert!(sim.run_until(Duration::from_secs(30), Simulation::is_converged));
+    assert_eq!(sim.head(2).index, 3);
+    assert!(sim
+        .block_forest(0)
+        .find_block(minority_block.hash())
+        .is_some());
+
+    // The transaction of the abandoned branch goes back to the mempool.
+    let forest = sim.block_forest(2);
+    assert!(forest.find_transaction_block(tx.hash()).is_none());
+    assert!(forest.mempool().contains(tx.hash()));
+    let block = sim.mine_block(2, &wallet).unwrap();
+    assert!(block.transactions().iter().any(|t| t.hash() == tx.hash()));
+}
+
+#[test]
+fn test_convergence_with_drops() {
+    let mut sim = run_random_mining(1);
+    assert!(sim.stats().dropped > 0);
+    assert!(sim.run_until(Duration::from_secs(60), Simulation::is_converged));
+    assert_eq!(sim.head(0).index, 6);
+}
+
+#[test]
+fn test_determinism() {
+    let lhs = run_random_mining(42);
+    let rhs = run_random_mining(42);
+    assert_eq!(lhs.stats(), rhs.stats());
+    assert_eq!(lhs.now(), rhs.now());
+    for node in 0..lhs.node_count() {
+        assert_eq!(lhs.head(node).hash(), rhs.head(node).hash());
+    }
+}
+
+#[test]
+fn test_latency() {
+    let wallet: WalletId = test_key().to_public_key().into();
+    let mut sim = Simulation::new(SimulationConfig {
+        node_count: 2,
+        latency: Duration::from_secs(2),
+        jitter: Duration::ZERO,
+        eager_requests_interval: Duration::ZERO,
+        ..Default::default()
+    });
+
+    let block = sim.mine_block(0, &wallet).unwrap();
+    sim.run_for(Duration::from_millis(1999));
+    assert!(sim.block_forest(1).find_block(block.hash()).is_none());
+    sim.run_for(Duration::from_millis(1));
+    assert_eq!(sim.head(1).hash(), block.hash());
+    assert_eq!(sim.in_flight(), 0);
+}
@@ -15,4 +15,4 @@ pub use runtime::{runtime_id, spawn, Runtime};
 pub use timer::sleep;
 
 #[cfg(feature = "net")]
-pub use network::UdpSocket;
+pub use network::{tcp, TcpListener, TcpStream, UdpDatagrams, UdpSocket};
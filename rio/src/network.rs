@@ -1,5 +1,7 @@
 mod driver;
+pub mod tcp;
 mod udp;
 
 pub use driver::{NetworkDriver, NetworkHandle};
-pub use udp::UdpSocket;
+pub use tcp::{TcpListener, TcpStream};
+pub use udp::{UdpDatagrams, UdpSocket};
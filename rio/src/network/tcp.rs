@@ -0,0 +1,329 @@
+use super::driver::ReadinessKind;
+
+use crate::runtime::RuntimeHandle;
+
+use futures::{
+    future::poll_fn,
+    io::{AsyncRead, AsyncWrite},
+};
+use log::debug;
+use mio::Token;
+
+use std::{
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr},
+    os::fd::{AsRawFd, RawFd},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct TcpListener {
+    inner: mio::net::TcpListener,
+    runtime: RuntimeHandle,
+    // TODO: your code here.
+}
+
+impl TcpListener {
+    pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    /// Waits for read readiness of the listener. The accepted stream is registered with the
+    /// runtime of the listener.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    // TODO: your code here.
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // TODO: your code here.
+        unimplemented!()
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reads and writes have separate readiness bits and wakers, like those of `UdpSocket`, so a
+/// read and a write may be pending at the same time without waking each other.
+pub struct TcpStream {
+    inner: mio::net::TcpStream,
+    runtime: RuntimeHandle,
+    // TODO: your code here.
+}
+
+impl TcpStream {
+    /// Starts a non-blocking connect and waits for write readiness, then reports the error of
+    /// the connection attempt, if any.
+    pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    /// Returns 0 once the peer has shut down its write side.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, buf)).await
+    }
+
+    /// Tries to read if the stream is known to be readable, otherwise stores the waker of `cx`
+    /// to be woken on read readiness. `AsyncRead` is built on it.
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    /// Same as `poll_read`, for write readiness.
+    pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(ErrorKind::WriteZero.into()),
+                len => buf = &buf[len..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Nothing is buffered on our side, so this is a plain syscall.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    /// Halves borrowing the stream, to read and write concurrently within one task.
+    ///
+    /// With `futures::io::AsyncReadExt` in scope, `stream.split()` is its `split`, which takes
+    /// the stream by value. Call `TcpStream::split(&stream)` then.
+    pub fn split(&self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        (ReadHalf(self), WriteHalf(self))
+    }
+
+    /// Halves owning the stream, to read and write from different tasks. The stream is closed
+    /// once both are dropped.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let stream = Arc::new(self);
+        (OwnedReadHalf(stream.clone()), OwnedWriteHalf(stream))
+    }
+
+    // TODO: your code here.
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // TODO: your code here.
+        unimplemented!()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct ReadHalf<'a>(&'a TcpStream);
+
+impl ReadHalf<'_> {
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).await
+    }
+}
+
+pub struct WriteHalf<'a>(&'a TcpStream);
+
+impl WriteHalf<'_> {
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf).await
+    }
+
+    /// Shuts down the write side, the peer reads EOF.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.0.shutdown(Shutdown::Write)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct OwnedReadHalf(Arc<TcpStream>);
+
+impl OwnedReadHalf {
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).await
+    }
+}
+
+pub struct OwnedWriteHalf(Arc<TcpStream>);
+
+impl OwnedWriteHalf {
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf).await
+    }
+
+    /// Shuts down the write side, the peer reads EOF.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.0.shutdown(Shutdown::Write)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Nothing is buffered on our side, so flushing is a no-op and closing shuts down the write side.
+// The impls for `&TcpStream` allow reading and writing through shared references, like `split`.
+
+impl AsyncRead for &TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        TcpStream::poll_read(self.get_mut(), cx, buf)
+    }
+}
+
+impl AsyncWrite for &TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        TcpStream::poll_write(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown(Shutdown::Write))
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self).poll_close(cx)
+    }
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_close(cx)
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_close(cx)
+    }
+}
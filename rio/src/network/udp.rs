@@ -2,7 +2,7 @@ use super::driver::ReadinessKind;
 
 use crate::runtime::RuntimeHandle;
 
-use futures::future::poll_fn;
+use futures::{future::poll_fn, ready, Sink, Stream};
 use log::debug;
 use mio::Token;
 
@@ -10,7 +10,8 @@ use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
     os::fd::{AsRawFd, RawFd},
-    task::Poll,
+    pin::Pin,
+    task::{Context, Poll},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -57,6 +58,28 @@ impl UdpSocket {
         unimplemented!()
     }
 
+    /// Tries to receive if the socket is known to be readable, otherwise stores the waker of `cx`
+    /// to be woken on read readiness. `UdpDatagrams` is built on it.
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
+    /// Same as `poll_recv_from`, for write readiness.
+    pub fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        // TODO: your code here.
+        unimplemented!()
+    }
+
     // TODO: your code here.
 }
 
@@ -72,3 +95,77 @@ impl AsRawFd for UdpSocket {
         self.inner.as_raw_fd()
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// `Stream` of received datagrams and `Sink` of datagrams to send, each with its peer address.
+/// Datagrams longer than `max_datagram_size` are truncated, as with `recv_from`.
+pub struct UdpDatagrams {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+    pending: Option<(Vec<u8>, SocketAddr)>,
+}
+
+impl UdpDatagrams {
+    pub fn new(socket: UdpSocket, max_datagram_size: usize) -> Self {
+        Self {
+            socket,
+            buf: vec![0; max_datagram_size],
+            pending: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// A datagram passed to `start_send` but not flushed yet is dropped.
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+}
+
+impl Stream for UdpDatagrams {
+    type Item = io::Result<(Vec<u8>, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let result = ready!(this.socket.poll_recv_from(cx, &mut this.buf))
+            .map(|(len, addr)| (this.buf[..len].to_vec(), addr));
+        Poll::Ready(Some(result))
+    }
+}
+
+impl Sink<(Vec<u8>, SocketAddr)> for UdpDatagrams {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (Vec<u8>, SocketAddr)) -> io::Result<()> {
+        let this = self.get_mut();
+        assert!(
+            this.pending.is_none(),
+            "start_send called before poll_ready"
+        );
+        this.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some((data, addr)) = &this.pending {
+            let len = ready!(this.socket.poll_send_to(cx, data, *addr))?;
+            if len != data.len() {
+                debug!("datagram of {} bytes was sent partially", data.len());
+            }
+            this.pending = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
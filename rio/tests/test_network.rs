@@ -1,14 +1,21 @@
 #![cfg(feature = "net")]
 
-use rio::UdpSocket;
-
-use futures::{channel::oneshot, task::ArcWake, FutureExt};
+use rio::{TcpListener, TcpStream, UdpDatagrams, UdpSocket};
+
+use futures::{
+    channel::oneshot,
+    future::{join, select, Either},
+    io::{copy, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    task::ArcWake,
+    FutureExt, SinkExt, StreamExt,
+};
 use log::debug;
 use test_log::test;
 
 use std::{
     future::{poll_fn, Future},
-    net::SocketAddr,
+    io::ErrorKind,
+    net::{Shutdown, SocketAddr},
     ops::Deref,
     os::fd::AsRawFd,
     pin::pin,
@@ -455,3 +462,307 @@ async fn stress_test_ping_pong() {
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+async fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+    let (client, accepted) = join(TcpStream::connect(address), listener.accept()).await;
+    let client = client.unwrap();
+    let (server, peer_address) = accepted.unwrap();
+    assert_eq!(peer_address, client.local_addr().unwrap());
+    assert_eq!(client.peer_addr().unwrap(), address);
+    (client, server)
+}
+
+async fn read_to_end(stream: &TcpStream) -> Vec<u8> {
+    let mut data = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        let len = stream.read(&mut buf).await.unwrap();
+        if len == 0 {
+            return data;
+        }
+        data.extend_from_slice(&buf[..len]);
+    }
+}
+
+#[rio::test]
+async fn test_tcp_ping_pong() {
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client_handle = rio::spawn(async move {
+        let stream = TcpStream::connect(address).await.unwrap();
+        for i in 0..5 {
+            stream
+                .write_all(format!("ping #{}", i).as_bytes())
+                .await
+                .unwrap();
+            debug!("sent ping #{}", i);
+
+            let mut buf = [0; 7];
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 7);
+            assert_eq!(&buf, format!("pong #{}", i).as_bytes());
+            debug!("got pong #{}", i);
+        }
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    for i in 0..5 {
+        let mut buf = [0; 7];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 7);
+        assert_eq!(&buf, format!("ping #{}", i).as_bytes());
+        debug!("got ping #{}", i);
+
+        stream
+            .write_all(format!("pong #{}", i).as_bytes())
+            .await
+            .unwrap();
+        debug!("sent pong #{}", i);
+    }
+
+    client_handle.await.unwrap();
+}
+
+#[rio::test]
+async fn test_tcp_fan_in() {
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let handles = (0..3)
+        .map(|i| {
+            rio::spawn(async move {
+                let stream = TcpStream::connect(address).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                assert_eq!(read_to_end(&stream).await, b"pong");
+                debug!("got pong (id {})", i);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..handles.len() {
+        let (stream, _) = listener.accept().await.unwrap();
+        debug!("accepted connection #{}", i);
+        assert_eq!(read_to_end(&stream).await, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+    }
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+#[rio::test]
+async fn test_tcp_shutdown() {
+    let (client, server) = connected_pair().await;
+
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_to_end(&server).await, b"hello");
+
+    // The other direction is still open.
+    server.write_all(b"bye").await.unwrap();
+    drop(server);
+    assert_eq!(read_to_end(&client).await, b"bye");
+}
+
+#[rio::test]
+async fn test_tcp_connection_refused() {
+    let address = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let err = TcpStream::connect(address).await.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+}
+
+#[rio::test]
+async fn test_tcp_split() {
+    let (client, server) = connected_pair().await;
+
+    // Echo from separate tasks.
+    let (server_reader, server_writer) = server.into_split();
+    let echo_handle = rio::spawn(async move {
+        let mut buf = [0; 4096];
+        loop {
+            let len = server_reader.read(&mut buf).await.unwrap();
+            if len == 0 {
+                server_writer.shutdown().unwrap();
+                return;
+            }
+            server_writer.write_all(&buf[..len]).await.unwrap();
+        }
+    });
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+    let (client_reader, client_writer) = TcpStream::split(&client);
+    let write = async {
+        client_writer.write_all(&data).await.unwrap();
+        client_writer.shutdown().unwrap();
+    };
+    let read = async {
+        let mut echoed = vec![];
+        let mut buf = [0; 4096];
+        loop {
+            let len = client_reader.read(&mut buf).await.unwrap();
+            if len == 0 {
+                return echoed;
+            }
+            echoed.extend_from_slice(&buf[..len]);
+        }
+    };
+    let ((), echoed) = join(write, read).await;
+    assert!(echoed == data);
+
+    echo_handle.await.unwrap();
+}
+
+#[rio::test]
+async fn test_tcp_runtime_switch() {
+    let (client, server) = connected_pair().await;
+
+    let handle = std::thread::spawn(move || {
+        rio::Runtime::new_current_thread().block_on(async move {
+            client.write_all(b"ping").await.unwrap();
+        })
+    });
+    handle.join().unwrap();
+
+    assert_eq!(read_to_end(&server).await, b"ping");
+}
+
+#[rio::test]
+async fn test_tcp_no_unnecessary_syscalls() {
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let (stream, _) = connected_pair().await;
+
+    // NB: nothing connects to the listener and nothing is sent to the stream, so neither
+    // should ever touch its closed file descriptor.
+    nix::unistd::close(listener.as_raw_fd()).unwrap();
+    nix::unistd::close(stream.as_raw_fd()).unwrap();
+
+    {
+        let mut accept_future = pin!(listener.accept());
+        let mut buf = [0; 4];
+        let mut read_future = pin!(stream.read(&mut buf));
+        {
+            let waker = poll_fn(|cx| Poll::Ready(cx.waker().clone())).await;
+            let mut context = Context::from_waker(&waker);
+            for _ in 0..10 {
+                assert!(accept_future.poll_unpin(&mut context).is_pending());
+                assert!(read_future.poll_unpin(&mut context).is_pending());
+            }
+        }
+    }
+
+    // NB: leak both to prevent Drop from trying to close fds again.
+    std::mem::forget(listener);
+    std::mem::forget(stream);
+}
+
+#[rio::test]
+async fn test_tcp_writes_dont_wake_reads() {
+    let (client, server) = connected_pair().await;
+    let drain_handle = rio::spawn(async move { read_to_end(&server).await.len() });
+
+    let mut buf = [0u8; 4];
+    let read_waker = CountingWaker::from_current().await;
+    {
+        let mut read_future = pin!(client.read(&mut buf));
+        assert!(read_waker.poll_future(&mut read_future).is_pending());
+
+        let chunk = vec![0; 64 * 1024];
+        let mut bytes_written = 0;
+        while bytes_written < 64 * 1024 * 1024 {
+            bytes_written += client.write(&chunk).await.unwrap();
+        }
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(drain_handle.await.unwrap(), bytes_written);
+    }
+
+    assert_eq!(read_waker.call_count(), 0);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[rio::test]
+async fn test_tcp_async_io() {
+    let (client, server) = connected_pair().await;
+
+    // Echo with `futures::io::copy` over the halves of the accepted stream.
+    let echo_handle = rio::spawn(async move {
+        let (mut reader, mut writer) = server.into_split();
+        let len = copy(&mut reader, &mut writer).await.unwrap();
+        writer.close().await.unwrap();
+        len
+    });
+
+    let (reader, mut writer) = client.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    for i in 0..5 {
+        writer
+            .write_all(format!("line #{}\n", i).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(lines.next().await.unwrap().unwrap(), format!("line #{}", i));
+    }
+    writer.close().await.unwrap();
+    assert!(lines.next().await.is_none());
+    assert_eq!(echo_handle.await.unwrap(), 40);
+}
+
+#[rio::test]
+async fn test_tcp_read_to_end() {
+    let (mut client, mut server) = connected_pair().await;
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+    let write = async {
+        client.write_all(&data).await.unwrap();
+        client.close().await.unwrap();
+    };
+    let read = async {
+        let mut received = vec![];
+        server.read_to_end(&mut received).await.unwrap();
+        received
+    };
+    let ((), received) = join(write, read).await;
+    assert!(received == data);
+}
+
+#[rio::test]
+async fn test_udp_datagrams() {
+    let first_socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let first_address = first_socket.local_addr().unwrap();
+    let second_socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let second_address = second_socket.local_addr().unwrap();
+
+    let mut first = UdpDatagrams::new(first_socket, 64);
+    let mut second = UdpDatagrams::new(second_socket, 64);
+
+    let messages = (0..5).map(|i| Ok((format!("ping #{}", i).into_bytes(), second_address)));
+    first
+        .send_all(&mut futures::stream::iter(messages))
+        .await
+        .unwrap();
+
+    for i in 0..5 {
+        let (data, address) = second.next().await.unwrap().unwrap();
+        assert_eq!(data, format!("ping #{}", i).as_bytes());
+        assert_eq!(address, first_address);
+    }
+
+    second
+        .send((b"pong".to_vec(), first_address))
+        .await
+        .unwrap();
+    let received = select(first.next(), rio::sleep(Duration::from_secs(3)).boxed()).await;
+    let Either::Left((Some(Ok((data, address))), _)) = received else {
+        panic!("no pong received");
+    };
+    assert_eq!(data, b"pong");
+    assert_eq!(address, second_address);
+    assert_eq!(first.into_inner().local_addr().unwrap(), first_address);
+}